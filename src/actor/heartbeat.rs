@@ -1,31 +1,124 @@
 use actix::prelude::*;
-use std::time::Duration;
+use actix_web::{error::BlockingError, web};
+use log::{debug, error, info, warn};
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
 use uuid::Uuid;
 
 use actix::io::SinkWrite;
 
-use futures::stream::SplitSink;
-use std::net::{SocketAddr, ToSocketAddrs};
+use futures::stream::{SplitSink, StreamExt};
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
 use tokio_util::udp::UdpFramed;
 
 use super::inventory::InventoryActor;
 use crate::{
-    messages::{HBCodec, HeartBeat, Monitor},
-    phi,
+    messages::{Arrival, HBCodec, HeartBeat, Monitor, Resolved},
+    net::Resolver,
+    peer::Peer,
+    phi::Clock,
+    trace::TraceWriter,
 };
 
+/// How often every monitored peer is pinged, unless configured otherwise.
+pub const PING_INTERVAL: Duration = Duration::from_millis(150);
+/// Pings left unanswered before a peer's address is resolved again early.
+const SILENT_PINGS: u32 = 5;
+/// Shortest time between two resolutions of a silent peer's address.
+const RESOLVE_BACKOFF: Duration = Duration::from_secs(5);
+
 type SinkItem = (HeartBeat, SocketAddr);
 type UdpSink = SplitSink<UdpFramed<HBCodec>, SinkItem>;
 
+/// Carries heartbeats for the `UdpActor`: a UDP socket, or the simulated
+/// network of `sim`. Received packets reach the actor as a stream of
+/// `UdpPacket`s added to its context.
+pub trait Transport {
+    fn send(&mut self, packet: HeartBeat, to: SocketAddr);
+}
+
+impl Transport for SinkWrite<SinkItem, UdpSink> {
+    fn send(&mut self, packet: HeartBeat, to: SocketAddr) {
+        self.write((packet, to)).unwrap();
+    }
+}
+
+/// Sends heartbeats through `socket` and hands what it receives to the
+/// actor of `ctx`.
+pub fn udp(socket: UdpSocket, ctx: &mut Context<UdpActor>) -> Box<dyn Transport> {
+    let (sink, stream) = UdpFramed::new(socket, HBCodec).split();
+    ctx.add_stream(stream.filter_map(|item| async {
+        match item {
+            Ok((data, sender)) => Some(UdpPacket(data, sender)),
+            Err(e) => {
+                warn!("Dropping packet: {}", e);
+                None
+            }
+        }
+    }));
+    Box::new(SinkWrite::new(sink, ctx))
+}
+
 pub struct UdpActor {
     pub me: Uuid,
-    pub sink: SinkWrite<SinkItem, UdpSink>,
+    pub transport: Box<dyn Transport>,
     pub inventory: Addr<InventoryActor>,
-    pub monitored: std::collections::HashMap<Uuid, Addr<HeartBeatActor>>,
+    pub monitored: HashMap<Uuid, Addr<HeartBeatActor>>,
+    pub trace: Option<TraceWriter>,
+    pub ping_interval: Duration,
+    /// How often peer addresses are resolved again.
+    pub resolve_interval: Duration,
+    /// Picks the address each peer is pinged at.
+    pub resolver: Resolver,
+    /// Peers from the configuration, as last applied.
+    pub static_peers: HashMap<Uuid, Peer>,
+    /// Peers each discovery source found last.
+    pub discovered: HashMap<String, HashMap<Uuid, Peer>>,
+    /// Monitored peers registered by discovery, rather than from the
+    /// configuration or by hand, which discovery alone may unregister.
+    pub added: HashSet<Uuid>,
+    pub clock: Clock,
+}
+
+impl UdpActor {
+    /// Whether the configuration or a discovery source lists the peer.
+    fn listed(&self, id: &Uuid) -> bool {
+        self.static_peers.contains_key(id) || self.discovered.values().any(|p| p.contains_key(id))
+    }
+
+    /// The entry a discovery source lists for the peer, if any.
+    fn discovered_peer(&self, id: &Uuid) -> Option<Peer> {
+        self.discovered
+            .values()
+            .find_map(|peers| peers.get(id))
+            .cloned()
+    }
+
+    fn record(&mut self, arrival: &Arrival) {
+        if let Some(trace) = &mut self.trace {
+            if let Err(e) = trace.write(arrival) {
+                error!("Trace recording failed, disabling it: {:?}", e);
+                self.trace = None;
+            }
+        }
+    }
 }
 
 impl Actor for UdpActor {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(Duration::new(1, 0), |actor, _ctx| {
+            if let Some(trace) = &mut actor.trace {
+                if let Err(e) = trace.flush() {
+                    warn!("Trace flush failed: {:?}", e);
+                }
+            }
+        });
+    }
 }
 
 #[derive(Message)]
@@ -36,12 +129,84 @@ pub struct StopHeartbeat;
 #[rtype(result = "()")]
 pub struct UdpPacket(pub HeartBeat, pub SocketAddr);
 
+/// Replaces the configured peers: new, changed or no longer monitored entries
+/// are registered and dropped ones unregistered, unless discovered. Peers
+/// registered at runtime are left alone.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct StaticPeers(pub std::vec::Vec<Peer>);
+
+/// The peers a discovery source found: new or changed ones are registered
+/// and those it no longer lists unregistered, unless listed elsewhere.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Discovered {
+    pub source: String,
+    pub peers: std::vec::Vec<Peer>,
+}
+
+/// Changes how often peers are pinged and their addresses resolved; sent
+/// to the `UdpActor`, which passes it on to every `HeartBeatActor`.
+#[derive(Message, Clone, Copy)]
+#[rtype(result = "()")]
+pub struct Intervals {
+    pub ping: Duration,
+    pub resolve: Duration,
+}
+
+/// Tells a `HeartBeatActor` its peer answered at the given time.
+#[derive(Message, Clone, Copy)]
+#[rtype(result = "()")]
+struct Answered(u128);
+
+/// Registrations and removals that turn the `previous` configured peers
+/// into `wanted`.
+pub fn reconcile(
+    previous: &HashMap<Uuid, Peer>,
+    wanted: &[Peer],
+    monitored: impl Fn(&Uuid) -> bool,
+) -> (std::vec::Vec<Peer>, std::vec::Vec<Uuid>) {
+    let register = wanted
+        .iter()
+        .filter(|peer| previous.get(&peer.id) != Some(peer) || !monitored(&peer.id))
+        .cloned()
+        .collect();
+    let unregister = previous
+        .keys()
+        .filter(|id| wanted.iter().all(|peer| peer.id != **id))
+        .copied()
+        .collect();
+    (register, unregister)
+}
+
+/// What a node does with a packet it received, independent of the transport.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Received {
+    Reply(HeartBeat),
+    Arrival(Arrival),
+    Ignored,
+}
+
+/// Answers pings and turns pongs into arrivals stamped with `now`.
+pub fn receive(me: Uuid, packet: HeartBeat, now: u128) -> Received {
+    match packet {
+        HeartBeat::Ping(_id, seq, ts) => Received::Reply(HeartBeat::Pong(me, seq, ts)),
+        HeartBeat::Pong(id, seq, sent) => Received::Arrival(Arrival {
+            id,
+            seq,
+            sent,
+            received: now,
+        }),
+        _ => Received::Ignored,
+    }
+}
+
 impl Handler<HeartBeat> for UdpActor {
     type Result = ();
 
     fn handle(&mut self, msg: HeartBeat, _: &mut Context<Self>) {
-        if let HeartBeat::DoPing(id, ts, addr) = msg {
-            self.sink.write((HeartBeat::Ping(id, ts), addr)).unwrap();
+        if let HeartBeat::DoPing(id, seq, ts, addr) = msg {
+            self.transport.send(HeartBeat::Ping(id, seq, ts), addr);
         }
     }
 }
@@ -52,7 +217,8 @@ impl Handler<Monitor> for UdpActor {
     fn handle(&mut self, msg: Monitor, ctx: &mut Context<Self>) {
         match msg {
             Monitor::Register(ref peer) => {
-                println!("Register peer {:?}", peer);
+                self.added.remove(&peer.id);
+                info!("Register peer {:?}", peer);
                 if peer.id == self.me {
                     return;
                 }
@@ -62,13 +228,22 @@ impl Handler<Monitor> for UdpActor {
                     peer.id,
                     peer.name.clone(),
                     peer.address.clone(),
+                    self.resolver,
                     ctx.address(),
+                    Intervals {
+                        ping: self.ping_interval,
+                        resolve: self.resolve_interval,
+                    },
+                    self.clock,
                 )
                 .start();
-                self.monitored.insert(peer.id, addr);
+                if let Some(previous) = self.monitored.insert(peer.id, addr) {
+                    previous.do_send(StopHeartbeat);
+                }
             }
             Monitor::UnRegister(ref uuid) => {
-                println!("Unregister peer {:?}", uuid);
+                info!("Unregister peer {:?}", uuid);
+                self.added.remove(uuid);
                 self.monitored
                     .remove(uuid)
                     .and_then::<Addr<HeartBeatActor>, _>(|addr| {
@@ -81,34 +256,139 @@ impl Handler<Monitor> for UdpActor {
     }
 }
 
+impl Handler<StaticPeers> for UdpActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: StaticPeers, ctx: &mut Context<Self>) {
+        let monitored = &self.monitored;
+        let (register, unregister) =
+            reconcile(&self.static_peers, &msg.0, |id| monitored.contains_key(id));
+        self.static_peers = msg.0.into_iter().map(|peer| (peer.id, peer)).collect();
+        for id in unregister {
+            // a discovered peer stays, without the overrides of its entry
+            match self.discovered_peer(&id) {
+                Some(peer) => {
+                    Handler::<Monitor>::handle(self, Monitor::Register(peer), ctx);
+                    self.added.insert(id);
+                }
+                None => Handler::<Monitor>::handle(self, Monitor::UnRegister(id), ctx),
+            }
+        }
+        for peer in register {
+            Handler::<Monitor>::handle(self, Monitor::Register(peer), ctx);
+        }
+    }
+}
+
+impl Handler<Discovered> for UdpActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: Discovered, ctx: &mut Context<Self>) {
+        let me = self.me;
+        let peers: std::vec::Vec<Peer> = msg.peers.into_iter().filter(|p| p.id != me).collect();
+        let previous = self.discovered.remove(&msg.source).unwrap_or_default();
+        let monitored = &self.monitored;
+        let (register, unregister) = reconcile(&previous, &peers, |id| monitored.contains_key(id));
+        for peer in &register {
+            info!("Discovered peer {} ({}) through {}", peer.name, peer.address, msg.source);
+        }
+        self.discovered.insert(
+            msg.source.clone(),
+            peers.into_iter().map(|peer| (peer.id, peer)).collect(),
+        );
+        for id in unregister {
+            if self.added.contains(&id) && !self.listed(&id) {
+                info!("Peer {} is gone from {}", id, msg.source);
+                Handler::<Monitor>::handle(self, Monitor::UnRegister(id), ctx);
+            }
+        }
+        for peer in register {
+            // a peer registered by hand is left as it was
+            let manual =
+                !self.added.contains(&peer.id) && !self.static_peers.contains_key(&peer.id);
+            if manual && self.monitored.contains_key(&peer.id) {
+                continue;
+            }
+            // the configured entry, with its detector overrides, wins
+            match self.static_peers.get(&peer.id).cloned() {
+                Some(configured) => {
+                    Handler::<Monitor>::handle(self, Monitor::Register(configured), ctx)
+                }
+                None => {
+                    let id = peer.id;
+                    Handler::<Monitor>::handle(self, Monitor::Register(peer), ctx);
+                    self.added.insert(id);
+                }
+            }
+        }
+    }
+}
+
+impl Handler<Intervals> for UdpActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: Intervals, _ctx: &mut Context<Self>) {
+        self.ping_interval = msg.ping;
+        self.resolve_interval = msg.resolve;
+        for addr in self.monitored.values() {
+            addr.do_send(msg);
+        }
+    }
+}
+
+impl Handler<Resolved> for UdpActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: Resolved, _ctx: &mut Context<Self>) {
+        self.inventory.do_send(msg);
+    }
+}
+
 impl StreamHandler<UdpPacket> for UdpActor {
     fn handle(&mut self, rmsg: UdpPacket, _: &mut Context<Self>) {
-        println!("Received: ({:?}, {:?})", rmsg.0, rmsg.1);
-        match rmsg.0 {
-            HeartBeat::Ping(_id, _ts) => self
-                .sink
-                .write((HeartBeat::Pong(self.me, phi::now()), rmsg.1))
-                .unwrap(),
-            HeartBeat::Pong(id, _ts) => self.inventory.do_send(HeartBeat::Pong(id, phi::now())),
-            _ => println!("Received non match: ({:?}, {:?})", rmsg.0, rmsg.1),
+        debug!("Received: ({:?}, {:?})", rmsg.0, rmsg.1);
+        match receive(self.me, rmsg.0.clone(), self.clock.now()) {
+            Received::Reply(reply) => self.transport.send(reply, rmsg.1),
+            Received::Arrival(arrival) => {
+                self.record(&arrival);
+                if let Some(addr) = self.monitored.get(&arrival.id) {
+                    addr.do_send(Answered(arrival.received));
+                }
+                self.inventory.do_send(arrival);
+            }
+            Received::Ignored => debug!("Received non match: ({:?}, {:?})", rmsg.0, rmsg.1),
         };
     }
 }
 
 impl actix::io::WriteHandler<std::io::Error> for UdpActor {}
 
+/// Pings one peer. Its address is kept as given, usually a host name, and
+/// resolved again periodically and when the peer stops answering, so peers
+/// that come back with a new IP are found again.
 pub struct HeartBeatActor {
     id: Uuid,
     name: String,
     address: String,
-    socket_address: SocketAddr,
+    /// Where pings go, once `address` resolved.
+    socket_address: Option<SocketAddr>,
+    resolver: Resolver,
     pinger: Addr<UdpActor>,
+    seq: u64,
+    intervals: Intervals,
+    timer: Option<SpawnHandle>,
+    /// When resolution was last started and the last pong arrived.
+    resolved_at: Option<u128>,
+    answered: u128,
+    resolving: bool,
+    clock: Clock,
 }
 
 impl Actor for HeartBeatActor {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
+        self.resolve(ctx);
         self.heartbeat(ctx);
     }
 }
@@ -123,24 +403,331 @@ impl Handler<StopHeartbeat> for HeartBeatActor {
     }
 }
 
+impl Handler<Intervals> for HeartBeatActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: Intervals, ctx: &mut Context<Self>) {
+        let ping_changed = msg.ping != self.intervals.ping;
+        self.intervals = msg;
+        if ping_changed {
+            self.heartbeat(ctx);
+        }
+    }
+}
+
+impl Handler<Answered> for HeartBeatActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: Answered, _ctx: &mut Context<Self>) {
+        self.answered = self.answered.max(msg.0);
+    }
+}
+
 impl HeartBeatActor {
-    pub fn new(id: Uuid, name: String, address: String, pinger: Addr<UdpActor>) -> HeartBeatActor {
+    pub fn new(
+        id: Uuid,
+        name: String,
+        address: String,
+        resolver: Resolver,
+        pinger: Addr<UdpActor>,
+        intervals: Intervals,
+        clock: Clock,
+    ) -> HeartBeatActor {
         HeartBeatActor {
             id,
             name,
             pinger,
-            address: address.clone(),
-            socket_address: address.as_str().to_socket_addrs().expect("Invalid forwarding address specified").next().unwrap(),
+            seq: 0,
+            intervals,
+            timer: None,
+            address,
+            socket_address: None,
+            resolver,
+            resolved_at: None,
+            answered: 0,
+            resolving: false,
+            clock,
         }
     }
 
-    fn heartbeat(&self, ctx: &mut Context<Self>) {
-        ctx.run_interval(Duration::from_millis(150), |actor, _ctx| {
-            actor.pinger.do_send(HeartBeat::DoPing(
-                actor.id,
-                phi::now(),
-                actor.socket_address,
-            ));
+    /// Starts pinging at the current interval, replacing any earlier timer.
+    fn heartbeat(&mut self, ctx: &mut Context<Self>) {
+        if let Some(timer) = self.timer.take() {
+            ctx.cancel_future(timer);
+        }
+        let timer = ctx.run_interval(self.intervals.ping, |actor, ctx| {
+            let now = actor.clock.now();
+            if resolve_due(now, actor.resolved_at, actor.answered, actor.intervals) {
+                actor.resolve(ctx);
+            }
+            if let Some(socket_address) = actor.socket_address {
+                actor.seq += 1;
+                actor
+                    .pinger
+                    .do_send(HeartBeat::DoPing(actor.id, actor.seq, now, socket_address));
+            }
+        });
+        self.timer = Some(timer);
+    }
+
+    /// Resolves the address on the blocking thread pool, so a slow DNS
+    /// server does not hold up other peers. IP addresses need no lookup.
+    fn resolve(&mut self, ctx: &mut Context<Self>) {
+        if self.resolving {
+            return;
+        }
+        self.resolved_at = Some(self.clock.now());
+        if let Ok(literal) = self.address.parse::<SocketAddr>() {
+            match self.resolver.choose(&[literal]) {
+                Some(socket_address) => self.resolved(socket_address),
+                None => warn!(
+                    "Peer {} at {} is not reachable from the heartbeat socket",
+                    self.id, self.address
+                ),
+            }
+            return;
+        }
+        self.resolving = true;
+        let (resolver, address) = (self.resolver, self.address.clone());
+        web::block(move || resolver.resolve(&address))
+            .into_actor(self)
+            .map(|result, actor, _ctx| {
+                actor.resolving = false;
+                match result {
+                    Ok(socket_address) => actor.resolved(socket_address),
+                    Err(BlockingError::Error(e)) => warn!(
+                        "Could not resolve {} of peer {}: {}",
+                        actor.address, actor.id, e
+                    ),
+                    Err(BlockingError::Canceled) => (),
+                }
+            })
+            .spawn(ctx);
+    }
+
+    fn resolved(&mut self, socket_address: SocketAddr) {
+        match self.socket_address {
+            Some(current) if current == socket_address => return,
+            Some(current) => info!(
+                "Peer {} ({}) moved from {} to {}",
+                self.id, self.address, current, socket_address
+            ),
+            None => (),
+        }
+        self.socket_address = Some(socket_address);
+        self.pinger.do_send(Resolved {
+            id: self.id,
+            address: socket_address,
+        });
+    }
+}
+
+/// Whether a peer's address should be resolved again: once the resolve
+/// interval passed, or earlier when the peer has not answered the last few
+/// pings since the previous resolution, as a restarted peer would not.
+pub fn resolve_due(
+    now: u128,
+    resolved_at: Option<u128>,
+    answered: u128,
+    intervals: Intervals,
+) -> bool {
+    let resolved_at = match resolved_at {
+        Some(at) => at,
+        None => return true,
+    };
+    let since = now.saturating_sub(resolved_at);
+    let silent = now.saturating_sub(answered.max(resolved_at));
+    since >= intervals.resolve.as_millis()
+        || (silent >= (intervals.ping * SILENT_PINGS).as_millis()
+            && since >= RESOLVE_BACKOFF.as_millis())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        reconcile, resolve_due, Discovered, Intervals, StaticPeers, Transport, UdpActor,
+        PING_INTERVAL,
+    };
+    use crate::{
+        actor::inventory::InventoryActor,
+        messages::{GetStatus, HeartBeat, Monitor},
+        net::{Family, Resolver, Stack},
+        peer::Peer,
+        phi::{Clock, DetectorConfig, PhiAccrualFailureDetectorBuilder},
+        timeseries::{parse_tiers, TimeSeries},
+    };
+    use actix::prelude::*;
+    use std::{
+        collections::{HashMap, HashSet},
+        net::SocketAddr,
+        time::Duration,
+    };
+    use uuid::Uuid;
+
+    /// Drops every packet.
+    struct Mute;
+
+    impl Transport for Mute {
+        fn send(&mut self, _packet: HeartBeat, _to: SocketAddr) {}
+    }
+
+    #[test]
+    fn resolve_when_due_or_silent() {
+        let intervals = Intervals {
+            ping: Duration::from_millis(100),
+            resolve: Duration::from_secs(30),
+        };
+        assert!(resolve_due(1_000, None, 0, intervals));
+        // answering peers are resolved again after the interval only
+        assert!(!resolve_due(20_000, Some(0), 19_950, intervals));
+        assert!(resolve_due(30_000, Some(0), 29_950, intervals));
+        // silent ones earlier, but not more often than the backoff
+        assert!(resolve_due(6_000, Some(0), 4_000, intervals));
+        assert!(!resolve_due(4_000, Some(0), 1_000, intervals));
+        assert!(!resolve_due(10_200, Some(10_000), 4_000, intervals));
+        assert!(resolve_due(15_000, Some(10_000), 4_000, intervals));
+    }
+
+    #[test]
+    fn reconcile_static_peers() {
+        let peer = |name: &str| {
+            Peer::new(Uuid::new_v4(), name.to_owned(), "127.0.0.1:9000".to_owned())
+        };
+        let (a, b, c) = (peer("a"), peer("b"), peer("c"));
+        let previous: HashMap<Uuid, Peer> =
+            vec![(a.id, a.clone()), (b.id, b.clone())].into_iter().collect();
+
+        let mut tuned = b.clone();
+        tuned.detector = Some(DetectorConfig {
+            threshold: Some(4.0),
+            ..DetectorConfig::default()
+        });
+        let (register, unregister) =
+            reconcile(&previous, &[a.clone(), tuned.clone(), c.clone()], |_| true);
+        assert_eq!(register, vec![tuned, c]);
+        assert!(unregister.is_empty());
+
+        // entries removed at runtime come back, dropped ones go
+        let (register, unregister) =
+            reconcile(&previous, std::slice::from_ref(&a), |id| *id != a.id);
+        assert_eq!(register, vec![a]);
+        assert_eq!(unregister, vec![b.id]);
+    }
+
+    #[test]
+    fn configured_overrides_win_over_discovery() {
+        let me = Uuid::new_v4();
+        let plain = Peer::new(Uuid::new_v4(), "b".to_owned(), "127.0.0.1:9000".to_owned());
+        let mut tuned = plain.clone();
+        tuned.detector = Some(DetectorConfig {
+            threshold: Some(4.0),
+            ..DetectorConfig::default()
+        });
+
+        let thresholds = actix_rt::System::new("discovered").block_on(async move {
+            let tiers = parse_tiers("1s:1m").unwrap();
+            let inventory = InventoryActor::new(
+                me,
+                PhiAccrualFailureDetectorBuilder::new(),
+                TimeSeries::new(tiers),
+            )
+            .start();
+            let udp = UdpActor {
+                me,
+                transport: Box::new(Mute),
+                inventory: inventory.clone(),
+                monitored: HashMap::new(),
+                trace: None,
+                ping_interval: PING_INTERVAL,
+                resolve_interval: Duration::from_secs(30),
+                resolver: Resolver::new(Family::Any, Stack::V4),
+                static_peers: HashMap::new(),
+                discovered: HashMap::new(),
+                added: HashSet::new(),
+                clock: Clock::System,
+            }
+            .start();
+            let threshold = || {
+                let inventory = inventory.clone();
+                async move {
+                    let status = inventory.send(GetStatus).await.unwrap();
+                    status.0[0].detector.threshold
+                }
+            };
+
+            udp.send(StaticPeers(vec![tuned])).await.unwrap();
+            let discovered = |peers| Discovered {
+                source: "file".to_owned(),
+                peers,
+            };
+            udp.send(discovered(vec![plain])).await.unwrap();
+            let both = threshold().await;
+            // dropped from the configuration, it is monitored as discovered
+            udp.send(StaticPeers(vec![])).await.unwrap();
+            let discovered_only = threshold().await;
+            (both, discovered_only)
+        });
+        assert_eq!(thresholds, (4.0, 16.0));
+    }
+
+    #[test]
+    fn manual_registration_survives_discovery() {
+        let me = Uuid::new_v4();
+        let plain = Peer::new(Uuid::new_v4(), "b".to_owned(), "127.0.0.1:9000".to_owned());
+        let mut tuned = plain.clone();
+        tuned.detector = Some(DetectorConfig {
+            threshold: Some(4.0),
+            ..DetectorConfig::default()
+        });
+
+        let thresholds = actix_rt::System::new("manual").block_on(async move {
+            let tiers = parse_tiers("1s:1m").unwrap();
+            let inventory = InventoryActor::new(
+                me,
+                PhiAccrualFailureDetectorBuilder::new(),
+                TimeSeries::new(tiers),
+            )
+            .start();
+            let udp = UdpActor {
+                me,
+                transport: Box::new(Mute),
+                inventory: inventory.clone(),
+                monitored: HashMap::new(),
+                trace: None,
+                ping_interval: PING_INTERVAL,
+                resolve_interval: Duration::from_secs(30),
+                resolver: Resolver::new(Family::Any, Stack::V4),
+                static_peers: HashMap::new(),
+                discovered: HashMap::new(),
+                added: HashSet::new(),
+                clock: Clock::System,
+            }
+            .start();
+            let thresholds = || {
+                let inventory = inventory.clone();
+                async move {
+                    let status = inventory.send(GetStatus).await.unwrap();
+                    status
+                        .0
+                        .iter()
+                        .map(|peer| peer.detector.threshold)
+                        .collect::<Vec<_>>()
+                }
+            };
+            let discovered = |peers| Discovered {
+                source: "file".to_owned(),
+                peers,
+            };
+
+            // registered by hand, then found by discovery as well
+            udp.send(Monitor::Register(tuned)).await.unwrap();
+            udp.send(discovered(vec![plain])).await.unwrap();
+            let refreshed = thresholds().await;
+            // and discovery no longer listing it leaves it registered
+            udp.send(discovered(vec![])).await.unwrap();
+            let dropped = thresholds().await;
+            (refreshed, dropped)
         });
+        assert_eq!(thresholds, (vec![4.0], vec![4.0]));
     }
 }
@@ -1,18 +1,46 @@
 use actix::prelude::*;
-use std::{collections::HashMap, time::Duration};
+use log::warn;
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    time::Duration,
+};
 use uuid::Uuid;
 
-use super::monitor::MonitorActor;
 use crate::{
-    messages::{self, HeartBeat, Monitor, PeerStatus, Status, StatusEvent},
-    phi::{self, PhiAccrualFailureDetector, PhiAccrualFailureDetectorBuilder, State},
+    messages::{
+        self, Arrival, GetStatus, GetTimeSeries, Level, Monitor, PeerStatus, Resolved, Span,
+        Status, StatusEvent,
+    },
+    net,
+    phi::{
+        self, Clock, DetectorConfig, PhiAccrualFailureDetector, PhiAccrualFailureDetectorBuilder,
+        SnapshotError, State,
+    },
+    timeseries::TimeSeries,
 };
 
+/// How often subscribers are sent the status of every peer.
+pub const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Fraction of the threshold past which a live peer counts as suspect.
+const SUSPECT_RATIO: f64 = 0.5;
+/// Level changes kept per peer for the dashboard timeline.
+const TIMELINE_LENGTH: usize = 64;
+/// Weight of a new sample in the smoothed round trip time, as in TCP.
+const RTT_WEIGHT: f64 = 0.125;
+
 pub struct PeerMonitor {
     id: Uuid,
     name: String,
     address: String,
+    resolved: Option<SocketAddr>,
     status: PhiAccrualFailureDetector,
+    /// Overrides the peer was registered with, kept to rebuild its detector
+    /// when the defaults change.
+    config: DetectorConfig,
+    rtt: Option<f64>,
+    timeline: VecDeque<Span>,
 }
 
 impl PeerMonitor {
@@ -26,14 +54,61 @@ impl PeerMonitor {
             id,
             name,
             address,
+            resolved: None,
             status,
+            config: DetectorConfig::default(),
+            rtt: None,
+            timeline: VecDeque::new(),
         }
     }
 
+    /// Applies `config` over `defaults` to the running detector, keeping its
+    /// heartbeat history.
+    pub fn reconfigure(
+        &mut self,
+        defaults: &PhiAccrualFailureDetectorBuilder,
+        config: DetectorConfig,
+    ) -> Result<(), SnapshotError> {
+        self.status = self.status.reconfigure(defaults, &config)?;
+        self.config = config;
+        Ok(())
+    }
+
     pub fn heartbeat(&mut self, now: u128) {
         self.status.heartbeat(now);
     }
 
+    /// Records a pong: a heartbeat at its arrival and a round trip sample.
+    pub fn arrival(&mut self, arrival: &Arrival) {
+        self.heartbeat(arrival.received);
+        let sample = arrival.received.saturating_sub(arrival.sent) as f64;
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => rtt + RTT_WEIGHT * (sample - rtt),
+            None => sample,
+        });
+        self.observe(arrival.received);
+    }
+
+    pub fn level(&self, now: u128) -> Level {
+        match self.state(now) {
+            State::Dead(_) => Level::Dead,
+            State::Alive(phi) if phi >= self.status.threshold() * SUSPECT_RATIO => Level::Suspect,
+            State::Alive(_) => Level::Alive,
+        }
+    }
+
+    /// Starts a new timeline span if the level changed since the last one.
+    pub fn observe(&mut self, now: u128) {
+        let level = self.level(now);
+        if self.timeline.back().map(|span| span.level) == Some(level) {
+            return;
+        }
+        self.timeline.push_back(Span { level, since: now });
+        if self.timeline.len() > TIMELINE_LENGTH {
+            self.timeline.pop_front();
+        }
+    }
+
     pub fn state(&self, now: u128) -> State {
         self.status.state(now)
     }
@@ -45,23 +120,39 @@ impl PeerMonitor {
     pub fn history(&self, num: usize) -> std::vec::Vec<u128> {
         self.status.history(num)
     }
-}
 
-impl From<&PeerMonitor> for PeerStatus {
-    fn from(item: &PeerMonitor) -> Self {
-        let st = item.state(phi::now());
+    pub fn recorded(&self) -> u64 {
+        self.status.recorded()
+    }
+
+    pub fn rtt(&self) -> Option<f64> {
+        self.rtt
+    }
+
+    /// The status shown for the peer at `now`.
+    pub fn status(&self, now: u128) -> PeerStatus {
+        let st = self.state(now);
         let phi = match st {
             phi::State::Alive(p) => p,
             phi::State::Dead(p) => p,
         };
         PeerStatus {
-            id: item.id.clone(),
-            name: item.name.clone(),
-            address: item.address.clone(),
-            history: item.history(20),
-            phi: phi,
+            id: self.id,
+            name: self.name.clone(),
+            address: self.address.clone(),
+            resolved: self.resolved.map(|a| net::unmapped(a).to_string()),
+            history: self.history(20),
+            phi,
             state: st,
-            last: item.last(),
+            last: self.last(),
+            recorded: self.recorded(),
+            age: match self.last() {
+                0 => 0,
+                last => now.saturating_sub(last),
+            },
+            rtt: self.rtt,
+            detector: self.status.parameters(),
+            timeline: self.timeline.iter().cloned().collect(),
         }
     }
 }
@@ -70,37 +161,68 @@ pub struct InventoryActor {
     my_id: Uuid,
     inv: HashMap<Uuid, PeerMonitor>,
     fd: PhiAccrualFailureDetectorBuilder,
-    subs: bool,
-    monit: Option<Addr<MonitorActor>>,
+    subscribers: HashMap<Uuid, Recipient<Status>>,
+    timeseries: TimeSeries,
+    clock: Clock,
 }
 
 impl InventoryActor {
-    pub fn new(my_id: Uuid) -> Self {
+    pub fn new(
+        my_id: Uuid,
+        fd: PhiAccrualFailureDetectorBuilder,
+        timeseries: TimeSeries,
+    ) -> Self {
         InventoryActor {
             my_id,
             inv: HashMap::new(),
-            fd: PhiAccrualFailureDetectorBuilder::new(),
-            subs: false,
-            monit: None,
+            fd,
+            subscribers: HashMap::new(),
+            timeseries,
+            clock: Clock::System,
         }
     }
 
+    /// Reads the time from `clock` rather than the system clock.
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Records phi and level of every peer at the time series resolution.
+    fn sample(&self, ctx: &mut Context<Self>) {
+        let resolution = Duration::from_millis(self.timeseries.resolution() as u64);
+        ctx.run_interval(resolution, |actor, _ctx| {
+            let now = actor.clock.now();
+            for peer in actor.inv.values() {
+                let phi = match peer.state(now) {
+                    State::Alive(p) | State::Dead(p) => p,
+                };
+                actor.timeseries.record(peer.id, now, phi, peer.level(now));
+            }
+        });
+    }
+
     fn get_status(&self) -> Status {
-        messages::Status(
-            self.inv
-                .iter()
-                .map(|(_id, peer)| PeerStatus::from(peer))
-                .collect(),
-        )
+        let now = self.clock.now();
+        messages::Status(self.inv.values().map(|peer| peer.status(now)).collect())
     }
 
+    /// Timelines advance on every tick, but status is only computed while
+    /// someone is subscribed; subscribers that went away without
+    /// unsubscribing are dropped.
     fn push_status(&self, ctx: &mut Context<Self>) {
-        ctx.run_interval(Duration::new(1, 0), |actor, _ctx| {
-            if actor.subs {
-                if let Some(addr) = &actor.monit {
-                    addr.do_send(actor.get_status());
-                }
+        ctx.run_interval(STATUS_INTERVAL, |actor, _ctx| {
+            let now = actor.clock.now();
+            for peer in actor.inv.values_mut() {
+                peer.observe(now);
             }
+            if actor.subscribers.is_empty() {
+                return;
+            }
+            let status = actor.get_status();
+            actor.subscribers.retain(|_id, addr| {
+                !matches!(addr.do_send(status.clone()), Err(SendError::Closed(_)))
+            });
         });
     }
 }
@@ -110,6 +232,7 @@ impl Actor for InventoryActor {
 
     fn started(&mut self, ctx: &mut Self::Context) {
         self.push_status(ctx);
+        self.sample(ctx);
     }
 }
 
@@ -119,38 +242,240 @@ impl Handler<Monitor> for InventoryActor {
     fn handle(&mut self, msg: Monitor, _ctx: &mut Context<Self>) {
         match msg {
             Monitor::Register(peer) => {
-                let monit = PeerMonitor::new(peer.id, peer.name, peer.address, self.fd.build());
+                let config = peer.detector.unwrap_or_default();
+                if let Some(monit) = self.inv.get_mut(&peer.id) {
+                    if let Err(e) = monit.reconfigure(&self.fd, config) {
+                        warn!("Keeping detector of {}: {}", peer.id, e);
+                    }
+                    monit.name = peer.name;
+                    monit.address = peer.address;
+                    return;
+                }
+                let detector = config.builder(&self.fd).build();
+                let mut monit = PeerMonitor::new(peer.id, peer.name, peer.address, detector);
+                monit.config = config;
                 self.inv.insert(peer.id, monit);
             }
             Monitor::UnRegister(uuid) => {
                 self.inv.remove(&uuid);
+                self.timeseries.remove(&uuid);
             }
         };
     }
 }
 
-impl Handler<HeartBeat> for InventoryActor {
+/// Replaces the node-wide detector defaults and rebuilds the detector of
+/// every peer under its own overrides, keeping heartbeat histories.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct DetectorDefaults(pub PhiAccrualFailureDetectorBuilder);
+
+impl Handler<DetectorDefaults> for InventoryActor {
     type Result = ();
 
-    fn handle(&mut self, msg: HeartBeat, _ctx: &mut Context<Self>) {
-        if let HeartBeat::Pong(id, ts) = msg {
-            if let Some(p) = self.inv.get_mut(&id) {
-                p.status.heartbeat(ts);
+    fn handle(&mut self, msg: DetectorDefaults, _ctx: &mut Context<Self>) {
+        self.fd = msg.0;
+        for (id, monit) in self.inv.iter_mut() {
+            let config = monit.config.clone();
+            if let Err(e) = monit.reconfigure(&self.fd, config) {
+                warn!("Keeping detector of {}: {}", id, e);
             }
         }
     }
 }
 
+impl Handler<Arrival> for InventoryActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: Arrival, _ctx: &mut Context<Self>) {
+        if let Some(p) = self.inv.get_mut(&msg.id) {
+            p.arrival(&msg);
+        }
+    }
+}
+
+impl Handler<Resolved> for InventoryActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: Resolved, _ctx: &mut Context<Self>) {
+        if let Some(p) = self.inv.get_mut(&msg.id) {
+            p.resolved = Some(msg.address);
+        }
+    }
+}
+
+impl Handler<GetStatus> for InventoryActor {
+    type Result = MessageResult<GetStatus>;
+
+    fn handle(&mut self, _msg: GetStatus, _ctx: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.get_status())
+    }
+}
+
+impl Handler<GetTimeSeries> for InventoryActor {
+    type Result = MessageResult<GetTimeSeries>;
+
+    fn handle(&mut self, msg: GetTimeSeries, _ctx: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.timeseries.query(msg.id, msg.from, msg.to, msg.step))
+    }
+}
+
 impl Handler<StatusEvent> for InventoryActor {
     type Result = ();
 
     fn handle(&mut self, msg: StatusEvent, _ctx: &mut Context<Self>) {
         match msg {
-            StatusEvent::Subscribe(addr) => {
-                self.monit = Some(addr);
-                self.subs = true;
+            StatusEvent::Subscribe(id, addr) => {
+                self.subscribers.insert(id, addr);
+            }
+            StatusEvent::UnSubscribe(id) => {
+                self.subscribers.remove(&id);
             }
-            StatusEvent::UnSubscribe => {}
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{DetectorDefaults, InventoryActor, PeerMonitor};
+    use crate::{
+        messages::{Arrival, GetStatus, Level, Monitor, Status, StatusEvent},
+        peer::Peer,
+        phi::{DetectorConfig, PhiAccrualFailureDetectorBuilder},
+        timeseries::{self, TimeSeries},
+    };
+    use actix::prelude::*;
+    use futures::{channel::mpsc, StreamExt};
+    use std::sync::{Arc, Mutex};
+    use uuid::Uuid;
+
+    /// Reports every status it gets under its name.
+    struct Collector(&'static str, mpsc::UnboundedSender<&'static str>);
+
+    impl Actor for Collector {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<Status> for Collector {
+        type Result = ();
+
+        fn handle(&mut self, _msg: Status, _ctx: &mut Context<Self>) {
+            let _ = self.1.unbounded_send(self.0);
+        }
+    }
+
+    #[test]
+    fn subscribers_are_independent() {
+        let received = actix_rt::System::new("subscribers").block_on(async {
+            let tiers = timeseries::parse_tiers(timeseries::DEFAULT_TIERS).unwrap();
+            let inventory = InventoryActor::new(
+                Uuid::new_v4(),
+                PhiAccrualFailureDetectorBuilder::new(),
+                TimeSeries::new(tiers),
+            )
+            .start();
+            let (tx, mut rx) = mpsc::unbounded();
+            let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+            let subscribe =
+                |id, name| StatusEvent::Subscribe(id, Collector(name, tx.clone()).start().recipient());
+            inventory.send(subscribe(a, "first")).await.unwrap();
+            inventory.send(subscribe(b, "second")).await.unwrap();
+
+            // a tick reaches both, the following ones only the second
+            let mut received = vec![rx.next().await.unwrap(), rx.next().await.unwrap()];
+            received.sort();
+            inventory.send(StatusEvent::UnSubscribe(a)).await.unwrap();
+            received.push(rx.next().await.unwrap());
+            received.push(rx.next().await.unwrap());
+            received
+        });
+
+        assert_eq!(received, ["first", "second", "second", "second"]);
+    }
+
+    #[test]
+    fn timeline_and_rtt() {
+        let id = Uuid::new_v4();
+        let detector = PhiAccrualFailureDetectorBuilder::new().build();
+        let mut peer = PeerMonitor::new(id, "a".to_owned(), "a:9000".to_owned(), detector);
+
+        let mut received = 1_000_000;
+        for seq in 0..20 {
+            received += 500;
+            let sent = received - if seq == 0 { 8 } else { 16 };
+            peer.arrival(&Arrival {
+                id,
+                seq,
+                sent,
+                received,
+            });
+        }
+        let rtt = peer.rtt().unwrap();
+        assert!(rtt > 14.0 && rtt < 16.0);
+
+        for elapsed in (100..60_000).step_by(100) {
+            peer.observe(received + elapsed);
+        }
+        let levels: Vec<_> = peer.timeline.iter().map(|span| span.level).collect();
+        assert_eq!(levels, vec![Level::Alive, Level::Suspect, Level::Dead]);
+
+        let status = peer.status(received + 60_000);
+        assert_eq!(status.timeline.len(), 3);
+        assert_eq!(status.detector.threshold, 16.0);
+    }
+
+    #[test]
+    fn new_defaults_keep_history() {
+        let statuses = Arc::new(Mutex::new(Vec::new()));
+        let collected = statuses.clone();
+
+        System::run(move || {
+            let tiers = timeseries::parse_tiers(timeseries::DEFAULT_TIERS).unwrap();
+            let inventory = InventoryActor::new(
+                Uuid::new_v4(),
+                PhiAccrualFailureDetectorBuilder::new(),
+                TimeSeries::new(tiers),
+            )
+            .start();
+            let plain = Peer::new(Uuid::new_v4(), "a".to_owned(), "a:9000".to_owned());
+            let mut tuned = Peer::new(Uuid::new_v4(), "b".to_owned(), "b:9000".to_owned());
+            tuned.detector = Some(DetectorConfig {
+                threshold: Some(12.0),
+                ..DetectorConfig::default()
+            });
+            for peer in &[plain, tuned] {
+                inventory.do_send(Monitor::Register(peer.clone()));
+                for seq in 0..5 {
+                    let received = 1_000_000 + seq as u128 * 500;
+                    inventory.do_send(Arrival {
+                        id: peer.id,
+                        seq,
+                        sent: received - 10,
+                        received,
+                    });
+                }
+            }
+            let defaults = DetectorConfig {
+                threshold: Some(3.0),
+                ..DetectorConfig::default()
+            };
+            inventory.do_send(DetectorDefaults(
+                defaults.builder(&PhiAccrualFailureDetectorBuilder::new()),
+            ));
+
+            actix_rt::spawn(async move {
+                let status = inventory.send(GetStatus).await.unwrap();
+                let mut peers = status.0;
+                peers.sort_by(|x, y| x.name.cmp(&y.name));
+                *collected.lock().unwrap() = peers
+                    .iter()
+                    .map(|p| (p.detector.threshold, p.recorded))
+                    .collect();
+                System::current().stop();
+            });
+        })
+        .unwrap();
+
+        assert_eq!(*statuses.lock().unwrap(), vec![(3.0, 4), (12.0, 4)]);
+    }
+}
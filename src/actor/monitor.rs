@@ -1,12 +1,28 @@
-use super::inventory::InventoryActor;
-use crate::messages;
+use super::{
+    heartbeat::UdpActor,
+    inventory::{InventoryActor, STATUS_INTERVAL},
+};
+use crate::{
+    auth::{AuthError, Role},
+    messages::{self, Monitor, PeerStatus},
+    net,
+    phi,
+    protocol::{
+        self, Command, DeltaEncoder, Envelope, Event, Request, StreamMode, DEFAULT_PHI_EPSILON,
+        PROTOCOL_VERSION,
+    },
+    sse::EventLog,
+};
 use actix::prelude::*;
 use actix::{Actor, Handler, StreamHandler};
 use actix_web_actors::ws;
+use bytes::Bytes;
 use dev::{MessageResponse, ResponseChannel};
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use log::{info, warn};
 use serde_json as json;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     time::{Duration, Instant},
 };
 use uuid::Uuid;
@@ -14,6 +30,12 @@ use ws::WebsocketContext;
 
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Events kept for `Last-Event-ID` resume, a few minutes of status ticks.
+const EVENT_LOG_SIZE: usize = 256;
+/// How long the inventory subscription outlives the last client: about as
+/// long as the log covers, so a client reconnecting within it resumes.
+const LOG_RETENTION: Duration =
+    Duration::from_secs(STATUS_INTERVAL.as_secs() * EVENT_LOG_SIZE as u64);
 
 #[derive(Message)]
 #[rtype(result = "ResponseId")]
@@ -42,17 +64,99 @@ pub struct Disconnect {
     pub id: Uuid,
 }
 
-pub struct MonitorActor(
-    pub HashMap<Uuid, Recipient<messages::Status>>,
-    pub Addr<InventoryActor>,
-);
+/// Opens a Server-Sent Events stream, replaying the events after
+/// `last_event_id` when they are still in the log.
+#[derive(Message)]
+#[rtype(result = "UnboundedReceiver<Bytes>")]
+pub struct ConnectEvents {
+    pub last_event_id: Option<u64>,
+}
+
+/// Fans status out to the websocket sessions and event streams, subscribed
+/// to the inventory only while at least one of them is connected.
+pub struct MonitorActor {
+    id: Uuid,
+    sessions: HashMap<Uuid, Recipient<messages::Status>>,
+    streams: std::vec::Vec<UnboundedSender<Bytes>>,
+    inventory: Addr<InventoryActor>,
+    log: EventLog,
+    /// Latest status seen, to detect state transitions for the streams.
+    seen: HashMap<Uuid, PeerStatus>,
+    subscribed: bool,
+    /// Ends the subscription once nobody reconnected for `LOG_RETENTION`.
+    idle: Option<SpawnHandle>,
+}
 
 impl MonitorActor {
+    pub fn new(inventory: Addr<InventoryActor>) -> Self {
+        MonitorActor {
+            id: Uuid::new_v4(),
+            sessions: HashMap::new(),
+            streams: std::vec::Vec::new(),
+            inventory,
+            log: EventLog::new(EVENT_LOG_SIZE),
+            seen: HashMap::new(),
+            subscribed: false,
+            idle: None,
+        }
+    }
+
     fn notify_status(&self, msg: messages::Status) {
-        for (_, addr) in self.0.iter() {
+        for addr in self.sessions.values() {
             let _ = addr.do_send(msg.clone());
         }
     }
+
+    /// Records the tick, preceded by any state transitions, in the event log
+    /// and forwards it to the event streams.
+    fn notify_streams(&mut self, msg: &messages::Status, ctx: &mut Context<Self>) {
+        let mut frames = std::vec::Vec::new();
+        for transition in protocol::transitions(&self.seen, &msg.0, phi::now()) {
+            let data = json::to_string(&transition).unwrap();
+            frames.push(self.log.push("transition", &data));
+        }
+        self.seen = msg.0.iter().map(|s| (s.id, s.clone())).collect();
+        let data = json::to_string(&msg.0).unwrap();
+        frames.push(self.log.push("status", &data));
+
+        self.streams.retain(|tx| {
+            frames
+                .iter()
+                .all(|frame| tx.unbounded_send(frame.clone()).is_ok())
+        });
+        self.update_subscription(ctx);
+    }
+
+    /// Keeps the inventory subscription in line with the connected clients.
+    /// After the last one left, the log keeps recording until it would have
+    /// aged out, so a client reconnecting meanwhile resumes without a gap.
+    /// Then events go unrecorded, so the log is cleared and reconnecting
+    /// streams start over from a snapshot.
+    fn update_subscription(&mut self, ctx: &mut Context<Self>) {
+        let wanted = !self.sessions.is_empty() || !self.streams.is_empty();
+        if wanted {
+            if let Some(idle) = self.idle.take() {
+                ctx.cancel_future(idle);
+            }
+            if !self.subscribed {
+                self.subscribed = true;
+                self.inventory.do_send(messages::StatusEvent::Subscribe(
+                    self.id,
+                    ctx.address().recipient(),
+                ));
+            }
+        } else if self.subscribed && self.idle.is_none() {
+            self.idle = Some(ctx.run_later(LOG_RETENTION, |actor, _ctx| {
+                actor.idle = None;
+                actor.subscribed = false;
+                actor
+                    .inventory
+                    .do_send(messages::StatusEvent::UnSubscribe(actor.id));
+                actor.log.clear();
+                actor.seen.clear();
+            }));
+        }
+    }
 }
 
 impl Actor for MonitorActor {
@@ -63,14 +167,12 @@ impl Handler<Connect> for MonitorActor {
     type Result = ResponseId;
 
     fn handle(&mut self, msg: Connect, ctx: &mut Context<Self>) -> Self::Result {
-        println!("Someone joined");
+        info!("Someone joined");
 
         // register session with random id
         let id = ResponseId(uuid::Uuid::new_v4());
-        self.0.insert(id.0, msg.addr);
-
-        self.1
-            .do_send(messages::StatusEvent::Subscribe(ctx.address()));
+        self.sessions.insert(id.0, msg.addr);
+        self.update_subscription(ctx);
         id
     }
 }
@@ -79,20 +181,39 @@ impl Handler<Connect> for MonitorActor {
 impl Handler<Disconnect> for MonitorActor {
     type Result = ();
 
-    fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) {
-        println!("Someone disconnected");
+    fn handle(&mut self, msg: Disconnect, ctx: &mut Context<Self>) {
+        info!("Someone disconnected");
+
+        self.sessions.remove(&msg.id);
+        self.update_subscription(ctx);
+    }
+}
+
+impl Handler<ConnectEvents> for MonitorActor {
+    type Result = MessageResult<ConnectEvents>;
 
-        self.0.remove(&msg.id);
-        if self.0.is_empty() {
-            self.1.do_send(messages::StatusEvent::UnSubscribe);
+    /// Without a usable `Last-Event-ID` the stream starts with the next
+    /// status tick, which is a full snapshot.
+    fn handle(&mut self, msg: ConnectEvents, ctx: &mut Context<Self>) -> Self::Result {
+        info!("Event stream connected");
+
+        let (tx, rx) = mpsc::unbounded();
+        if let Some(replay) = msg.last_event_id.and_then(|id| self.log.since(id)) {
+            for frame in replay {
+                let _ = tx.unbounded_send(frame);
+            }
         }
+        self.streams.push(tx);
+        self.update_subscription(ctx);
+        MessageResult(rx)
     }
 }
 
 impl Handler<messages::Status> for MonitorActor {
     type Result = ();
 
-    fn handle(&mut self, msg: messages::Status, _ctx: &mut Self::Context) {
+    fn handle(&mut self, msg: messages::Status, ctx: &mut Self::Context) {
+        self.notify_streams(&msg, ctx);
         self.notify_status(msg);
     }
 }
@@ -101,14 +222,47 @@ pub struct MonitorSession {
     pub id: Uuid,
     pub hb: Instant,
     pub monit: Addr<MonitorActor>,
+    inventory: Addr<InventoryActor>,
+    heartbeat: Addr<UdpActor>,
+    /// Whether this session may register and unregister peers.
+    role: Role,
+    peers: Option<HashSet<Uuid>>,
+    mode: StreamMode,
+    interval: Duration,
+    pushed: Option<Instant>,
+    /// Latest status seen, to detect state transitions.
+    seen: HashMap<Uuid, PeerStatus>,
+    encoder: DeltaEncoder,
 }
 
 impl MonitorSession {
+    pub fn new(
+        monit: Addr<MonitorActor>,
+        inventory: Addr<InventoryActor>,
+        heartbeat: Addr<UdpActor>,
+        role: Role,
+    ) -> Self {
+        MonitorSession {
+            id: Uuid::new_v4(),
+            hb: Instant::now(),
+            monit,
+            inventory,
+            heartbeat,
+            role,
+            peers: None,
+            mode: StreamMode::Deltas,
+            interval: STATUS_INTERVAL,
+            pushed: None,
+            seen: HashMap::new(),
+            encoder: DeltaEncoder::new(DEFAULT_PHI_EPSILON),
+        }
+    }
+
     fn hb(&self, ctx: &mut ws::WebsocketContext<Self>) {
         ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
             // check client heartbeats
             if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
-                println!("Websocket Client heartbeat failed, disconnecting!");
+                warn!("Websocket Client heartbeat failed, disconnecting!");
 
                 act.monit.do_send(Disconnect { id: act.id });
 
@@ -120,6 +274,148 @@ impl MonitorSession {
             ctx.ping(b"");
         });
     }
+
+    fn send(&self, ctx: &mut ws::WebsocketContext<Self>, id: Option<u64>, event: Event) {
+        ctx.text(json::to_string(&Envelope::new(id, event)).unwrap());
+    }
+
+    fn error(&self, ctx: &mut ws::WebsocketContext<Self>, id: Option<u64>, message: String) {
+        self.send(ctx, id, Event::Error { message });
+    }
+
+    fn subscribed(&self, status: messages::Status) -> std::vec::Vec<PeerStatus> {
+        match &self.peers {
+            Some(peers) => status
+                .0
+                .into_iter()
+                .filter(|s| peers.contains(&s.id))
+                .collect(),
+            None => status.0,
+        }
+    }
+
+    fn push(&mut self, ctx: &mut ws::WebsocketContext<Self>, peers: std::vec::Vec<PeerStatus>) {
+        let event = match self.mode {
+            StreamMode::Full => Some(self.encoder.snapshot(peers)),
+            StreamMode::Deltas => self.encoder.encode(peers),
+            StreamMode::Transitions => None,
+        };
+        if let Some(event) = event {
+            self.send(ctx, None, event);
+        }
+        self.pushed = Some(Instant::now());
+    }
+
+    /// Restarts the stream with a fresh snapshot, on connect or when the
+    /// client lost track of the sequence.
+    fn resync(&mut self, ctx: &mut ws::WebsocketContext<Self>, id: Option<u64>) {
+        self.encoder.reset();
+        self.inventory
+            .send(messages::GetStatus)
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(status) => {
+                        let peers = act.subscribed(status);
+                        let event = act.encoder.snapshot(peers);
+                        act.send(ctx, id, event);
+                    }
+                    Err(e) => act.error(ctx, id, e.to_string()),
+                }
+                fut::ready(())
+            })
+            .spawn(ctx);
+    }
+
+    fn command(&mut self, ctx: &mut ws::WebsocketContext<Self>, text: &str) {
+        let req: Request = match json::from_str(text) {
+            Ok(req) => req,
+            Err(e) => return self.error(ctx, None, format!("invalid request: {}", e)),
+        };
+        if req.v != PROTOCOL_VERSION {
+            return self.error(
+                ctx,
+                req.id,
+                format!("unsupported protocol version {}", req.v),
+            );
+        }
+
+        let id = req.id;
+        match req.command {
+            Command::Subscribe { peers } => {
+                self.peers = peers.map(|p| p.into_iter().collect());
+                self.seen.clear();
+                self.resync(ctx, id);
+            }
+            Command::SetRate { interval_ms } => {
+                let interval = Duration::from_millis(interval_ms);
+                if interval < STATUS_INTERVAL {
+                    return self.error(
+                        ctx,
+                        id,
+                        format!(
+                            "interval must be at least {}ms",
+                            STATUS_INTERVAL.as_millis()
+                        ),
+                    );
+                }
+                self.interval = interval;
+                self.send(ctx, id, Event::Ack);
+            }
+            Command::SetMode { mode, epsilon } => {
+                self.mode = mode;
+                if let Some(epsilon) = epsilon {
+                    self.encoder.set_epsilon(epsilon);
+                }
+                self.encoder.reset();
+                self.send(ctx, id, Event::Ack);
+            }
+            Command::Resync => self.resync(ctx, id),
+            Command::Snapshot => {
+                self.inventory
+                    .send(messages::GetStatus)
+                    .into_actor(self)
+                    .then(move |res, act, ctx| {
+                        match res {
+                            Ok(status) => {
+                                let peers = act.subscribed(status);
+                                act.send(ctx, id, Event::Status { seq: None, peers });
+                            }
+                            Err(e) => act.error(ctx, id, e.to_string()),
+                        }
+                        fut::ready(())
+                    })
+                    .spawn(ctx);
+            }
+            Command::Register { .. } | Command::Unregister { .. } if self.role < Role::Admin => {
+                self.error(ctx, id, AuthError::Forbidden.to_string())
+            }
+            Command::Register { peer } => {
+                if let Some(Err(e)) = peer.detector.as_ref().map(|c| c.validate()) {
+                    return self.error(ctx, id, e);
+                }
+                if let Err(e) = net::split_host_port(&peer.address) {
+                    return self.error(ctx, id, e);
+                }
+                self.forward(ctx, id, Monitor::Register(peer))
+            }
+            Command::Unregister { id: peer } => self.forward(ctx, id, Monitor::UnRegister(peer)),
+        }
+    }
+
+    fn forward(&mut self, ctx: &mut ws::WebsocketContext<Self>, id: Option<u64>, msg: Monitor) {
+        self.heartbeat
+            .send(msg)
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(_) => act.send(ctx, id, Event::Ack),
+                    Err(e) => act.error(ctx, id, e.to_string()),
+                }
+                fut::ready(())
+            })
+            .spawn(ctx);
+    }
 }
 
 impl Actor for MonitorSession {
@@ -128,6 +424,7 @@ impl Actor for MonitorSession {
     fn started(&mut self, ctx: &mut Self::Context) {
         // we'll start heartbeat process on session start.
         self.hb(ctx);
+        self.resync(ctx, None);
 
         let addr = ctx.address();
         self.monit
@@ -170,8 +467,10 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for MonitorSession {
             ws::Message::Pong(_) => {
                 self.hb = Instant::now();
             }
-            ws::Message::Text(_) => {}
-            ws::Message::Binary(_) => {}
+            ws::Message::Text(text) => self.command(ctx, &text),
+            ws::Message::Binary(_) => {
+                self.error(ctx, None, "binary frames are not supported".to_owned())
+            }
             ws::Message::Close(reason) => {
                 ctx.close(reason);
                 ctx.stop();
@@ -187,7 +486,21 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for MonitorSession {
 impl Handler<messages::Status> for MonitorSession {
     type Result = ();
 
+    /// Transitions are reported on every status tick, the status itself at
+    /// most once per requested interval.
     fn handle(&mut self, msg: messages::Status, ctx: &mut Self::Context) {
-        ctx.text(json::to_string(&msg).unwrap());
+        let peers = self.subscribed(msg);
+        for transition in protocol::transitions(&self.seen, &peers, phi::now()) {
+            self.send(ctx, None, Event::Transition(transition));
+        }
+        self.seen = peers.iter().map(|s| (s.id, s.clone())).collect();
+
+        let due = match self.pushed {
+            Some(at) => at.elapsed() + STATUS_INTERVAL / 2 >= self.interval,
+            None => true,
+        };
+        if due {
+            self.push(ctx, peers);
+        }
     }
 }
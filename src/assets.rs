@@ -0,0 +1,70 @@
+//! Dashboard files compiled into the binary, so a node serves its dashboard
+//! without reading `static/` at runtime or reaching any outside network.
+
+use actix_web::{web, HttpResponse};
+
+pub struct Asset {
+    pub name: &'static str,
+    pub content_type: &'static str,
+    pub body: &'static [u8],
+}
+
+macro_rules! asset {
+    ($name:expr, $content_type:expr) => {
+        Asset {
+            name: $name,
+            content_type: $content_type,
+            body: include_bytes!(concat!("../static/", $name)),
+        }
+    };
+}
+
+pub const ASSETS: &[Asset] = &[
+    asset!("index.html", "text/html; charset=utf-8"),
+    asset!("dashboard.css", "text/css; charset=utf-8"),
+    asset!("dashboard.js", "application/javascript; charset=utf-8"),
+    asset!("chart.js", "application/javascript; charset=utf-8"),
+];
+
+pub fn get(name: &str) -> Option<&'static Asset> {
+    ASSETS.iter().find(|asset| asset.name == name)
+}
+
+pub async fn serve(name: web::Path<String>) -> HttpResponse {
+    match get(&name) {
+        Some(asset) => HttpResponse::Ok()
+            .content_type(asset.content_type)
+            .body(asset.body),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get, ASSETS};
+
+    /// URLs that are shown to the user rather than loaded.
+    const ALLOWED: &[(&str, &str)] =
+        &[("index.html", "placeholder=\"Node URL (http://host:port)\"")];
+
+    #[test]
+    fn dashboard_is_self_contained() {
+        assert!(get("index.html").is_some());
+        assert!(get("../Cargo.toml").is_none());
+
+        for asset in ASSETS {
+            let mut body = String::from_utf8_lossy(asset.body).into_owned();
+            for (name, allowed) in ALLOWED {
+                if *name == asset.name {
+                    assert!(body.contains(allowed), "{} no longer has {}", name, allowed);
+                    body = body.replace(allowed, "");
+                }
+            }
+            assert!(
+                !body.contains("https://") && !body.contains("http://"),
+                "{} loads an external resource",
+                asset.name
+            );
+        }
+    }
+}
@@ -0,0 +1,295 @@
+//! API authentication: static bearer tokens and client certificate
+//! identities, each granting a read-only or admin role. With nothing
+//! configured the API stays open, as before.
+
+use actix_web::{
+    dev::Payload, http::StatusCode, web, FromRequest, HttpRequest, HttpResponse, ResponseError,
+};
+use futures::future::{ready, Ready};
+use std::{collections::HashMap, fmt, str::FromStr};
+
+/// What a client may do; admins may also do everything readers may.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    /// Peer status, history, events and the dashboard.
+    Read,
+    /// Registering and removing peers.
+    Admin,
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Role::Read),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!("unknown role {:?}, expected read or admin", s)),
+        }
+    }
+}
+
+/// Subject common name of a verified client certificate. The TLS listener
+/// puts an `Option<ClientIdentity>` in the extensions of every request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientIdentity(pub String);
+
+#[derive(Debug, Clone, Default)]
+pub struct Auth {
+    tokens: std::vec::Vec<(String, Role)>,
+    clients: HashMap<String, Role>,
+}
+
+impl Auth {
+    pub fn new() -> Self {
+        Auth::default()
+    }
+
+    pub fn token(mut self, token: impl Into<String>, role: Role) -> Self {
+        self.tokens.push((token.into(), role));
+        self
+    }
+
+    pub fn client(mut self, subject: impl Into<String>, role: Role) -> Self {
+        self.clients.insert(subject.into(), role);
+        self
+    }
+
+    /// Builds the configuration from `role:value` pairs separated by commas,
+    /// such as `admin:s3cret,read:viewer`, for tokens and for client
+    /// certificate subjects.
+    pub fn parse(tokens: &str, clients: &str) -> Result<Self, String> {
+        let split = |spec: &str| -> std::vec::Vec<String> {
+            spec.split(',').map(str::to_owned).collect()
+        };
+        Auth::from_entries(&split(tokens), &split(clients))
+    }
+
+    /// Same as `parse`, with one `role:value` entry per item.
+    pub fn from_entries(tokens: &[String], clients: &[String]) -> Result<Self, String> {
+        let mut auth = Auth::new();
+        for (role, token) in pairs(tokens)? {
+            auth = auth.token(token, role);
+        }
+        for (role, subject) in pairs(clients)? {
+            auth = auth.client(subject, role);
+        }
+        Ok(auth)
+    }
+
+    /// Whether any credential is configured; if not, every request is admin.
+    pub fn enabled(&self) -> bool {
+        !self.tokens.is_empty() || !self.clients.is_empty()
+    }
+
+    /// Highest role granted by the token or the client certificate, `None`
+    /// when neither is known.
+    pub fn role(&self, token: Option<&str>, client: Option<&ClientIdentity>) -> Option<Role> {
+        if !self.enabled() {
+            return Some(Role::Admin);
+        }
+        // compare against every token so timing does not reveal which matched
+        let by_token = token.and_then(|token| {
+            self.tokens
+                .iter()
+                .filter(|(known, _)| constant_time_eq(known.as_bytes(), token.as_bytes()))
+                .map(|(_, role)| *role)
+                .fold(None, |best: Option<Role>, role| best.max(Some(role)))
+        });
+        let by_client = client.and_then(|c| self.clients.get(&c.0).copied());
+        by_token.max(by_client)
+    }
+
+    /// Checks that the request carries credentials for at least `required`.
+    pub fn authorize(&self, req: &HttpRequest, required: Role) -> Result<Role, AuthError> {
+        let token = bearer(req);
+        let extensions = req.extensions();
+        let client = extensions
+            .get::<Option<ClientIdentity>>()
+            .and_then(Option::as_ref);
+        match self.role(token.as_deref(), client) {
+            Some(role) if role >= required => Ok(role),
+            Some(_) => Err(AuthError::Forbidden),
+            None => Err(AuthError::Unauthenticated),
+        }
+    }
+}
+
+fn pairs(entries: &[String]) -> Result<std::vec::Vec<(Role, String)>, String> {
+    entries
+        .iter()
+        .map(|e| e.trim())
+        .filter(|p| !p.is_empty())
+        .map(|part| {
+            let mut fields = part.splitn(2, ':');
+            let role = fields.next().unwrap_or("").trim().parse()?;
+            match fields.next().map(str::trim) {
+                Some(value) if !value.is_empty() => Ok((role, value.to_owned())),
+                _ => Err(format!("{:?} must be written as role:value", part)),
+            }
+        })
+        .collect()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Token from the `Authorization: Bearer` header, or from the `token` query
+/// parameter for clients that cannot set headers (browser WebSocket and
+/// EventSource).
+fn bearer(req: &HttpRequest) -> Option<String> {
+    let header = req
+        .headers()
+        .get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| {
+            let mut parts = v.splitn(2, ' ');
+            match (parts.next(), parts.next()) {
+                (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") => {
+                    Some(token.trim().to_owned())
+                }
+                _ => None,
+            }
+        });
+    header.or_else(|| {
+        web::Query::<HashMap<String, String>>::from_query(req.query_string())
+            .ok()
+            .and_then(|q| q.into_inner().remove("token"))
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthError {
+    /// Missing or unknown credentials.
+    Unauthenticated,
+    /// Known credentials without the required role.
+    Forbidden,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Unauthenticated => write!(f, "missing or invalid credentials"),
+            AuthError::Forbidden => write!(f, "admin role required"),
+        }
+    }
+}
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::Unauthenticated => StatusCode::UNAUTHORIZED,
+            AuthError::Forbidden => StatusCode::FORBIDDEN,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut res = HttpResponse::build(self.status_code());
+        if *self == AuthError::Unauthenticated {
+            res.header("WWW-Authenticate", "Bearer");
+        }
+        res.body(self.to_string())
+    }
+}
+
+fn extract(req: &HttpRequest, required: Role) -> Result<Role, AuthError> {
+    match req.app_data::<web::Data<Auth>>() {
+        Some(auth) => auth.authorize(req, required),
+        // fail closed if the server was set up without an `Auth`
+        None => Err(AuthError::Unauthenticated),
+    }
+}
+
+/// Extractor for handlers that need at least read access; holds the role
+/// actually granted.
+pub struct Reader(pub Role);
+
+impl FromRequest for Reader {
+    type Error = AuthError;
+    type Future = Ready<Result<Self, AuthError>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(extract(req, Role::Read).map(Reader))
+    }
+}
+
+/// Extractor for handlers that change the monitored peers.
+pub struct Admin;
+
+impl FromRequest for Admin {
+    type Error = AuthError;
+    type Future = Ready<Result<Self, AuthError>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(extract(req, Role::Admin).map(|_| Admin))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Admin, Auth, AuthError, ClientIdentity, Reader, Role};
+    use actix_web::{test::TestRequest, FromRequest};
+    use futures::executor::block_on;
+
+    #[test]
+    fn roles_from_tokens_and_clients() {
+        let auth = Auth::parse("admin:s3cret, read:viewer", "read:dashboard").unwrap();
+        assert_eq!(auth.role(Some("s3cret"), None), Some(Role::Admin));
+        assert_eq!(auth.role(Some("viewer"), None), Some(Role::Read));
+        assert_eq!(auth.role(Some("s3cre"), None), None);
+        assert_eq!(auth.role(None, None), None);
+        let client = ClientIdentity("dashboard".to_owned());
+        assert_eq!(auth.role(None, Some(&client)), Some(Role::Read));
+        assert_eq!(auth.role(Some("s3cret"), Some(&client)), Some(Role::Admin));
+
+        assert_eq!(Auth::new().role(None, None), Some(Role::Admin));
+        assert!(Auth::parse("root:x", "").is_err());
+        assert!(Auth::parse("admin", "").is_err());
+        assert!(Auth::parse("", "read:").is_err());
+    }
+
+    #[test]
+    fn extractors_check_roles() {
+        let auth = Auth::new()
+            .token("s3cret", Role::Admin)
+            .token("viewer", Role::Read);
+        let request = |header: Option<&str>, uri: &str| {
+            let req = TestRequest::with_uri(uri).data(auth.clone());
+            match header {
+                Some(value) => req.header("Authorization", value),
+                None => req,
+            }
+            .to_http_request()
+        };
+        let admin = |req| block_on(Admin::from_request(&req, &mut actix_web::dev::Payload::None));
+        let reader =
+            |req| block_on(Reader::from_request(&req, &mut actix_web::dev::Payload::None));
+
+        assert!(admin(request(Some("Bearer s3cret"), "/")).is_ok());
+        assert_eq!(
+            admin(request(Some("Bearer viewer"), "/")).err(),
+            Some(AuthError::Forbidden)
+        );
+        assert_eq!(
+            reader(request(None, "/")).err(),
+            Some(AuthError::Unauthenticated)
+        );
+        assert_eq!(reader(request(None, "/ws/?token=viewer")).unwrap().0, Role::Read);
+        assert_eq!(
+            reader(request(Some("Basic dmlld2Vy"), "/")).err(),
+            Some(AuthError::Unauthenticated)
+        );
+        let unconfigured = TestRequest::default().to_http_request();
+        assert_eq!(
+            block_on(Reader::from_request(
+                &unconfigured,
+                &mut actix_web::dev::Payload::None
+            ))
+            .err(),
+            Some(AuthError::Unauthenticated)
+        );
+    }
+}
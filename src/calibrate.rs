@@ -0,0 +1,271 @@
+use std::{collections::HashMap, io};
+use uuid::Uuid;
+
+use crate::{
+    phi::{Mode, PhiAccrualFailureDetector, PhiAccrualFailureDetectorBuilder},
+    trace::{self, TraceRecord},
+};
+
+/// Longest silence the search for the suspicion point will consider.
+const MAX_SUSPICION_MS: u128 = 60 * 60 * 1000;
+
+const USAGE: &str = "usage: phifd calibrate <trace> [--thresholds 1,2,4,8,16] \
+                     [--windows 50,100,200,1000] [--mode normal|histogram]";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub trace: String,
+    pub thresholds: std::vec::Vec<f64>,
+    pub windows: std::vec::Vec<usize>,
+    pub mode: Mode,
+}
+
+impl Options {
+    pub fn parse(args: &[String]) -> Result<Options, String> {
+        let mut options = Options {
+            trace: String::new(),
+            thresholds: vec![1.0, 2.0, 4.0, 8.0, 16.0],
+            windows: vec![50, 100, 200, 1000],
+            mode: Mode::Normal,
+        };
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--thresholds" => options.thresholds = list(args.next())?,
+                "--windows" => options.windows = list(args.next())?,
+                "--mode" => {
+                    options.mode = match args.next().map(String::as_str) {
+                        Some("normal") => Mode::Normal,
+                        Some("histogram") => Mode::Histogram,
+                        other => return Err(format!("unknown mode {:?}", other)),
+                    }
+                }
+                flag if flag.starts_with("--") => return Err(format!("unknown option {}", flag)),
+                path if options.trace.is_empty() => options.trace = path.to_owned(),
+                extra => return Err(format!("unexpected argument {}", extra)),
+            }
+        }
+
+        if options.trace.is_empty() {
+            return Err("missing trace file".to_owned());
+        }
+        if options.thresholds.is_empty() || options.windows.contains(&0) {
+            return Err("thresholds must not be empty and windows must be positive".to_owned());
+        }
+        Ok(options)
+    }
+}
+
+fn list<T: std::str::FromStr>(arg: Option<&String>) -> Result<std::vec::Vec<T>, String> {
+    let arg = arg.ok_or_else(|| "missing value".to_owned())?;
+    arg.split(',')
+        .map(|v| {
+            v.trim()
+                .parse()
+                .map_err(|_| format!("invalid value {:?}", v))
+        })
+        .collect()
+}
+
+/// Outcome of replaying a trace through one detector setting.
+///
+/// Every gap between two heartbeats of a peer is a point where the peer could
+/// have crashed: the time the detector needs to suspect it from there is a
+/// detection time, and suspecting it before the next heartbeat arrived is a
+/// false positive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    pub threshold: f64,
+    pub window: usize,
+    pub gaps: usize,
+    pub false_positives: usize,
+    pub mean_detection: f64,
+    pub max_detection: u128,
+}
+
+impl Report {
+    pub fn false_positive_rate(&self) -> f64 {
+        if self.gaps == 0 {
+            return 0.0;
+        }
+        self.false_positives as f64 / self.gaps as f64
+    }
+}
+
+pub fn calibrate(
+    records: &[TraceRecord],
+    thresholds: &[f64],
+    windows: &[usize],
+    mode: Mode,
+) -> std::vec::Vec<Report> {
+    let mut arrivals: HashMap<Uuid, std::vec::Vec<u128>> = HashMap::new();
+    for record in records {
+        arrivals
+            .entry(record.id)
+            .or_default()
+            .push(record.timestamp);
+    }
+    for timestamps in arrivals.values_mut() {
+        timestamps.sort_unstable();
+        timestamps.dedup();
+    }
+
+    let mut reports = std::vec::Vec::new();
+    for &window in windows {
+        for &threshold in thresholds {
+            reports.push(evaluate(&arrivals, threshold, window, mode));
+        }
+    }
+    reports
+}
+
+fn evaluate(
+    arrivals: &HashMap<Uuid, std::vec::Vec<u128>>,
+    threshold: f64,
+    window: usize,
+    mode: Mode,
+) -> Report {
+    let mut report = Report {
+        threshold,
+        window,
+        gaps: 0,
+        false_positives: 0,
+        mean_detection: 0.0,
+        max_detection: 0,
+    };
+    let mut total_detection = 0.0;
+
+    for timestamps in arrivals.values() {
+        let mut detector = PhiAccrualFailureDetectorBuilder::new()
+            .with_threshold(threshold)
+            .with_sample_size(window)
+            .with_mode(mode)
+            .build();
+
+        for pair in timestamps.windows(2) {
+            detector.heartbeat(pair[0]);
+            let suspicion = suspicion_time(&detector);
+
+            report.gaps += 1;
+            if suspicion < pair[1] - pair[0] {
+                report.false_positives += 1;
+            }
+            total_detection += suspicion as f64;
+            report.max_detection = report.max_detection.max(suspicion);
+        }
+    }
+
+    if report.gaps > 0 {
+        report.mean_detection = total_detection / report.gaps as f64;
+    }
+    report
+}
+
+/// Milliseconds after the latest heartbeat at which the detector first
+/// considers the peer unavailable. Phi only grows with silence, so the point
+/// can be found with an exponential then a binary search.
+fn suspicion_time(detector: &PhiAccrualFailureDetector) -> u128 {
+    let last = detector.last();
+    let mut high = 1;
+    while detector.is_available(last + high) {
+        if high >= MAX_SUSPICION_MS {
+            return MAX_SUSPICION_MS;
+        }
+        high *= 2;
+    }
+
+    let mut low = 0;
+    while low + 1 < high {
+        let mid = (low + high) / 2;
+        if detector.is_available(last + mid) {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    high
+}
+
+/// Entry point of `phifd calibrate`.
+pub fn run(args: &[String]) -> io::Result<()> {
+    let options = Options::parse(args)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{}\n{}", e, USAGE)))?;
+    let records = trace::read(&options.trace)?;
+    let reports = calibrate(
+        &records,
+        &options.thresholds,
+        &options.windows,
+        options.mode,
+    );
+
+    println!(
+        "{:>10} {:>8} {:>8} {:>14} {:>14} {:>10} {:>10}",
+        "threshold", "window", "gaps", "mean_detect_ms", "max_detect_ms", "false_pos", "fp_rate"
+    );
+    for r in reports {
+        println!(
+            "{:>10.2} {:>8} {:>8} {:>14.1} {:>14} {:>10} {:>10.6}",
+            r.threshold,
+            r.window,
+            r.gaps,
+            r.mean_detection,
+            r.max_detection,
+            r.false_positives,
+            r.false_positive_rate()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{calibrate, Options};
+    use crate::{phi::Mode, trace::TraceRecord};
+
+    fn trace() -> std::vec::Vec<TraceRecord> {
+        let id = uuid::Uuid::new_v4();
+        let mut timestamp = 1_000_000;
+        let mut records = std::vec::Vec::new();
+        for i in 0..300 {
+            timestamp += if i == 250 { 2500 } else { 1000 + i % 3 * 20 };
+            records.push(TraceRecord { id, timestamp });
+        }
+        records
+    }
+
+    #[test]
+    fn threshold_trades_detection_for_mistakes() {
+        let reports = calibrate(&trace(), &[1.0, 16.0], &[200], Mode::Normal);
+        let (low, high) = (&reports[0], &reports[1]);
+
+        assert_eq!(low.gaps, 299);
+        assert!(low.false_positives >= 1);
+        assert_eq!(high.false_positives, 0);
+        assert!(low.mean_detection < high.mean_detection);
+        assert!(high.max_detection > 1000);
+    }
+
+    #[test]
+    fn reports_every_setting() {
+        let reports = calibrate(&trace(), &[2.0, 8.0], &[10, 100], Mode::Histogram);
+        let settings: std::vec::Vec<_> = reports.iter().map(|r| (r.window, r.threshold)).collect();
+        assert_eq!(settings, vec![(10, 2.0), (10, 8.0), (100, 2.0), (100, 8.0)]);
+    }
+
+    #[test]
+    fn parse_options() {
+        let args: std::vec::Vec<String> =
+            vec!["trace.csv", "--thresholds", "3,9", "--mode", "histogram"]
+                .into_iter()
+                .map(String::from)
+                .collect();
+        let options = Options::parse(&args).unwrap();
+        assert_eq!(options.trace, "trace.csv");
+        assert_eq!(options.thresholds, vec![3.0, 9.0]);
+        assert_eq!(options.mode, Mode::Histogram);
+
+        assert!(Options::parse(&[]).is_err());
+        assert!(Options::parse(&["t".to_owned(), "--windows".to_owned(), "0".to_owned()]).is_err());
+    }
+}
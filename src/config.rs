@@ -0,0 +1,1287 @@
+//! Node configuration: an optional TOML file, overridden by `PHI_*`
+//! environment variables and then by command line flags.
+
+use crate::{
+    auth::Auth,
+    discovery::{
+        self,
+        dns::{Record, Scheme},
+        multicast,
+    },
+    net::{self, Family},
+    peer::Peer,
+    phi::{DetectorConfig, PhiAccrualFailureDetectorBuilder},
+    timeseries::{self, Tier},
+    tls,
+};
+use log::info;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fs, io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use uuid::Uuid;
+
+pub const USAGE: &str = "\
+usage: phifd [run] [options]    start the node
+       phifd check [options]  validate the configuration and print it
+       phifd calibrate <trace> [calibrate options]
+       phifd help | version
+
+options:
+  -c, --config <file>        TOML configuration file (env PHI_CONFIG)
+      --id <uuid>            node id
+      --name <name>          node name
+      --state-dir <dir>      where the generated node id is kept
+      --address <host:port>  heartbeat address advertised to peers
+      --listen <host:port>   heartbeat address to bind, if not --address
+      --bind <host:port>     HTTP address
+      --url <url>            HTTP base URL advertised to other nodes
+      --seed <url>           node to join at startup, repeatable
+      --log <filter>         log level or env_logger filter";
+
+const LOG_LEVELS: &[&str] = &["off", "error", "warn", "info", "debug", "trace"];
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Base URLs of nodes whose `/api/info` is fetched and registered at
+    /// startup.
+    pub seeds: std::vec::Vec<String>,
+    pub node: Node,
+    pub http: Http,
+    pub heartbeat: Heartbeat,
+    /// Detector defaults for every peer.
+    pub detector: DetectorConfig,
+    pub timeseries: TimeSeries,
+    pub trace: Trace,
+    pub log: Log,
+    pub auth: AuthConfig,
+    pub tls: Option<Tls>,
+    /// Peers monitored from startup, with optional detector overrides.
+    pub peers: std::vec::Vec<Peer>,
+    pub discovery: Discovery,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Node {
+    /// Generated on first start and kept in `state_dir` when not set.
+    pub id: Option<Uuid>,
+    /// The host name when not set.
+    pub name: Option<String>,
+    /// UDP address peers send heartbeats to, as they can reach it.
+    pub address: Option<String>,
+    /// Local UDP address to bind, when it differs from `address`, such as
+    /// `0.0.0.0:9000` behind NAT or in a container.
+    pub listen: Option<String>,
+    /// Which address to use for hosts with both IPv4 and IPv6 ones.
+    pub address_family: Family,
+    pub state_dir: PathBuf,
+}
+
+impl Default for Node {
+    fn default() -> Self {
+        Node {
+            id: None,
+            name: None,
+            address: None,
+            listen: None,
+            address_family: Family::Any,
+            state_dir: PathBuf::from(".phifd"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Http {
+    pub bind: String,
+    /// Base URL other nodes and browsers reach the API at, returned by
+    /// `/api/info`.
+    pub url: Option<String>,
+}
+
+impl Default for Http {
+    fn default() -> Self {
+        Http {
+            bind: "127.0.0.1:8000".to_owned(),
+            url: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Heartbeat {
+    /// How often every peer is pinged, such as `150ms`.
+    pub ping_interval: String,
+    /// How often peer host names are resolved again; silent peers are
+    /// resolved sooner.
+    pub resolve_interval: String,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Heartbeat {
+            ping_interval: "150ms".to_owned(),
+            resolve_interval: "30s".to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeSeries {
+    pub tiers: String,
+}
+
+impl Default for TimeSeries {
+    fn default() -> Self {
+        TimeSeries {
+            tiers: timeseries::DEFAULT_TIERS.to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Trace {
+    /// File heartbeats are recorded to; recording is off without it.
+    pub path: Option<PathBuf>,
+    pub max_bytes: u64,
+    pub max_files: usize,
+}
+
+impl Default for Trace {
+    fn default() -> Self {
+        Trace {
+            path: None,
+            max_bytes: 64 * 1024 * 1024,
+            max_files: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Log {
+    /// `env_logger` filter, such as `info` or `warn,actix_web=info`.
+    pub level: String,
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Log {
+            level: "info".to_owned(),
+        }
+    }
+}
+
+/// Credentials as `role:value` entries; see `Auth::parse`.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub tokens: std::vec::Vec<String>,
+    pub clients: std::vec::Vec<String>,
+    /// Bearer token sent to the API of other nodes, when joining seeds and
+    /// identifying discovered nodes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tls {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    #[serde(default)]
+    pub client_ca: Option<PathBuf>,
+    #[serde(default = "required")]
+    pub client_auth: String,
+}
+
+fn required() -> String {
+    "required".to_owned()
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Discovery {
+    /// How often the sources are asked for the nodes they list.
+    pub interval: String,
+    /// JSON file listing peers, read again whenever it changes.
+    pub file: Option<PathBuf>,
+    pub dns: Option<DnsDiscovery>,
+    pub multicast: Option<MulticastDiscovery>,
+}
+
+impl Default for Discovery {
+    fn default() -> Self {
+        Discovery {
+            interval: "30s".to_owned(),
+            file: None,
+            dns: None,
+            multicast: None,
+        }
+    }
+}
+
+/// A DNS name listing the nodes, such as a Kubernetes headless service.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DnsDiscovery {
+    pub name: String,
+    #[serde(default = "srv")]
+    pub record: Record,
+    /// Whether the nodes' API is reached over `http` or `https`.
+    #[serde(default = "http")]
+    pub scheme: Scheme,
+    /// The nodes' API port, for A records; SRV records carry it.
+    #[serde(default)]
+    pub port: Option<u16>,
+    /// `ip` or `ip:port` of the DNS server, the system's when not set.
+    #[serde(default)]
+    pub nameserver: Option<String>,
+}
+
+fn srv() -> Record {
+    Record::Srv
+}
+
+fn http() -> Scheme {
+    Scheme::Http
+}
+
+/// Announcing this node on the LAN and registering the nodes announcing the
+/// same cluster.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MulticastDiscovery {
+    pub cluster: String,
+    /// Multicast group, or the IPv4 broadcast address, and port.
+    #[serde(default = "group")]
+    pub group: String,
+    /// IPv4 address of the interface to announce on, the default one when
+    /// not set.
+    #[serde(default)]
+    pub interface: Option<String>,
+    #[serde(default = "announce_interval")]
+    pub interval: String,
+    /// How long a node that stopped announcing stays registered.
+    #[serde(default = "expire")]
+    pub expire: String,
+}
+
+fn group() -> String {
+    multicast::GROUP.to_owned()
+}
+
+fn announce_interval() -> String {
+    "5s".to_owned()
+}
+
+fn expire() -> String {
+    "5m".to_owned()
+}
+
+/// Values given on the command line, overriding the file and environment.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Flags {
+    pub config: Option<PathBuf>,
+    pub id: Option<String>,
+    pub name: Option<String>,
+    pub state_dir: Option<PathBuf>,
+    pub address: Option<String>,
+    pub listen: Option<String>,
+    pub bind: Option<String>,
+    pub url: Option<String>,
+    pub seeds: std::vec::Vec<String>,
+    pub log: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Run(Flags),
+    Check(Flags),
+    Calibrate(std::vec::Vec<String>),
+    Help,
+    Version,
+}
+
+/// Parses the arguments after the program name.
+pub fn parse_args(args: &[String]) -> Result<Command, String> {
+    let (command, rest) = match args.first().map(String::as_str) {
+        Some("calibrate") => return Ok(Command::Calibrate(args[1..].to_vec())),
+        Some("help") | Some("--help") | Some("-h") => return Ok(Command::Help),
+        Some("version") | Some("--version") | Some("-V") => return Ok(Command::Version),
+        Some("run") => ("run", &args[1..]),
+        Some("check") => ("check", &args[1..]),
+        _ => ("run", args),
+    };
+
+    let mut flags = Flags::default();
+    let mut rest = rest.iter();
+    while let Some(arg) = rest.next() {
+        let mut value = || {
+            rest.next()
+                .cloned()
+                .ok_or_else(|| format!("{} needs a value", arg))
+        };
+        match arg.as_str() {
+            "-c" | "--config" => flags.config = Some(value()?.into()),
+            "--id" => flags.id = Some(value()?),
+            "--name" => flags.name = Some(value()?),
+            "--state-dir" => flags.state_dir = Some(value()?.into()),
+            "--address" => flags.address = Some(value()?),
+            "--listen" => flags.listen = Some(value()?),
+            "--bind" => flags.bind = Some(value()?),
+            "--url" => flags.url = Some(value()?),
+            "--seed" => flags.seeds.push(value()?),
+            "--log" => flags.log = Some(value()?),
+            other => return Err(format!("unexpected argument {}", other)),
+        }
+    }
+    Ok(match command {
+        "check" => Command::Check(flags),
+        _ => Command::Run(flags),
+    })
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        toml::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// The configuration file named by the flags or `PHI_CONFIG`, with the
+    /// environment and the flags applied on top, validated. Lists every
+    /// problem found.
+    pub fn resolve(
+        flags: &Flags,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Result<Config, std::vec::Vec<String>> {
+        let path = flags
+            .config
+            .clone()
+            .or_else(|| var("PHI_CONFIG").map(PathBuf::from));
+        let mut config = match path {
+            Some(path) => Config::load(&path).map_err(|e| vec![e])?,
+            None => Config::default(),
+        };
+        let mut errors = config.apply_env(var);
+        errors.extend(config.apply_flags(flags));
+        if let Err(invalid) = config.validate() {
+            errors.extend(invalid);
+        }
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Applies the `PHI_*` variables that are set, returning the ones that
+    /// could not be parsed.
+    pub fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> std::vec::Vec<String> {
+        let mut errors = std::vec::Vec::new();
+        if let Some(me) = var("PHI_ME") {
+            match me.parse::<Peer>() {
+                Ok(peer) => {
+                    self.node.id = Some(peer.id);
+                    self.node.name = Some(peer.name);
+                    self.node.address = Some(peer.address);
+                }
+                Err(e) => errors.push(format!("PHI_ME: {}", e)),
+            }
+        }
+        if let Some(peers) = var("PHI_PEERS") {
+            match list(&peers).iter().map(|p| p.parse()).collect() {
+                Ok(peers) => self.peers = peers,
+                Err(e) => errors.push(format!("PHI_PEERS: {}", e)),
+            }
+        }
+        if let Some(id) = var("PHI_ID") {
+            match Uuid::parse_str(&id) {
+                Ok(id) => self.node.id = Some(id),
+                Err(e) => errors.push(format!("PHI_ID: {}", e)),
+            }
+        }
+        self.node.name = var("PHI_NAME").or_else(|| self.node.name.take());
+        self.node.address = var("PHI_ADDRESS").or_else(|| self.node.address.take());
+        self.node.listen = var("PHI_LISTEN").or_else(|| self.node.listen.take());
+        if let Some(family) = var("PHI_ADDRESS_FAMILY") {
+            match family.parse() {
+                Ok(family) => self.node.address_family = family,
+                Err(e) => errors.push(format!("PHI_ADDRESS_FAMILY: {}", e)),
+            }
+        }
+        if let Some(dir) = var("PHI_STATE_DIR") {
+            self.node.state_dir = dir.into();
+        }
+        if let Some(bind) = var("PHI_REST") {
+            self.http.bind = bind;
+        }
+        self.http.url = var("PHI_REST_URL").or_else(|| self.http.url.take());
+        if let Some(interval) = var("PHI_PING_INTERVAL") {
+            self.heartbeat.ping_interval = interval;
+        }
+        if let Some(interval) = var("PHI_RESOLVE_INTERVAL") {
+            self.heartbeat.resolve_interval = interval;
+        }
+        if let Some(seeds) = var("PHI_SEEDS") {
+            self.seeds = list(&seeds);
+        }
+        if let Some(tiers) = var("PHI_TIMESERIES_TIERS") {
+            self.timeseries.tiers = tiers;
+        }
+        if let Some(path) = var("PHI_TRACE") {
+            self.trace.path = Some(path.into());
+        }
+        if let Some(max_bytes) = var("PHI_TRACE_MAX_BYTES") {
+            match max_bytes.parse() {
+                Ok(max_bytes) => self.trace.max_bytes = max_bytes,
+                Err(_) => errors.push(format!(
+                    "PHI_TRACE_MAX_BYTES: {:?} is not a number",
+                    max_bytes
+                )),
+            }
+        }
+        if let Some(max_files) = var("PHI_TRACE_MAX_FILES") {
+            match max_files.parse() {
+                Ok(max_files) => self.trace.max_files = max_files,
+                Err(_) => errors.push(format!(
+                    "PHI_TRACE_MAX_FILES: {:?} is not a number",
+                    max_files
+                )),
+            }
+        }
+        if let Some(level) = var("RUST_LOG") {
+            self.log.level = level;
+        }
+        if let Some(tokens) = var("PHI_AUTH_TOKENS") {
+            self.auth.tokens = list(&tokens);
+        }
+        if let Some(clients) = var("PHI_AUTH_CLIENTS") {
+            self.auth.clients = list(&clients);
+        }
+        self.auth.token = var("PHI_AUTH_TOKEN").or_else(|| self.auth.token.take());
+        match (var("PHI_TLS_CERT"), var("PHI_TLS_KEY")) {
+            (Some(cert), Some(key)) => {
+                self.tls = Some(Tls {
+                    cert: cert.into(),
+                    key: key.into(),
+                    client_ca: None,
+                    client_auth: required(),
+                })
+            }
+            (None, None) => (),
+            _ => errors.push("PHI_TLS_CERT and PHI_TLS_KEY must be set together".to_owned()),
+        }
+        if let Some(interval) = var("PHI_DISCOVERY_INTERVAL") {
+            self.discovery.interval = interval;
+        }
+        if let Some(path) = var("PHI_DISCOVERY_FILE") {
+            self.discovery.file = Some(path.into());
+        }
+        if let Some(name) = var("PHI_DISCOVERY_DNS") {
+            self.discovery.dns = Some(DnsDiscovery {
+                name,
+                record: srv(),
+                scheme: http(),
+                port: None,
+                nameserver: None,
+            });
+        }
+        if let Some(dns) = &mut self.discovery.dns {
+            if let Some(record) = var("PHI_DISCOVERY_DNS_RECORD") {
+                match record.parse() {
+                    Ok(record) => dns.record = record,
+                    Err(e) => errors.push(format!("PHI_DISCOVERY_DNS_RECORD: {}", e)),
+                }
+            }
+            if let Some(scheme) = var("PHI_DISCOVERY_DNS_SCHEME") {
+                match scheme.parse() {
+                    Ok(scheme) => dns.scheme = scheme,
+                    Err(e) => errors.push(format!("PHI_DISCOVERY_DNS_SCHEME: {}", e)),
+                }
+            }
+            if let Some(port) = var("PHI_DISCOVERY_DNS_PORT") {
+                match port.parse() {
+                    Ok(port) => dns.port = Some(port),
+                    Err(_) => errors.push(format!(
+                        "PHI_DISCOVERY_DNS_PORT: {:?} is not a port",
+                        port
+                    )),
+                }
+            }
+            dns.nameserver = var("PHI_DISCOVERY_DNS_NAMESERVER").or_else(|| dns.nameserver.take());
+        }
+        if let Some(cluster) = var("PHI_DISCOVERY_MULTICAST") {
+            self.discovery.multicast = Some(MulticastDiscovery {
+                cluster,
+                group: group(),
+                interface: None,
+                interval: announce_interval(),
+                expire: expire(),
+            });
+        }
+        if let Some(multicast) = &mut self.discovery.multicast {
+            if let Some(group) = var("PHI_DISCOVERY_MULTICAST_GROUP") {
+                multicast.group = group;
+            }
+            multicast.interface =
+                var("PHI_DISCOVERY_MULTICAST_INTERFACE").or_else(|| multicast.interface.take());
+        }
+        if let Some(tls) = &mut self.tls {
+            if let Some(ca) = var("PHI_TLS_CLIENT_CA") {
+                tls.client_ca = Some(ca.into());
+            }
+            if let Some(client_auth) = var("PHI_TLS_CLIENT_AUTH") {
+                tls.client_auth = client_auth;
+            }
+        }
+        errors
+    }
+
+    pub fn apply_flags(&mut self, flags: &Flags) -> std::vec::Vec<String> {
+        let mut errors = std::vec::Vec::new();
+        if let Some(id) = &flags.id {
+            match Uuid::parse_str(id) {
+                Ok(id) => self.node.id = Some(id),
+                Err(e) => errors.push(format!("--id: {}", e)),
+            }
+        }
+        if let Some(name) = &flags.name {
+            self.node.name = Some(name.clone());
+        }
+        if let Some(dir) = &flags.state_dir {
+            self.node.state_dir = dir.clone();
+        }
+        if let Some(address) = &flags.address {
+            self.node.address = Some(address.clone());
+        }
+        if let Some(listen) = &flags.listen {
+            self.node.listen = Some(listen.clone());
+        }
+        if let Some(bind) = &flags.bind {
+            self.http.bind = bind.clone();
+        }
+        if let Some(url) = &flags.url {
+            self.http.url = Some(url.clone());
+        }
+        if !flags.seeds.is_empty() {
+            self.seeds = flags.seeds.clone();
+        }
+        if let Some(level) = &flags.log {
+            self.log.level = level.clone();
+        }
+        errors
+    }
+
+    /// Checks every setting, naming each invalid one.
+    pub fn validate(&self) -> Result<(), std::vec::Vec<String>> {
+        let mut errors = std::vec::Vec::new();
+        let mut check = |field: &str, result: Result<(), String>| {
+            if let Err(e) = result {
+                errors.push(format!("{}: {}", field, e));
+            }
+        };
+        check("node", self.check_node());
+        check("http.bind", resolvable(&self.http.bind));
+        if let Some(url) = &self.http.url {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                check("http.url", Err(format!("{:?} must be an http:// or https:// URL", url)));
+            }
+        }
+        check("heartbeat.ping_interval", self.ping_interval().map(|_| ()));
+        check(
+            "heartbeat.resolve_interval",
+            self.resolve_interval().map(|_| ()),
+        );
+        check("detector", self.detector.validate());
+        check("timeseries.tiers", self.tiers().map(|_| ()));
+        if self.trace.max_files == 0 {
+            check("trace.max_files", Err("must be positive".to_owned()));
+        }
+        check("log.level", log_filter(&self.log.level));
+        check("auth", self.auth().map(|_| ()));
+        check("tls", self.tls().map(|_| ()));
+        check("peers", self.check_peers());
+        check("discovery.interval", self.discovery_interval().map(|_| ()));
+        check("discovery.dns", self.check_dns());
+        check("discovery.multicast", self.multicast().map(|_| ()));
+        for seed in &self.seeds {
+            if !seed.starts_with("http://") && !seed.starts_with("https://") {
+                check(
+                    "seeds",
+                    Err(format!("{:?} must be an http:// or https:// URL", seed)),
+                );
+            } else {
+                check("seeds", discovery::check_tls_host(seed));
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// A copy with token values hidden, for printing.
+    pub fn redacted(&self) -> Config {
+        let mut config = self.clone();
+        for token in &mut config.auth.tokens {
+            let role = token.split(':').next().unwrap_or("").to_owned();
+            *token = format!("{}:<redacted>", role);
+        }
+        if config.auth.token.is_some() {
+            config.auth.token = Some("<redacted>".to_owned());
+        }
+        config
+    }
+
+    /// Sections that differ from the `running` configuration but are only
+    /// read at startup, so a reload cannot apply them.
+    pub fn startup_only_changes(&self, running: &Config) -> std::vec::Vec<&'static str> {
+        let sections = [
+            ("seeds", self.seeds != running.seeds),
+            ("node", self.node != running.node),
+            ("http", self.http != running.http),
+            ("timeseries", self.timeseries != running.timeseries),
+            ("trace", self.trace != running.trace),
+            ("auth", self.auth != running.auth),
+            ("tls", self.tls != running.tls),
+            ("discovery", self.discovery != running.discovery),
+        ];
+        sections
+            .iter()
+            .filter(|(_, changed)| *changed)
+            .map(|(name, _)| *name)
+            .collect()
+    }
+
+    fn check_peers(&self) -> Result<(), String> {
+        if let Some(me) = self.peers.iter().find(|p| Some(p.id) == self.node.id) {
+            return Err(format!("{} is this node", me.id));
+        }
+        check_peers(&self.peers)
+    }
+
+    fn check_dns(&self) -> Result<(), String> {
+        let dns = match &self.discovery.dns {
+            Some(dns) => dns,
+            None => return Ok(()),
+        };
+        if dns.name.trim().is_empty() {
+            return Err("name must not be empty".to_owned());
+        }
+        match (dns.record, dns.port) {
+            (Record::A, None) => return Err("port is required for a records".to_owned()),
+            (Record::Srv, Some(_)) => {
+                return Err("port only applies to a records, srv records carry it".to_owned())
+            }
+            _ => (),
+        }
+        if dns.record == Record::A && dns.scheme == Scheme::Https {
+            return Err("https needs srv records, certificates are not verified for IPs".to_owned());
+        }
+        self.nameserver().map(|_| ())
+    }
+
+    fn check_node(&self) -> Result<(), String> {
+        if self.node.name.as_deref().map(str::trim) == Some("") {
+            return Err("name must not be empty".to_owned());
+        }
+        // the advertised address may only resolve for the peers
+        let address = self.node.address.as_ref().ok_or("address is not set")?;
+        host_port(address).map_err(|e| format!("address {}", e))?;
+        self.listen_addr()
+            .map(|_| ())
+            .map_err(|e| format!("listen {}", e))
+    }
+
+    /// Fills in the node id and name left out of the configuration: the id
+    /// is read from the state directory, or generated and saved there on
+    /// first start, and the name is the host name.
+    pub fn identify(&mut self) -> Result<(), String> {
+        if self.node.id.is_none() {
+            let dir = &self.node.state_dir;
+            let id = node_id(dir)
+                .map_err(|e| format!("node.state_dir: {}: {}", dir.display(), e))?;
+            self.node.id = Some(id);
+        }
+        if self.node.name.is_none() {
+            self.node.name = Some(host_name());
+        }
+        Ok(())
+    }
+
+    /// This node's identity, once `identify` filled it in.
+    pub fn me(&self) -> Result<Peer, String> {
+        let id = self.node.id.ok_or("id is not set")?;
+        let name = self.node.name.clone().ok_or("name is not set")?;
+        let address = self.node.address.clone().ok_or("address is not set")?;
+        Ok(Peer::new(id, name, address))
+    }
+
+    /// Local address of the heartbeat socket.
+    pub fn listen(&self) -> Option<String> {
+        self.node.listen.clone().or_else(|| self.node.address.clone())
+    }
+
+    /// The heartbeat socket address, in the preferred family when the host
+    /// has both.
+    pub fn listen_addr(&self) -> Result<SocketAddr, String> {
+        let listen = self.listen().ok_or("address is not set")?;
+        let candidates: std::vec::Vec<SocketAddr> = listen
+            .to_socket_addrs()
+            .map_err(|e| format!("{:?}: {}", listen, e))?
+            .collect();
+        self.node
+            .address_family
+            .pick(&candidates)
+            .ok_or_else(|| format!("{:?} does not resolve", listen))
+    }
+
+    pub fn ping_interval(&self) -> Result<Duration, String> {
+        positive(&self.heartbeat.ping_interval)
+    }
+
+    pub fn resolve_interval(&self) -> Result<Duration, String> {
+        positive(&self.heartbeat.resolve_interval)
+    }
+
+    pub fn discovery_interval(&self) -> Result<Duration, String> {
+        positive(&self.discovery.interval)
+    }
+
+    /// The DNS server discovery asks, on port 53 unless given.
+    pub fn nameserver(&self) -> Result<Option<SocketAddr>, String> {
+        let nameserver = match self.discovery.dns.as_ref().and_then(|d| d.nameserver.as_ref()) {
+            Some(nameserver) => nameserver,
+            None => return Ok(None),
+        };
+        nameserver
+            .parse::<SocketAddr>()
+            .or_else(|_| nameserver.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+            .map(Some)
+            .map_err(|_| format!("nameserver {:?} is not an ip or ip:port", nameserver))
+    }
+
+    pub fn multicast(&self) -> Result<Option<multicast::Settings>, String> {
+        let m = match &self.discovery.multicast {
+            Some(m) => m,
+            None => return Ok(None),
+        };
+        if m.cluster.trim().is_empty() {
+            return Err("cluster must not be empty".to_owned());
+        }
+        let group: SocketAddr = m
+            .group
+            .parse()
+            .map_err(|_| format!("group {:?} is not an ip:port", m.group))?;
+        let usable = match group.ip() {
+            IpAddr::V4(ip) => ip.is_multicast() || ip.is_broadcast(),
+            IpAddr::V6(ip) => ip.is_multicast(),
+        };
+        if !usable {
+            return Err(format!(
+                "group {} is neither a multicast group nor the broadcast address",
+                group.ip()
+            ));
+        }
+        let interface = match &m.interface {
+            Some(interface) => interface
+                .parse()
+                .map_err(|_| format!("interface {:?} is not an IPv4 address", interface))?,
+            None => Ipv4Addr::UNSPECIFIED,
+        };
+        Ok(Some(multicast::Settings {
+            cluster: m.cluster.clone(),
+            group,
+            interface,
+            interval: positive(&m.interval).map_err(|e| format!("interval: {}", e))?,
+            expire: positive(&m.expire).map_err(|e| format!("expire: {}", e))?,
+        }))
+    }
+
+    /// Detector defaults for peers registered without overrides.
+    pub fn detector(&self) -> PhiAccrualFailureDetectorBuilder {
+        self.detector
+            .builder(&PhiAccrualFailureDetectorBuilder::new())
+    }
+
+    pub fn tiers(&self) -> Result<std::vec::Vec<Tier>, String> {
+        timeseries::parse_tiers(&self.timeseries.tiers)
+    }
+
+    pub fn auth(&self) -> Result<Auth, String> {
+        Auth::from_entries(&self.auth.tokens, &self.auth.clients)
+    }
+
+    pub fn tls(&self) -> Result<Option<tls::Settings>, String> {
+        self.tls
+            .as_ref()
+            .map(|t| {
+                Ok(tls::Settings {
+                    cert: t.cert.clone(),
+                    key: t.key.clone(),
+                    client_ca: t.client_ca.clone(),
+                    client_auth: t.client_auth.parse()?,
+                })
+            })
+            .transpose()
+    }
+}
+
+/// File in the state directory holding the generated node id.
+const NODE_ID_FILE: &str = "node-id";
+
+/// The id saved in `dir`, or a new one saved there.
+fn node_id(dir: &Path) -> io::Result<Uuid> {
+    let path = dir.join(NODE_ID_FILE);
+    match fs::read_to_string(&path) {
+        Ok(text) => Uuid::parse_str(text.trim()).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", NODE_ID_FILE, e))
+        }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let id = Uuid::new_v4();
+            fs::create_dir_all(dir)?;
+            // written aside and renamed, so a crash never leaves a partial id
+            let partial = dir.join(format!("{}.tmp", NODE_ID_FILE));
+            fs::write(&partial, format!("{}\n", id))?;
+            fs::rename(&partial, &path)?;
+            info!("Generated node id {} in {}", id, path.display());
+            Ok(id)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn host_name() -> String {
+    hostname::get()
+        .ok()
+        .and_then(|name| name.into_string().ok())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "phifd".to_owned())
+}
+
+fn list(value: &str) -> std::vec::Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+fn positive(duration: &str) -> Result<Duration, String> {
+    match timeseries::duration(duration)? {
+        0 => Err("must be positive".to_owned()),
+        ms => Ok(Duration::from_millis(ms as u64)),
+    }
+}
+
+/// Checks a peer list: ids are unique, addresses well formed and detector
+/// overrides valid.
+pub fn check_peers(peers: &[Peer]) -> Result<(), String> {
+    let mut seen = HashSet::new();
+    for peer in peers {
+        if !seen.insert(peer.id) {
+            return Err(format!("{} is listed twice", peer.id));
+        }
+        host_port(&peer.address).map_err(|e| format!("{}: address {}", peer.name, e))?;
+        if let Some(detector) = &peer.detector {
+            detector
+                .validate()
+                .map_err(|e| format!("{}: {}", peer.name, e))?;
+        }
+    }
+    Ok(())
+}
+
+/// Checks the form of a peer address only, as peers may not resolve yet
+/// when this node starts.
+fn host_port(address: &str) -> Result<(), String> {
+    net::split_host_port(address).map(|_| ())
+}
+
+fn resolvable(address: &str) -> Result<(), String> {
+    match address.to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => Err(format!("{:?} does not resolve", address)),
+        Err(e) => Err(format!("{:?}: {}", address, e)),
+    }
+}
+
+/// Accepts `env_logger` filters made of comma separated `level` or
+/// `module=level` directives.
+fn log_filter(filter: &str) -> Result<(), String> {
+    for directive in filter.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        let level = directive.rsplit('=').next().unwrap_or(directive);
+        if !LOG_LEVELS.contains(&level.to_ascii_lowercase().as_str()) {
+            return Err(format!("unknown level in {:?}", directive));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_args, Command, Config, Flags};
+    use crate::{
+        discovery::dns::{Record, Scheme},
+        net::Family,
+    };
+    use std::collections::HashMap;
+    use uuid::Uuid;
+
+    const FILE: &str = r#"
+seeds = ["http://b:8000"]
+
+[node]
+id = "78441273-5550-4733-9d37-1c0319a21053"
+name = "a"
+address = "127.0.0.1:9000"
+
+[heartbeat]
+ping_interval = "250ms"
+
+[detector]
+threshold = 8.0
+mode = "Histogram"
+
+[auth]
+tokens = ["admin:s3cret"]
+
+[[peers]]
+id = "b7822b9d-a32b-4525-9af6-45216cd96d40"
+name = "b"
+address = "127.0.0.1:9001"
+
+[[peers]]
+id = "bffcaacb-df8d-43b8-b693-445a031ac215"
+name = "c"
+address = "127.0.0.1:9002"
+detector = { threshold = 4.0 }
+"#;
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn file_env_and_flags() {
+        let mut config: Config = toml::from_str(FILE).unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.ping_interval().unwrap().as_millis(), 250);
+        assert_eq!(config.detector().build().threshold(), 8.0);
+
+        config.seeds = vec!["https://b:8443".to_owned()];
+        assert!(config.validate().is_ok());
+        config.seeds = vec!["https://127.0.0.1:8443".to_owned(), "b:8000".to_owned()];
+        assert_eq!(config.validate().unwrap_err().len(), 2);
+        config.seeds = vec!["http://b:8000".to_owned()];
+
+        config.apply_env(env(&[("PHI_AUTH_TOKEN", "viewer")]));
+        assert_eq!(config.auth.token.as_deref(), Some("viewer"));
+        assert_eq!(config.redacted().auth.token.as_deref(), Some("<redacted>"));
+
+        let errors = config.apply_env(env(&[
+            ("PHI_REST", "127.0.0.1:8100"),
+            ("PHI_NAME", "alpha"),
+            ("PHI_TRACE_MAX_FILES", "many"),
+        ]));
+        assert_eq!(errors.len(), 1);
+        assert_eq!(config.http.bind, "127.0.0.1:8100");
+        assert_eq!(config.node.name.as_deref(), Some("alpha"));
+
+        let flags = Flags {
+            name: Some("first".to_owned()),
+            ..Flags::default()
+        };
+        assert!(config.apply_flags(&flags).is_empty());
+        assert_eq!(config.me().unwrap().name, "first");
+    }
+
+    #[test]
+    fn reports_every_invalid_setting() {
+        let flags = Flags::default();
+        let errors = Config::resolve(
+            &flags,
+            env(&[
+                ("PHI_ME", "not-a-uuid/a/127.0.0.1:9000"),
+                ("PHI_PING_INTERVAL", "0ms"),
+                ("PHI_RESOLVE_INTERVAL", "soon"),
+                ("PHI_AUTH_TOKENS", "root:x"),
+                ("PHI_TLS_CERT", "cert.pem"),
+                ("RUST_LOG", "loud"),
+            ]),
+        )
+        .unwrap_err();
+        for field in &[
+            "PHI_ME",
+            "PHI_TLS_CERT",
+            "node:",
+            "heartbeat.ping_interval",
+            "heartbeat.resolve_interval",
+            "auth",
+            "log.level",
+        ] {
+            assert!(
+                errors.iter().any(|e| e.starts_with(field)),
+                "no {} in {:?}",
+                field,
+                errors
+            );
+        }
+
+        let ok = Config::resolve(
+            &flags,
+            env(&[(
+                "PHI_ME",
+                "78441273-5550-4733-9d37-1c0319a21053/a/127.0.0.1:9000",
+            )]),
+        )
+        .unwrap();
+        assert_eq!(ok.me().unwrap().name, "a");
+        assert!(toml::from_str::<Config>("[node]\nport = 1").is_err());
+    }
+
+    #[test]
+    fn static_peers() {
+        let mut config: Config = toml::from_str(FILE).unwrap();
+        assert_eq!(config.peers.len(), 2);
+        assert_eq!(
+            config.peers[1].detector.as_ref().unwrap().threshold,
+            Some(4.0)
+        );
+
+        config.peers.push(config.peers[0].clone());
+        assert!(config.validate().unwrap_err()[0].starts_with("peers:"));
+
+        let errors = config.apply_env(env(&[(
+            "PHI_PEERS",
+            "b7822b9d-a32b-4525-9af6-45216cd96d40/b/127.0.0.1:9001, 78441273-5550-4733-9d37-1c0319a21053/a/127.0.0.1:9000",
+        )]));
+        assert!(errors.is_empty());
+        assert_eq!(config.peers.len(), 2);
+        assert!(config.validate().unwrap_err()[0].contains("is this node"));
+        assert!(!config
+            .apply_env(env(&[("PHI_PEERS", "b/127.0.0.1:9001")]))
+            .is_empty());
+
+        let errors = config.apply_env(env(&[
+            (
+                "PHI_PEERS",
+                "b7822b9d-a32b-4525-9af6-45216cd96d40/b/[::1]:9001",
+            ),
+            ("PHI_ADDRESS_FAMILY", "ipv6"),
+        ]));
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(config.peers[0].address, "[::1]:9001");
+        assert_eq!(config.node.address_family, Family::Ipv6);
+        assert!(config.validate().is_ok());
+        config.peers[0].address = "::1:9001".to_owned();
+        assert!(config.validate().unwrap_err()[0].contains("bracketed"));
+        assert!(!config
+            .apply_env(env(&[(
+                "PHI_PEERS",
+                "b7822b9d-a32b-4525-9af6-45216cd96d40/b/::1:9001"
+            )]))
+            .is_empty());
+    }
+
+    #[test]
+    fn generated_identity() {
+        let dir = std::env::temp_dir().join(format!("phifd-state-{}", Uuid::new_v4()));
+        let mut config = Config::default();
+        config.node.address = Some("127.0.0.1:9000".to_owned());
+        config.node.state_dir = dir.clone();
+        assert!(config.validate().is_ok());
+        assert!(config.me().is_err());
+
+        let mut first = config.clone();
+        first.identify().unwrap();
+        let me = first.me().unwrap();
+        assert!(!me.name.is_empty());
+        let mut second = config.clone();
+        second.identify().unwrap();
+        assert_eq!(second.node.id, Some(me.id));
+
+        // a configured id wins and leaves the saved one alone
+        let explicit = Uuid::new_v4();
+        config.node.id = Some(explicit);
+        config.node.name = Some("a".to_owned());
+        config.identify().unwrap();
+        assert_eq!(config.node.id, Some(explicit));
+        assert_eq!(config.node.name.as_deref(), Some("a"));
+        let saved = std::fs::read_to_string(dir.join("node-id")).unwrap();
+        assert_eq!(saved.trim(), me.id.to_string());
+
+        std::fs::write(dir.join("node-id"), "garbage").unwrap();
+        config.node.id = None;
+        assert!(config.identify().unwrap_err().starts_with("node.state_dir"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn advertised_addresses() {
+        let mut config = Config::default();
+        config.node.address = Some("phifd-a.invalid:9000".to_owned());
+        let errors = config.validate().unwrap_err();
+        assert!(errors[0].starts_with("node: listen"), "{:?}", errors);
+
+        let flags = Flags {
+            listen: Some("127.0.0.1:9000".to_owned()),
+            url: Some("https://phifd-a.invalid".to_owned()),
+            ..Flags::default()
+        };
+        assert!(config.apply_flags(&flags).is_empty());
+        assert!(config.validate().is_ok());
+        assert_eq!(config.listen().as_deref(), Some("127.0.0.1:9000"));
+        config.node.id = Some(Uuid::new_v4());
+        config.node.name = Some("a".to_owned());
+        assert_eq!(config.me().unwrap().address, "phifd-a.invalid:9000");
+
+        config.apply_env(env(&[("PHI_REST_URL", "phifd-a.invalid:8000")]));
+        assert!(config.validate().unwrap_err()[0].starts_with("http.url"));
+    }
+
+    #[test]
+    fn discovery_settings() {
+        let mut config: Config = toml::from_str(
+            r#"
+[node]
+address = "127.0.0.1:9000"
+
+[discovery.dns]
+name = "_http._tcp.phifd.default.svc.cluster.local"
+"#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.discovery.dns.as_ref().unwrap().record, Record::Srv);
+        assert_eq!(config.nameserver(), Ok(None));
+        assert_eq!(config.discovery_interval().unwrap().as_secs(), 30);
+
+        let errors = config.apply_env(env(&[
+            ("PHI_DISCOVERY_FILE", "/var/lib/inventory/peers.json"),
+            ("PHI_DISCOVERY_DNS", "phifd.default.svc.cluster.local"),
+            ("PHI_DISCOVERY_DNS_RECORD", "a"),
+            ("PHI_DISCOVERY_DNS_SCHEME", "https"),
+            ("PHI_DISCOVERY_DNS_NAMESERVER", "10.96.0.10"),
+        ]));
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(
+            config.validate().unwrap_err(),
+            vec!["discovery.dns: port is required for a records"]
+        );
+        assert_eq!(
+            config.discovery.file.as_deref(),
+            Some(std::path::Path::new("/var/lib/inventory/peers.json"))
+        );
+        config.apply_env(env(&[("PHI_DISCOVERY_DNS_PORT", "8000")]));
+        assert_eq!(
+            config.validate().unwrap_err(),
+            vec!["discovery.dns: https needs srv records, certificates are not verified for IPs"]
+        );
+        config.apply_env(env(&[("PHI_DISCOVERY_DNS_RECORD", "srv")]));
+        assert_eq!(config.discovery.dns.as_ref().unwrap().scheme, Scheme::Https);
+        config.discovery.dns.as_mut().unwrap().port = None;
+        assert!(config.validate().is_ok());
+        config.apply_env(env(&[
+            ("PHI_DISCOVERY_DNS_RECORD", "a"),
+            ("PHI_DISCOVERY_DNS_SCHEME", "http"),
+            ("PHI_DISCOVERY_DNS_PORT", "8000"),
+        ]));
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.nameserver(),
+            Ok(Some("10.96.0.10:53".parse().unwrap()))
+        );
+
+        let errors = config.apply_env(env(&[
+            ("PHI_DISCOVERY_DNS_RECORD", "aaaa"),
+            ("PHI_DISCOVERY_DNS_NAMESERVER", "kube-dns"),
+            ("PHI_DISCOVERY_INTERVAL", "0s"),
+        ]));
+        assert!(errors[0].starts_with("PHI_DISCOVERY_DNS_RECORD"));
+        let errors = config.validate().unwrap_err();
+        assert!(errors[0].starts_with("discovery.interval"));
+        assert!(errors[1].contains("\"kube-dns\" is not an ip"));
+    }
+
+    #[test]
+    fn multicast_settings() {
+        let mut config = Config::default();
+        config.node.address = Some("127.0.0.1:9000".to_owned());
+        let errors = config.apply_env(env(&[("PHI_DISCOVERY_MULTICAST", "prod")]));
+        assert!(errors.is_empty());
+        let settings = config.multicast().unwrap().unwrap();
+        assert_eq!(settings.cluster, "prod");
+        assert_eq!(settings.group, "239.255.70.68:9700".parse().unwrap());
+        assert_eq!(settings.interval.as_secs(), 5);
+        assert_eq!(settings.expire.as_secs(), 300);
+
+        config.apply_env(env(&[
+            ("PHI_DISCOVERY_MULTICAST_GROUP", "255.255.255.255:9700"),
+            ("PHI_DISCOVERY_MULTICAST_INTERFACE", "192.0.2.7"),
+        ]));
+        assert!(config.validate().is_ok());
+        config.apply_env(env(&[("PHI_DISCOVERY_MULTICAST_GROUP", "[ff02::70]:9700")]));
+        assert!(config.validate().is_ok());
+
+        config.apply_env(env(&[("PHI_DISCOVERY_MULTICAST_GROUP", "192.0.2.1:9700")]));
+        assert!(config.validate().unwrap_err()[0].starts_with("discovery.multicast: group"));
+        let toml = "[discovery.multicast]\ncluster = \"prod\"\nexpire = \"0s\"";
+        let config: Config = toml::from_str(toml).unwrap();
+        assert_eq!(config.multicast(), Err("expire: must be positive".to_owned()));
+    }
+
+    #[test]
+    fn startup_only_changes() {
+        let running: Config = toml::from_str(FILE).unwrap();
+        let mut changed = running.clone();
+        changed.detector.threshold = Some(10.0);
+        changed.heartbeat.ping_interval = "1s".to_owned();
+        changed.log.level = "debug".to_owned();
+        changed.peers.pop();
+        assert!(changed.startup_only_changes(&running).is_empty());
+
+        changed.http.bind = "127.0.0.1:8080".to_owned();
+        changed.auth.tokens.clear();
+        assert_eq!(changed.startup_only_changes(&running), vec!["http", "auth"]);
+    }
+
+    #[test]
+    fn command_line() {
+        let args = |s: &str| {
+            s.split_whitespace()
+                .map(str::to_owned)
+                .collect::<std::vec::Vec<_>>()
+        };
+        assert_eq!(parse_args(&[]).unwrap(), Command::Run(Flags::default()));
+        match parse_args(&args("check -c phifd.toml --seed http://a --seed http://b")).unwrap() {
+            Command::Check(flags) => {
+                assert_eq!(flags.config.unwrap().to_str(), Some("phifd.toml"));
+                assert_eq!(flags.seeds.len(), 2);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(
+            parse_args(&args("calibrate t.csv")).unwrap(),
+            Command::Calibrate(args("t.csv"))
+        );
+        assert!(parse_args(&args("--bind")).is_err());
+        assert!(parse_args(&args("--port 1")).is_err());
+    }
+}
@@ -0,0 +1,311 @@
+//! Discovery through DNS, such as the headless service of a Kubernetes
+//! StatefulSet: SRV records name each node's API host and port, while A and
+//! AAAA records list their IPs, the API port being configured.
+
+use super::{Found, Source};
+use futures::future::{FutureExt, LocalBoxFuture};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+use trust_dns_resolver::{
+    config::{LookupIpStrategy, NameServerConfigGroup, ResolverConfig, ResolverOpts},
+    error::{ResolveError, ResolveErrorKind},
+    AsyncResolver,
+};
+
+/// The records a name is looked up for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Record {
+    /// SRV records, as in `_http._tcp.phifd.default.svc.cluster.local`.
+    Srv,
+    /// A and AAAA records, with the API on a known port.
+    A,
+}
+
+impl FromStr for Record {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "srv" => Ok(Record::Srv),
+            "a" => Ok(Record::A),
+            _ => Err(format!("unknown record type {:?}, expected srv or a", s)),
+        }
+    }
+}
+
+/// The scheme of the API URLs built from the records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scheme {
+    Http,
+    Https,
+}
+
+impl Scheme {
+    fn as_str(self) -> &'static str {
+        match self {
+            Scheme::Http => "http",
+            Scheme::Https => "https",
+        }
+    }
+}
+
+impl FromStr for Scheme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "http" => Ok(Scheme::Http),
+            "https" => Ok(Scheme::Https),
+            _ => Err(format!("unknown scheme {:?}, expected http or https", s)),
+        }
+    }
+}
+
+/// Looks up a DNS name for the nodes of the cluster.
+pub struct Dns {
+    name: String,
+    record: Record,
+    scheme: Scheme,
+    /// The API port, for A records.
+    port: u16,
+    resolver: AsyncResolver,
+}
+
+impl Dns {
+    /// Queries `nameserver`, or the system's resolvers without one. Must be
+    /// called on the actix runtime, which drives the resolver.
+    pub fn new(
+        name: String,
+        record: Record,
+        scheme: Scheme,
+        port: u16,
+        nameserver: Option<SocketAddr>,
+    ) -> Result<Dns, String> {
+        let (config, mut options) = match nameserver {
+            Some(server) => {
+                let servers = NameServerConfigGroup::from_ips_clear(&[server.ip()], server.port());
+                let config = ResolverConfig::from_parts(None, vec![], servers);
+                (config, ResolverOpts::default())
+            }
+            None => trust_dns_resolver::system_conf::read_system_conf()
+                .map_err(|e| format!("reading the system DNS configuration: {}", e))?,
+        };
+        options.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+        let (resolver, background) = AsyncResolver::new(config, options);
+        actix_rt::spawn(background);
+        Ok(Dns {
+            name,
+            record,
+            scheme,
+            port,
+            resolver,
+        })
+    }
+}
+
+impl Source for Dns {
+    fn name(&self) -> String {
+        format!("dns {}", self.name)
+    }
+
+    fn discover(&mut self) -> LocalBoxFuture<'static, Result<std::vec::Vec<Found>, String>> {
+        let name = self.name.clone();
+        let (scheme, port) = (self.scheme, self.port);
+        let found = match self.record {
+            Record::Srv => self
+                .resolver
+                .lookup_srv(name.as_str())
+                .map(|lookup| {
+                    lookup.map(|srv| {
+                        srv.iter()
+                            .map(|r| (r.target().to_utf8(), r.port()))
+                            .collect()
+                    })
+                })
+                .boxed_local(),
+            Record::A => self
+                .resolver
+                .lookup_ip(name.as_str())
+                .map(move |lookup| {
+                    lookup.map(|ips| ips.iter().map(|ip| (host(ip), port)).collect())
+                })
+                .boxed_local(),
+        };
+        found
+            .map(move |result| {
+                let endpoints = match result {
+                    Ok(endpoints) => endpoints,
+                    Err(e) => nothing_found(e).map_err(|e| format!("{}: {}", name, e))?,
+                };
+                Ok(urls(scheme, endpoints)
+                    .into_iter()
+                    .map(Found::Node)
+                    .collect())
+            })
+            .boxed_local()
+    }
+}
+
+/// An empty answer when the name has no records, as for a service without
+/// ready nodes, and the error otherwise.
+fn nothing_found(e: ResolveError) -> Result<std::vec::Vec<(String, u16)>, ResolveError> {
+    match e.kind() {
+        ResolveErrorKind::NoRecordsFound { .. } => Ok(vec![]),
+        _ => Err(e),
+    }
+}
+
+/// `ip` as the host part of a URL.
+fn host(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(v4) => v4.to_string(),
+        IpAddr::V6(v6) => format!("[{}]", v6),
+    }
+}
+
+/// API base URLs of the `(host, port)` endpoints, sorted, without the final
+/// dot of absolute host names.
+pub fn urls(scheme: Scheme, endpoints: std::vec::Vec<(String, u16)>) -> std::vec::Vec<String> {
+    let mut urls: std::vec::Vec<String> = endpoints
+        .into_iter()
+        .map(|(host, port)| {
+            format!(
+                "{}://{}:{}",
+                scheme.as_str(),
+                host.trim_end_matches('.'),
+                port
+            )
+        })
+        .collect();
+    urls.sort();
+    urls.dedup();
+    urls
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{urls, Dns, Record, Scheme};
+    use crate::discovery::{Found, Source};
+    use std::{net::UdpSocket, str::FromStr, thread};
+    use trust_dns_resolver::proto::{
+        op::{Message, MessageType, ResponseCode},
+        rr::{rdata::SRV, Name, RData, Record as Answer, RecordType},
+    };
+
+    /// Answers queries for the `phifd.test` zone like a Kubernetes headless
+    /// service with two nodes would.
+    fn nameserver() -> std::net::SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            while let Ok((n, from)) = socket.recv_from(&mut buf) {
+                let query = Message::from_vec(&buf[..n]).unwrap();
+                let mut reply = Message::new();
+                reply
+                    .set_id(query.id())
+                    .set_message_type(MessageType::Response)
+                    .set_recursion_available(true);
+                for q in query.queries() {
+                    reply.add_query(q.clone());
+                    let name = q.name().to_utf8();
+                    let answers: std::vec::Vec<RData> = match (name.as_str(), q.query_type()) {
+                        ("_http._tcp.phifd.test.", RecordType::SRV) => ["phifd-0", "phifd-1"]
+                            .iter()
+                            .map(|pod| {
+                                let target =
+                                    Name::from_str(&format!("{}.phifd.test.", pod)).unwrap();
+                                RData::SRV(SRV::new(0, 50, 8000, target))
+                            })
+                            .collect(),
+                        ("phifd.test.", RecordType::A) => {
+                            vec![RData::A("10.0.0.1".parse().unwrap())]
+                        }
+                        ("phifd.test.", RecordType::AAAA) => {
+                            vec![RData::AAAA("fd00::1".parse().unwrap())]
+                        }
+                        (n, RecordType::A) if n.ends_with(".phifd.test.") => {
+                            vec![RData::A("10.0.0.2".parse().unwrap())]
+                        }
+                        _ => vec![],
+                    };
+                    if answers.is_empty() {
+                        reply.set_response_code(ResponseCode::NXDomain);
+                    }
+                    for rdata in answers {
+                        reply.add_answer(Answer::from_rdata(q.name().clone(), 5, rdata));
+                    }
+                }
+                socket.send_to(&reply.to_vec().unwrap(), from).unwrap();
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn endpoint_urls() {
+        assert_eq!(
+            urls(
+                Scheme::Http,
+                vec![
+                    ("b.phifd.test.".to_owned(), 8000),
+                    ("a.phifd.test.".to_owned(), 8000),
+                    ("[fd00::1]".to_owned(), 8000),
+                    ("a.phifd.test".to_owned(), 8000),
+                ]
+            ),
+            vec![
+                "http://[fd00::1]:8000",
+                "http://a.phifd.test:8000",
+                "http://b.phifd.test:8000"
+            ]
+        );
+        assert_eq!(
+            urls(Scheme::Https, vec![("a.phifd.test.".to_owned(), 8443)]),
+            vec!["https://a.phifd.test:8443"]
+        );
+        assert_eq!(Record::from_str("srv"), Ok(Record::Srv));
+        assert!(Record::from_str("aaaa").is_err());
+        assert_eq!(Scheme::from_str("https"), Ok(Scheme::Https));
+        assert!(Scheme::from_str("ftp").is_err());
+    }
+
+    #[test]
+    fn discovers_from_a_nameserver() {
+        let server = Some(nameserver());
+        let nodes = |name: &str, record| {
+            let name = name.to_owned();
+            actix_rt::System::new("dns").block_on(async move {
+                Dns::new(name, record, Scheme::Http, 8000, server)
+                    .unwrap()
+                    .discover()
+                    .await
+            })
+        };
+
+        assert_eq!(
+            nodes("_http._tcp.phifd.test.", Record::Srv),
+            Ok(vec![
+                Found::Node("http://phifd-0.phifd.test:8000".to_owned()),
+                Found::Node("http://phifd-1.phifd.test:8000".to_owned()),
+            ])
+        );
+        assert_eq!(
+            nodes("phifd.test.", Record::A),
+            Ok(vec![
+                Found::Node("http://10.0.0.1:8000".to_owned()),
+                Found::Node("http://[fd00::1]:8000".to_owned()),
+            ])
+        );
+        // a service without nodes
+        assert_eq!(
+            nodes("_http._tcp.none.phifd.test.", Record::Srv),
+            Ok(vec![])
+        );
+    }
+}
@@ -0,0 +1,171 @@
+//! Discovery from a JSON file holding a list of `Peer` entries, as written by
+//! inventory tooling. The file is checked every second and read once it
+//! stopped changing, so a file still being written is not read half way.
+//! Once settled its content is hashed too, as an edit keeping the length
+//! within one modification time tick leaves the metadata alone.
+
+use super::{Found, Source};
+use crate::{config, peer::Peer};
+use futures::future::{self, FutureExt, LocalBoxFuture};
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs,
+    hash::{Hash, Hasher},
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// How often the file is checked for changes.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a changed file must stay unchanged before it is read.
+const SETTLE: Duration = Duration::from_secs(1);
+
+/// Modification time and length of the file, `None` when it is missing.
+type Stamp = Option<(SystemTime, u64)>;
+
+pub struct File {
+    path: PathBuf,
+    /// The file as last checked, and since when it looks like that.
+    seen: Stamp,
+    since: Instant,
+    /// The file as last read, if it was, and the hash of its content.
+    read: Option<(Stamp, Option<u64>)>,
+    /// The peers of the last valid file.
+    peers: std::vec::Vec<Found>,
+}
+
+impl File {
+    pub fn new(path: PathBuf) -> File {
+        File {
+            path,
+            seen: None,
+            since: Instant::now(),
+            read: None,
+            peers: std::vec::Vec::new(),
+        }
+    }
+
+    /// The peers of the file, read again when it changed and then settled.
+    /// A missing or invalid file is reported once, keeping the peers of the
+    /// last valid one.
+    pub fn check(&mut self, now: Instant) -> Result<std::vec::Vec<Found>, String> {
+        let stamp = fs::metadata(&self.path)
+            .and_then(|m| Ok((m.modified()?, m.len())))
+            .ok();
+        if stamp != self.seen {
+            self.seen = stamp;
+            self.since = now;
+        }
+        if now.duration_since(self.since) < SETTLE {
+            return Ok(self.peers.clone());
+        }
+        let content = fs::read(&self.path).map_err(|e| e.to_string());
+        let hash = content.as_ref().ok().map(|content| {
+            let mut hasher = DefaultHasher::new();
+            content.hash(&mut hasher);
+            hasher.finish()
+        });
+        if self.read != Some((stamp, hash)) {
+            self.read = Some((stamp, hash));
+            self.peers = load(&content?)?;
+        }
+        Ok(self.peers.clone())
+    }
+}
+
+fn load(content: &[u8]) -> Result<std::vec::Vec<Found>, String> {
+    let peers: std::vec::Vec<Peer> = serde_json::from_slice(content).map_err(|e| e.to_string())?;
+    config::check_peers(&peers)?;
+    Ok(peers.into_iter().map(Found::Peer).collect())
+}
+
+impl Source for File {
+    fn name(&self) -> String {
+        format!("file {}", self.path.display())
+    }
+
+    fn interval(&self) -> Option<Duration> {
+        Some(CHECK_INTERVAL)
+    }
+
+    fn discover(&mut self) -> LocalBoxFuture<'static, Result<std::vec::Vec<Found>, String>> {
+        future::ready(self.check(Instant::now())).boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{File, SETTLE};
+    use crate::discovery::Found;
+    use std::{fs, time::Instant};
+
+    const PEERS: &str = r#"[
+        {"id": "b7822b9d-a32b-4525-9af6-45216cd96d40", "name": "b", "address": "10.0.0.2:9000"},
+        {"id": "bffcaacb-df8d-43b8-b693-445a031ac215", "name": "c", "address": "[fd00::3]:9000",
+         "detector": {"threshold": 8.0}}
+    ]"#;
+
+    #[test]
+    fn reads_settled_valid_files() {
+        let path = std::env::temp_dir().join(format!("phifd-peers-{}.json", uuid::Uuid::new_v4()));
+        let mut file = File::new(path.clone());
+        let start = Instant::now();
+        let names = |found: std::vec::Vec<Found>| -> std::vec::Vec<String> {
+            found
+                .into_iter()
+                .map(|f| match f {
+                    Found::Peer(peer) => peer.name,
+                    Found::Node(url) => url,
+                })
+                .collect()
+        };
+
+        // a missing file is reported once
+        assert!(file
+            .check(start + SETTLE)
+            .unwrap_err()
+            .contains("No such file"));
+        assert_eq!(file.check(start + SETTLE * 2), Ok(vec![]));
+
+        fs::write(&path, PEERS).unwrap();
+        let written = start + SETTLE * 3;
+        assert_eq!(file.check(written), Ok(vec![]));
+        assert_eq!(names(file.check(written + SETTLE).unwrap()), ["b", "c"]);
+
+        // an edit keeping the length and the modification time
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+        fs::write(&path, PEERS.replace("10.0.0.2", "10.0.0.3")).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        let written = written + SETTLE * 2;
+        match &file.check(written).unwrap()[0] {
+            Found::Peer(peer) => assert_eq!(peer.address, "10.0.0.3:9000"),
+            found => panic!("{:?}", found),
+        }
+
+        // an invalid one keeps the peers of the last valid one
+        fs::write(&path, PEERS.replace("10.0.0.2:9000", "10.0.0.2")).unwrap();
+        let written = written + SETTLE * 2;
+        assert_eq!(names(file.check(written).unwrap()), ["b", "c"]);
+        assert!(file
+            .check(written + SETTLE)
+            .unwrap_err()
+            .contains("is not host:port"));
+        assert_eq!(names(file.check(written + SETTLE * 2).unwrap()), ["b", "c"]);
+
+        // one still being written is read once it settled
+        let half = &PEERS[..PEERS.find("},").unwrap() + 1];
+        fs::write(&path, half).unwrap();
+        let written = written + SETTLE * 3;
+        assert_eq!(names(file.check(written).unwrap()), ["b", "c"]);
+        fs::write(&path, format!("{}]", half)).unwrap();
+        let rewritten = written + SETTLE / 2;
+        assert_eq!(names(file.check(rewritten).unwrap()), ["b", "c"]);
+        assert_eq!(names(file.check(rewritten + SETTLE).unwrap()), ["b"]);
+        fs::remove_file(&path).unwrap();
+    }
+}
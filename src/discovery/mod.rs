@@ -0,0 +1,355 @@
+//! Finding the other nodes of the cluster without registering them by hand.
+//! Every source is asked for the nodes it knows at its interval; nodes
+//! that appear are registered with the `UdpActor` and nodes that disappear
+//! unregistered, leaving peers registered otherwise alone.
+
+pub mod dns;
+pub mod file;
+pub mod multicast;
+
+use crate::{
+    actor::heartbeat::{Discovered, UdpActor},
+    config::Config,
+    peer::Peer,
+};
+use actix::prelude::*;
+use actix_http::{
+    client::ConnectError,
+    http::{StatusCode, Uri},
+};
+use awc::error::SendRequestError;
+use futures::future::{join_all, LocalBoxFuture};
+use log::{info, warn};
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::IpAddr,
+    time::Duration,
+};
+
+/// A node reported by a source.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Found {
+    /// A node the source fully describes.
+    Peer(Peer),
+    /// The base URL of a node's API, whose `/api/info` describes it.
+    Node(String),
+}
+
+/// Somewhere the nodes of the cluster are listed.
+pub trait Source {
+    /// Names the source in logs, such as `dns _http._tcp.phifd`.
+    fn name(&self) -> String;
+
+    /// How often to ask, when not `discovery.interval`.
+    fn interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// The nodes listed right now. An error keeps the peers found last time.
+    fn discover(&mut self) -> LocalBoxFuture<'static, Result<std::vec::Vec<Found>, String>>;
+}
+
+/// The sources the configuration enables.
+pub fn sources(config: &Config) -> Result<std::vec::Vec<Box<dyn Source>>, String> {
+    let mut sources: std::vec::Vec<Box<dyn Source>> = std::vec::Vec::new();
+    if let Some(path) = &config.discovery.file {
+        sources.push(Box::new(file::File::new(path.clone())));
+    }
+    if let Some(settings) = &config.discovery.dns {
+        let port = settings.port.unwrap_or_default();
+        let dns = dns::Dns::new(
+            settings.name.clone(),
+            settings.record,
+            settings.scheme,
+            port,
+            config.nameserver()?,
+        )?;
+        sources.push(Box::new(dns));
+    }
+    if let Some(settings) = config.multicast()? {
+        let multicast = multicast::Multicast::new(settings, config.me()?)
+            .map_err(|e| format!("discovery.multicast: {}", e))?;
+        sources.push(Box::new(multicast));
+    }
+    Ok(sources)
+}
+
+/// Fetches the identity of the node whose API is at `base`, over HTTP or
+/// HTTPS, presenting `token` when given.
+pub async fn info(base: &str, token: Option<&str>) -> Result<Peer, String> {
+    let url = format!("{}/api/info", base.trim_end_matches('/'));
+    check_tls_host(&url)?;
+    let mut client = awc::Client::build();
+    if let Some(token) = token {
+        client = client.bearer_auth(token);
+    }
+    let mut res = client
+        .finish()
+        .get(&url)
+        .send()
+        .await
+        .map_err(|e| failure(&url, e))?;
+    match res.status() {
+        status if status.is_success() => (),
+        StatusCode::UNAUTHORIZED if token.is_some() => {
+            return Err(format!(
+                "{}: 401 Unauthorized, auth.token is not accepted",
+                url
+            ))
+        }
+        StatusCode::UNAUTHORIZED => {
+            return Err(format!("{}: 401 Unauthorized, auth.token is not set", url))
+        }
+        status => return Err(format!("{}: {}", url, status)),
+    }
+    res.json::<Peer>()
+        .await
+        .map_err(|e| format!("{}: {}", url, e))
+}
+
+/// Fails for HTTPS URLs with an IP address for host: the TLS client only
+/// verifies certificates for host names.
+pub fn check_tls_host(url: &str) -> Result<(), String> {
+    let uri: Uri = url.parse().map_err(|e| format!("{}: {}", url, e))?;
+    let host = uri
+        .host()
+        .unwrap_or("")
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    if uri.scheme_str() == Some("https") && host.parse::<IpAddr>().is_ok() {
+        return Err(format!(
+            "{}: https needs a host name, not an IP address",
+            url
+        ));
+    }
+    Ok(())
+}
+
+/// Describes a failed request, telling a failed TLS handshake apart from a
+/// node that is not up.
+fn failure(url: &str, e: SendRequestError) -> String {
+    match e {
+        // rustls reports handshake and certificate errors as invalid data
+        SendRequestError::Connect(ConnectError::Io(e))
+            if url.starts_with("https://") && e.kind() == io::ErrorKind::InvalidData =>
+        {
+            format!("{}: TLS handshake failed: {}", url, e)
+        }
+        e => format!("{}: {}", url, e),
+    }
+}
+
+struct Polled {
+    source: Box<dyn Source>,
+    /// Identities of the nodes behind the URLs it reported last time.
+    nodes: HashMap<String, Peer>,
+    polling: bool,
+}
+
+/// Polls every source and reports what it found to the `UdpActor`.
+pub struct DiscoveryActor {
+    sources: std::vec::Vec<Polled>,
+    interval: Duration,
+    /// Presented to the nodes reported by URL.
+    token: Option<String>,
+    heartbeat: Addr<UdpActor>,
+}
+
+impl DiscoveryActor {
+    pub fn new(
+        sources: std::vec::Vec<Box<dyn Source>>,
+        interval: Duration,
+        token: Option<String>,
+        heartbeat: Addr<UdpActor>,
+    ) -> Self {
+        let sources = sources
+            .into_iter()
+            .map(|source| Polled {
+                source,
+                nodes: HashMap::new(),
+                polling: false,
+            })
+            .collect();
+        DiscoveryActor {
+            sources,
+            interval,
+            token,
+            heartbeat,
+        }
+    }
+
+    fn poll(&mut self, index: usize, ctx: &mut Context<Self>) {
+        let polled = &mut self.sources[index];
+        // a slow source is not asked again before it answered
+        if polled.polling {
+            return;
+        }
+        polled.polling = true;
+        let lookup = polled.source.discover();
+        let known = polled.nodes.clone();
+        let token = self.token.clone();
+        async move {
+            let found = lookup.await?;
+            Ok::<_, String>(identify(found, known, token.as_deref()).await)
+        }
+        .into_actor(self)
+        .map(move |result, actor, _ctx| {
+            let polled = &mut actor.sources[index];
+            polled.polling = false;
+            let name = polled.source.name();
+            match result {
+                Ok((peers, nodes)) => {
+                    polled.nodes = nodes;
+                    actor.heartbeat.do_send(Discovered {
+                        source: name,
+                        peers,
+                    });
+                }
+                Err(e) => warn!("Discovery through {} failed: {}", name, e),
+            }
+        })
+        .spawn(ctx);
+    }
+}
+
+impl Actor for DiscoveryActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        for index in 0..self.sources.len() {
+            let interval = self.sources[index].source.interval();
+            self.poll(index, ctx);
+            ctx.run_interval(interval.unwrap_or(self.interval), move |actor, ctx| {
+                actor.poll(index, ctx)
+            });
+        }
+    }
+}
+
+/// Turns what a source found into peers, fetching the identity of the nodes
+/// it reported by URL. A node that does not answer keeps the identity it had
+/// in `known`, so a busy node is not dropped; the returned map is the new
+/// `known`.
+async fn identify(
+    found: std::vec::Vec<Found>,
+    known: HashMap<String, Peer>,
+    token: Option<&str>,
+) -> (std::vec::Vec<Peer>, HashMap<String, Peer>) {
+    let mut peers = std::vec::Vec::new();
+    let mut urls = std::vec::Vec::new();
+    for f in found {
+        match f {
+            Found::Peer(peer) => peers.push(peer),
+            Found::Node(url) => urls.push(url),
+        }
+    }
+    let answers = join_all(urls.iter().map(|url| info(url, token))).await;
+    let mut nodes = HashMap::new();
+    for (url, answer) in urls.into_iter().zip(answers) {
+        let peer = match answer {
+            Ok(peer) => peer,
+            Err(e) => match known.get(&url) {
+                Some(peer) => peer.clone(),
+                None => {
+                    info!("Discovered node is not answering yet: {}", e);
+                    continue;
+                }
+            },
+        };
+        nodes.insert(url, peer.clone());
+        peers.push(peer);
+    }
+    // a node may be listed under several names or addresses
+    let mut seen = HashSet::new();
+    peers.retain(|peer| seen.insert(peer.id));
+    (peers, nodes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{identify, info, Found};
+    use crate::peer::Peer;
+    use std::{
+        collections::HashMap,
+        io::{Read, Write},
+        net::TcpListener,
+        thread,
+    };
+    use uuid::Uuid;
+
+    /// Answers every request with 401, returning the address it listens on
+    /// and the requests it got.
+    fn locked_node() -> (String, std::sync::mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (tx, rx) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut buf = [0; 4096];
+                let n = stream.read(&mut buf).unwrap_or(0);
+                let _ = tx.send(String::from_utf8_lossy(&buf[..n]).into_owned());
+                let _ = stream.write_all(
+                    b"HTTP/1.1 401 Unauthorized\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                );
+            }
+        });
+        (addr, rx)
+    }
+
+    #[test]
+    fn reports_credential_and_tls_failures() {
+        let (addr, requests) = locked_node();
+        let ask = |url: String, token: Option<&'static str>| {
+            actix_rt::System::new("info")
+                .block_on(async move { info(&url, token).await })
+                .unwrap_err()
+        };
+
+        let e = ask(format!("http://{}", addr), None);
+        assert!(
+            e.ends_with("401 Unauthorized, auth.token is not set"),
+            "{}",
+            e
+        );
+        let e = ask(format!("http://{}/", addr), Some("s3cret"));
+        assert!(e.ends_with("auth.token is not accepted"), "{}", e);
+        requests.recv().unwrap();
+        let request = requests.recv().unwrap().to_lowercase();
+        assert!(request.starts_with("get /api/info "));
+        assert!(request.contains("authorization: bearer s3cret"));
+
+        // a node that does not speak TLS
+        let port = addr.rsplit(':').next().unwrap();
+        let e = ask(format!("https://localhost:{}", port), None);
+        assert!(e.contains("TLS handshake failed"), "{}", e);
+        let e = ask(format!("https://{}", addr), None);
+        assert!(
+            e.ends_with("https needs a host name, not an IP address"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn silent_nodes_keep_their_identity() {
+        let peer =
+            |name: &str| Peer::new(Uuid::new_v4(), name.to_owned(), format!("{}:9000", name));
+        let (a, b) = (peer("a"), peer("b"));
+        // nothing listens on port 1
+        let (busy, unknown) = ("http://127.0.0.1:1", "http://127.0.0.1:1/");
+        let known: HashMap<String, Peer> = vec![(busy.to_owned(), b.clone())].into_iter().collect();
+        let found = vec![
+            Found::Peer(a.clone()),
+            Found::Node(busy.to_owned()),
+            Found::Node(unknown.to_owned()),
+            Found::Peer(b.clone()),
+        ];
+
+        let (peers, nodes) =
+            actix_rt::System::new("identify").block_on(identify(found, known.clone(), None));
+        assert_eq!(peers, vec![a, b]);
+        assert_eq!(nodes, known);
+    }
+}
@@ -0,0 +1,225 @@
+//! Zero configuration discovery on a LAN: every node announces itself on a
+//! UDP multicast group, or by broadcast, and registers the nodes it hears
+//! announcing the same cluster name.
+
+use super::{Found, Source};
+use crate::{net, peer::Peer};
+use futures::future::{self, FutureExt, LocalBoxFuture};
+use log::warn;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+/// The group announcements go to, unless configured otherwise.
+pub const GROUP: &str = "239.255.70.68:9700";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub cluster: String,
+    pub group: SocketAddr,
+    pub interface: Ipv4Addr,
+    /// How often this node announces itself.
+    pub interval: Duration,
+    /// How long a node that stopped announcing stays registered, so it is
+    /// seen failing before it is forgotten.
+    pub expire: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Announcement {
+    cluster: String,
+    peer: Peer,
+}
+
+pub struct Multicast {
+    settings: Settings,
+    socket: UdpSocket,
+    /// What this node announces.
+    announcement: std::vec::Vec<u8>,
+    me: Uuid,
+    /// Nodes heard from, and when last.
+    heard: HashMap<Uuid, (Peer, Instant)>,
+}
+
+impl Multicast {
+    /// Joins the group to announce `me`, without the detector overrides
+    /// other nodes should not apply.
+    pub fn new(settings: Settings, me: Peer) -> io::Result<Multicast> {
+        let socket = net::multicast(settings.group, settings.interface)?;
+        let announcement = serde_json::to_vec(&Announcement {
+            cluster: settings.cluster.clone(),
+            peer: Peer::new(me.id, me.name, me.address),
+        })?;
+        Ok(Multicast {
+            settings,
+            socket,
+            announcement,
+            me: me.id,
+            heard: HashMap::new(),
+        })
+    }
+
+    fn announce(&self) -> io::Result<()> {
+        self.socket
+            .send_to(&self.announcement, self.settings.group)
+            .map(|_| ())
+    }
+
+    /// The nodes of the cluster that announced themselves within the expiry,
+    /// taking in the announcements received since the last call.
+    pub fn listen(&mut self, now: Instant) -> io::Result<std::vec::Vec<Found>> {
+        let mut buf = [0; 2048];
+        loop {
+            let (n, from) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            };
+            match serde_json::from_slice::<Announcement>(&buf[..n]) {
+                Ok(a) if a.cluster != self.settings.cluster || a.peer.id == self.me => (),
+                // detector overrides are not announced, nor taken from others
+                Ok(a) => match net::split_host_port(&a.peer.address) {
+                    Ok(_) => {
+                        let peer = Peer::new(a.peer.id, a.peer.name, a.peer.address);
+                        self.heard.insert(peer.id, (peer, now));
+                    }
+                    Err(e) => warn!("Ignoring announcement from {}: {}", from, e),
+                },
+                Err(e) => warn!("Ignoring announcement from {}: {}", from, e),
+            }
+        }
+        let expire = self.settings.expire;
+        self.heard
+            .retain(|_, (_, heard)| now.duration_since(*heard) < expire);
+        let mut peers: std::vec::Vec<Peer> =
+            self.heard.values().map(|(peer, _)| peer.clone()).collect();
+        peers.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(peers.into_iter().map(Found::Peer).collect())
+    }
+}
+
+impl Source for Multicast {
+    fn name(&self) -> String {
+        format!(
+            "multicast {} on {}",
+            self.settings.cluster, self.settings.group
+        )
+    }
+
+    fn interval(&self) -> Option<Duration> {
+        Some(self.settings.interval)
+    }
+
+    fn discover(&mut self) -> LocalBoxFuture<'static, Result<std::vec::Vec<Found>, String>> {
+        let result = self
+            .announce()
+            .and_then(|_| self.listen(Instant::now()))
+            .map_err(|e| e.to_string());
+        future::ready(result).boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Announcement, Multicast, Settings};
+    use crate::{discovery::Found, peer::Peer, phi::DetectorConfig};
+    use std::{
+        net::{Ipv4Addr, UdpSocket},
+        thread,
+        time::{Duration, Instant},
+    };
+    use uuid::Uuid;
+
+    #[test]
+    fn hears_its_cluster_only() {
+        // a port of its own, so concurrent test runs do not hear each other
+        let port = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let settings = |cluster: &str| Settings {
+            cluster: cluster.to_owned(),
+            group: format!("239.255.70.68:{}", port).parse().unwrap(),
+            interface: Ipv4Addr::LOCALHOST,
+            interval: Duration::from_secs(5),
+            expire: Duration::from_secs(60),
+        };
+        let node =
+            |name: &str| Peer::new(Uuid::new_v4(), name.to_owned(), format!("{}:9000", name));
+        let (a, b, other) = (node("a"), node("b"), node("other"));
+        let mut a_side = Multicast::new(settings("prod"), a.clone()).unwrap();
+        let b_side = Multicast::new(settings("prod"), b.clone()).unwrap();
+        let other_side = Multicast::new(settings("test"), other).unwrap();
+
+        for side in &[&a_side, &b_side, &other_side] {
+            side.announce().unwrap();
+        }
+        let invalid = Announcement {
+            cluster: "prod".to_owned(),
+            peer: Peer::new(Uuid::new_v4(), "bad".to_owned(), "bad".to_owned()),
+        };
+        b_side
+            .socket
+            .send_to(
+                &serde_json::to_vec(&invalid).unwrap(),
+                b_side.settings.group,
+            )
+            .unwrap();
+        thread::sleep(Duration::from_millis(200));
+
+        let now = Instant::now();
+        assert_eq!(a_side.listen(now).unwrap(), vec![Found::Peer(b)]);
+        // b stays until it has been silent for the expiry
+        assert_eq!(
+            a_side.listen(now + Duration::from_secs(59)).unwrap().len(),
+            1
+        );
+        assert!(a_side
+            .listen(now + Duration::from_secs(60))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn ignores_announced_overrides() {
+        let port = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let settings = Settings {
+            cluster: "prod".to_owned(),
+            group: format!("239.255.70.68:{}", port).parse().unwrap(),
+            interface: Ipv4Addr::LOCALHOST,
+            interval: Duration::from_secs(5),
+            expire: Duration::from_secs(60),
+        };
+        let me = Peer::new(Uuid::new_v4(), "a".to_owned(), "a:9000".to_owned());
+        let mut listener = Multicast::new(settings.clone(), me).unwrap();
+
+        let mut hostile = Peer::new(Uuid::new_v4(), "b".to_owned(), "b:9000".to_owned());
+        hostile.detector = Some(DetectorConfig {
+            sample_size: Some(0),
+            ..DetectorConfig::default()
+        });
+        let announcement = Announcement {
+            cluster: "prod".to_owned(),
+            peer: hostile.clone(),
+        };
+        listener
+            .socket
+            .send_to(&serde_json::to_vec(&announcement).unwrap(), settings.group)
+            .unwrap();
+        thread::sleep(Duration::from_millis(200));
+
+        let heard = listener.listen(Instant::now()).unwrap();
+        let expected = Peer::new(hostile.id, hostile.name, hostile.address);
+        assert_eq!(heard, vec![Found::Peer(expected)]);
+    }
+}
@@ -1,35 +1,70 @@
-use crate::{actor, messages, peer};
+use crate::{
+    actor,
+    auth::{Admin, Reader},
+    messages, peer,
+    reload::{Reload, Reloader},
+};
 use actix::Addr;
 use actix_web::{error, web, Error, HttpRequest, HttpResponse, Responder};
 use actix_web_actors::ws;
-use actor::{heartbeat::UdpActor, inventory::InventoryActor, monitor::MonitorActor};
-use futures::future::{ready, Ready};
+use actor::{
+    heartbeat::UdpActor,
+    inventory::InventoryActor,
+    monitor::{ConnectEvents, MonitorActor},
+};
+use futures::{
+    future::{ready, Ready},
+    StreamExt,
+};
 use peer::Peer;
-use std::time::Instant;
+use serde_derive::{Deserialize, Serialize};
 
 #[derive(Clone)]
 pub struct AppState {
     pub inventory: Addr<InventoryActor>,
     pub heartbeat: Addr<UdpActor>,
     pub monit: Addr<MonitorActor>,
-    pub me: Peer,
+    pub reloader: Addr<Reloader>,
+    pub me: Info,
 }
 
-impl Responder for peer::Peer {
+/// This node as other nodes reach it: its advertised heartbeat address and
+/// API URL. Registering the whole document registers the node.
+#[derive(Debug, Clone, Serialize)]
+pub struct Info {
+    #[serde(flatten)]
+    pub peer: Peer,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+impl Responder for Info {
     type Error = Error;
     type Future = Ready<Result<HttpResponse, Error>>;
 
     fn respond_to(self, _req: &HttpRequest) -> Self::Future {
         let body = serde_json::to_string(&self).unwrap();
 
-        // Create response and set content type
+        // Create response and set content type; other nodes' dashboards may
+        // fetch it to register this node
         ready(Ok(HttpResponse::Ok()
             .content_type("application/json")
+            .header("Access-Control-Allow-Origin", "*")
             .body(body)))
     }
 }
 
-pub async fn register(peer: web::Json<peer::Peer>, data: web::Data<AppState>) -> HttpResponse {
+pub async fn register(
+    _: Admin,
+    peer: web::Json<peer::Peer>,
+    data: web::Data<AppState>,
+) -> HttpResponse {
+    if let Some(Err(e)) = peer.detector.as_ref().map(|c| c.validate()) {
+        return HttpResponse::BadRequest().body(e);
+    }
+    if let Err(e) = crate::net::split_host_port(&peer.address) {
+        return HttpResponse::BadRequest().body(e);
+    }
     let addr = data.get_ref().heartbeat.clone();
 
     let res = addr
@@ -41,7 +76,11 @@ pub async fn register(peer: web::Json<peer::Peer>, data: web::Data<AppState>) ->
     }
 }
 
-pub async fn unregister(id: web::Path<uuid::Uuid>, data: web::Data<AppState>) -> HttpResponse {
+pub async fn unregister(
+    _: Admin,
+    id: web::Path<uuid::Uuid>,
+    data: web::Data<AppState>,
+) -> HttpResponse {
     let addr = data.get_ref().heartbeat.clone();
 
     let res = addr
@@ -53,21 +92,105 @@ pub async fn unregister(id: web::Path<uuid::Uuid>, data: web::Data<AppState>) ->
     }
 }
 
-pub async fn info(data: web::Data<AppState>) -> impl Responder {
+/// Reloads the configuration, answering with what was applied, or with the
+/// problems found if it is invalid.
+pub async fn reload(_: Admin, data: web::Data<AppState>) -> HttpResponse {
+    match data.get_ref().reloader.send(Reload).await {
+        Ok(Ok(outcome)) => HttpResponse::Ok().json(outcome),
+        Ok(Err(errors)) => HttpResponse::BadRequest().json(errors),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+pub async fn info(_: Reader, data: web::Data<AppState>) -> impl Responder {
     data.get_ref().me.clone()
 }
 
+/// Server-Sent Events stream of status ticks and transitions. Clients that
+/// reconnect with `Last-Event-ID` get the events they missed, when still
+/// buffered.
+pub async fn events(
+    _: Reader,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> HttpResponse {
+    let last_event_id = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok());
+
+    let res = data
+        .get_ref()
+        .monit
+        .send(ConnectEvents { last_event_id })
+        .await;
+    match res {
+        Ok(rx) => HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .header("Cache-Control", "no-cache")
+            .streaming(rx.map(Ok::<_, Error>)),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Range {
+    from: Option<u64>,
+    to: Option<u64>,
+    step: Option<u64>,
+}
+
+/// Phi history of a peer; `from` and `to` are milliseconds since the epoch
+/// and default to the last hour, `step` is in milliseconds.
+pub async fn timeseries(
+    _: Reader,
+    id: web::Path<uuid::Uuid>,
+    range: web::Query<Range>,
+    data: web::Data<AppState>,
+) -> HttpResponse {
+    let to = range.to.map_or_else(crate::phi::now, u128::from);
+    let from = range
+        .from
+        .map_or_else(|| to.saturating_sub(60 * 60 * 1000), u128::from);
+    if from > to || range.step == Some(0) {
+        return HttpResponse::BadRequest()
+            .body("from must not be after to and step must be positive");
+    }
+
+    let res = data
+        .get_ref()
+        .inventory
+        .send(messages::GetTimeSeries {
+            id: id.into_inner(),
+            from,
+            to,
+            step: range.step.map(u128::from),
+        })
+        .await;
+    match res {
+        Ok(Some(query)) => HttpResponse::Ok().json(query),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+/// Websocket monitoring sessions; commands that change the peers need the
+/// admin role.
 pub async fn index(
+    Reader(role): Reader,
     req: HttpRequest,
     stream: web::Payload,
     srv: web::Data<AppState>,
 ) -> Result<HttpResponse, error::Error> {
+    let srv = srv.get_ref();
     ws::start(
-        actor::monitor::MonitorSession {
-            id: uuid::Uuid::new_v4(),
-            hb: Instant::now(),
-            monit: srv.get_ref().monit.clone(),
-        },
+        actor::monitor::MonitorSession::new(
+            srv.monit.clone(),
+            srv.inventory.clone(),
+            srv.heartbeat.clone(),
+            role,
+        ),
         &req,
         stream,
     )
@@ -1,5 +1,14 @@
 #![allow(dead_code)]
 mod actor;
+mod auth;
+mod calibrate;
 mod messages;
+mod net;
 mod peer;
-mod phi;
\ No newline at end of file
+mod phi;
+mod protocol;
+#[cfg(test)]
+mod sim;
+mod sse;
+mod timeseries;
+mod trace;
\ No newline at end of file
@@ -0,0 +1,72 @@
+//! `env_logger` behind a handle whose filter can be replaced while running,
+//! so a configuration reload can change log levels.
+
+use log::{Log, Metadata, Record, SetLoggerError};
+use std::sync::RwLock;
+
+pub struct Logger(RwLock<env_logger::Logger>);
+
+/// Installs the global logger with `filter` and returns a handle to it.
+pub fn init(filter: &str) -> Result<&'static Logger, SetLoggerError> {
+    let logger: &'static Logger = Box::leak(Box::new(Logger::new(filter)));
+    log::set_logger(logger)?;
+    log::set_max_level(logger.0.read().unwrap().filter());
+    Ok(logger)
+}
+
+fn build(filter: &str) -> env_logger::Logger {
+    env_logger::Builder::new().parse_filters(filter).build()
+}
+
+impl Logger {
+    fn new(filter: &str) -> Logger {
+        Logger(RwLock::new(build(filter)))
+    }
+
+    /// Replaces the filter of every later log call.
+    pub fn set_filter(&self, filter: &str) {
+        let inner = build(filter);
+        log::set_max_level(inner.filter());
+        *self.0.write().unwrap() = inner;
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.read().unwrap().enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.0.read().unwrap().log(record)
+    }
+
+    fn flush(&self) {
+        self.0.read().unwrap().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Logger;
+    use log::{Level, Log, Metadata};
+
+    #[test]
+    fn filter_applies_to_node_messages() {
+        let discovery = |level| {
+            Metadata::builder()
+                .level(level)
+                .target("phifd::discovery")
+                .build()
+        };
+        let logger = Logger::new("info");
+        assert!(logger.enabled(&discovery(Level::Info)));
+        assert!(!logger.enabled(&discovery(Level::Debug)));
+
+        logger.set_filter("warn");
+        assert!(!logger.enabled(&discovery(Level::Info)));
+        assert!(logger.enabled(&discovery(Level::Warn)));
+
+        logger.set_filter("warn,phifd::discovery=debug");
+        assert!(logger.enabled(&discovery(Level::Debug)));
+    }
+}
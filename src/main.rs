@@ -1,80 +1,187 @@
 #![allow(dead_code)]
 
 mod actor;
+mod auth;
+mod assets;
+mod calibrate;
+mod config;
+mod discovery;
 mod handlers;
+mod logging;
 mod messages;
+mod net;
 mod peer;
 mod phi;
+mod protocol;
+mod reload;
+mod sse;
+mod timeseries;
+mod tls;
+mod trace;
 
-use crate::actor::heartbeat::{UdpActor, UdpPacket};
+use crate::actor::heartbeat::{self, StaticPeers, UdpActor};
 
 use actix::prelude::*;
 use dotenv::dotenv;
-use futures_util::stream::StreamExt;
-use std::io::Result;
-use std::{collections::HashMap, env, net::{SocketAddr, ToSocketAddrs}};
+use log::{info, warn};
+use std::io;
+use std::{collections::{HashMap, HashSet}, env, process, sync::Arc, time::Duration};
 use tokio::net::UdpSocket;
-use tokio_util::udp::UdpFramed;
 
-use actix::io::SinkWrite;
-
-use actix_files;
-use actix_web::{web, App, HttpResponse, HttpServer};
+use actix_http::{error::DispatchError, HttpService, Protocol, Request};
+use actix_rt::net::TcpStream;
+use actix_server::Server;
+use actix_service::{apply_fn_factory, fn_service, map_config, pipeline_factory, Service};
+use actix_web::{dev::AppConfig, web, App, HttpResponse, HttpServer};
+use futures::TryFutureExt;
 use actor::{inventory::InventoryActor, monitor::MonitorActor};
-use messages::HeartBeat;
+
+/// How long to wait before asking an unreachable seed again.
+const SEED_RETRY: Duration = Duration::from_secs(5);
 
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
-    env_logger::init();
 
-    let m_cfg = env::var("PHI_ME").expect("PHI_ME is not set!");
-    let raddr_cfg = env::var("PHI_REST").expect("PHI_REST is not set!");
+    let args: Vec<String> = env::args().skip(1).collect();
+    let command = config::parse_args(&args).unwrap_or_else(|e| {
+        eprintln!("{}\n\n{}", e, config::USAGE);
+        process::exit(2)
+    });
+    let flags = match command {
+        config::Command::Calibrate(args) => return calibrate::run(&args),
+        config::Command::Help => {
+            println!("{}", config::USAGE);
+            return Ok(());
+        }
+        config::Command::Version => {
+            println!("phifd {}", env!("CARGO_PKG_VERSION"));
+            return Ok(());
+        }
+        config::Command::Run(ref flags) | config::Command::Check(ref flags) => flags.clone(),
+    };
+
+    let mut config = config::Config::resolve(&flags, |name| env::var(name).ok())
+        .unwrap_or_else(|errors| {
+            eprintln!("invalid configuration:");
+            for e in errors {
+                eprintln!("  {}", e);
+            }
+            process::exit(2)
+        });
+    if let config::Command::Check(_) = command {
+        let effective = toml::to_string(&config.redacted()).map_err(|e| invalid(e.to_string()))?;
+        print!("{}", effective);
+        return Ok(());
+    }
+    let logger = logging::init(&config.log.level).map_err(|e| invalid(e.to_string()))?;
+    config.identify().unwrap_or_else(|e| {
+        eprintln!("invalid configuration:\n  {}", e);
+        process::exit(2)
+    });
 
-    let me_info = peer::Peer::from(m_cfg);
+    let me_info = config.me().map_err(invalid)?;
     let me = me_info.id;
 
-    let inv = InventoryActor::new(me);
+    let tiers = config.tiers().map_err(invalid)?;
+    let inv = InventoryActor::new(me, config.detector(), timeseries::TimeSeries::new(tiers));
     let inv_addr = inv.start();
 
-    let addr: SocketAddr = me_info.address.as_str().to_socket_addrs()?.next().unwrap();
-    let sock = UdpSocket::bind(&addr).await.unwrap();
-    println!(
+    let trace = match &config.trace.path {
+        Some(path) => Some(trace::TraceWriter::open(
+            path,
+            config.trace.max_bytes,
+            config.trace.max_files,
+        )?),
+        None => None,
+    };
+
+    let addr = config.listen_addr().map_err(invalid)?;
+    let (sock, stack) = net::bind(addr)?;
+    let sock = UdpSocket::from_std(sock)?;
+    info!(
         "Started udp server on: {:?}",
         sock.local_addr().unwrap().port()
     );
-    let (sink, stream) = UdpFramed::new(sock, messages::HBCodec).split();
-    let hb = UdpActor::create(|p_ctx| {
-        p_ctx.add_stream(
-            stream.filter_map(|item: Result<(HeartBeat, SocketAddr)>| async {
-                item.map(|(data, sender)| UdpPacket(data, sender)).ok()
-            }),
-        );
-        UdpActor {
-            me,
-            sink: SinkWrite::new(sink, p_ctx),
-            inventory: inv_addr.clone(),
-            monitored: HashMap::new(),
-        }
+    let resolver = net::Resolver::new(config.node.address_family, stack);
+    let ping_interval = config.ping_interval().map_err(invalid)?;
+    let resolve_interval = config.resolve_interval().map_err(invalid)?;
+    let hb = UdpActor::create(|p_ctx| UdpActor {
+        me,
+        transport: heartbeat::udp(sock, p_ctx),
+        inventory: inv_addr.clone(),
+        monitored: HashMap::new(),
+        trace,
+        ping_interval,
+        resolve_interval,
+        resolver,
+        static_peers: HashMap::new(),
+        discovered: HashMap::new(),
+        added: HashSet::new(),
+        clock: phi::Clock::System,
     });
+    hb.do_send(StaticPeers(config.peers.clone()));
+
+    let sources = discovery::sources(&config).map_err(invalid)?;
+    if !sources.is_empty() {
+        let interval = config.discovery_interval().map_err(invalid)?;
+        discovery::DiscoveryActor::new(sources, interval, config.auth.token.clone(), hb.clone())
+            .start();
+    }
 
-    let monit = MonitorActor(std::collections::HashMap::new(), inv_addr.clone()).start();
+    let monit = MonitorActor::new(inv_addr.clone()).start();
+
+    let reloader = reload::Reloader::new(
+        flags,
+        config.clone(),
+        hb.clone(),
+        inv_addr.clone(),
+        logger,
+    )
+    .start();
+    reload::on_hangup(reloader.clone())?;
+
+    let auth = config.auth().map_err(invalid)?;
+    if !auth.enabled() {
+        warn!("API authentication is disabled, anyone can register and remove peers");
+    }
 
     let state = handlers::AppState {
         inventory: inv_addr,
         heartbeat: hb,
-        monit: monit,
-        me: me_info,
+        monit,
+        reloader,
+        me: handlers::Info {
+            peer: me_info,
+            url: config.http.url.clone(),
+        },
     };
 
-    HttpServer::new(move || {
+    for seed in config.seeds.clone() {
+        actix_rt::spawn(join(
+            seed,
+            config.auth.token.clone(),
+            state.heartbeat.clone(),
+        ));
+    }
+
+    let tls = config.tls().map_err(invalid)?;
+    let bind = config.http.bind.clone();
+    let app = move || {
         App::new()
             .data(state.clone())
+            .data(auth.clone())
             .service(
                 web::scope("/api")
                     .route("/info", web::get().to(handlers::info))
+                    .route("/reload", web::post().to(handlers::reload))
                     .route("/register", web::post().to(handlers::register))
-                    .route("/unregister/{id}", web::delete().to(handlers::unregister)),
+                    .route("/unregister/{id}", web::delete().to(handlers::unregister))
+                    .route("/events", web::get().to(handlers::events))
+                    .route(
+                        "/peers/{id}/timeseries",
+                        web::get().to(handlers::timeseries),
+                    ),
             )
             // redirect to websocket.html
             .service(web::resource("/").route(web::get().to(|| {
@@ -84,10 +191,73 @@ async fn main() -> std::io::Result<()> {
             })))
             // websocket
             .service(web::resource("/ws/").to(handlers::index))
-            // static resources
-            .service(actix_files::Files::new("/static/", "static/"))
-    })
-    .bind(raddr_cfg)?
-    .run()
-    .await
+            // dashboard, embedded in the binary
+            .route("/static/{name}", web::get().to(assets::serve))
+    };
+
+    match tls {
+        Some(settings) => {
+            let certs = Arc::new(tls::Certificates::load(
+                settings.cert.clone(),
+                settings.key.clone(),
+            )?);
+            let acceptor = tls::acceptor(&settings, certs.clone())?;
+            tls::watch(certs);
+            info!("Serving HTTPS on {}", bind);
+
+            // HttpServer cannot expose the client certificate to handlers, so
+            // TLS connections are fed to the HTTP service by hand
+            Server::build()
+                .bind("phifd-https", bind.clone(), move || {
+                    let acceptor = acceptor.clone();
+                    pipeline_factory(fn_service(move |io: TcpStream| {
+                        let peer = io.peer_addr().ok();
+                        acceptor
+                            .accept(io)
+                            .map_ok(move |io| (io, Protocol::Http1, peer))
+                            .map_err(DispatchError::Io)
+                    }))
+                    .and_then({
+                        // actix-web only builds secure AppConfigs for its own
+                        // listeners, so requests get absolute https URIs
+                        // instead, which connection info reads the same way
+                        let bind = bind.clone();
+                        HttpService::build()
+                            .secure()
+                            .on_connect(tls::client_identity)
+                            .finish(apply_fn_factory(
+                                map_config(app(), |_| AppConfig::default()),
+                                move |mut req: Request, srv| {
+                                    tls::secure_uri(req.head_mut(), &bind);
+                                    srv.call(req)
+                                },
+                            ))
+                    })
+                })?
+                .run()
+                .await
+        }
+        None => HttpServer::new(app).bind(bind)?.run().await,
+    }
+}
+
+fn invalid(e: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e)
+}
+
+/// Registers the node behind a seed URL, retrying until it answers.
+async fn join(seed: String, token: Option<String>, heartbeat: Addr<UdpActor>) {
+    loop {
+        match discovery::info(&seed, token.as_deref()).await {
+            Ok(peer) => {
+                info!("Joined seed {}", seed);
+                heartbeat.do_send(messages::Monitor::Register(peer));
+                return;
+            }
+            Err(e) => {
+                warn!("Joining seed failed, retrying in {:?}: {}", SEED_RETRY, e);
+                actix_rt::time::delay_for(SEED_RETRY).await
+            }
+        }
+    }
 }
@@ -1,30 +1,69 @@
-use crate::actor::monitor::MonitorActor;
-use actix::prelude::{Addr, Message};
+use actix::prelude::{Message, Recipient};
 
 use serde_derive::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::{peer::Peer, phi::State};
+use crate::{
+    peer::Peer,
+    phi::{Parameters, State},
+    timeseries::Query,
+};
 
 use bytes::{BufMut, BytesMut};
 use serde_json as json;
 use std::{io, net::SocketAddr};
 use tokio_util::codec::{Decoder, Encoder};
 
-#[derive(Debug, Clone, Serialize, Deserialize, Message)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Message)]
 #[rtype(result = "()")]
 pub enum HeartBeat {
-    Ping(Uuid, u128),
-    Pong(Uuid, u128),
+    Ping(Uuid, u64, u128),
+    Pong(Uuid, u64, u128),
     Unknown,
-    DoPing(Uuid, u128, SocketAddr),
+    DoPing(Uuid, u64, u128, SocketAddr),
+}
+
+/// A pong received from a peer: the sequence number and send time echoed
+/// from our ping, and the local time it arrived.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Message)]
+#[rtype(result = "()")]
+pub struct Arrival {
+    pub id: Uuid,
+    pub seq: u64,
+    pub sent: u128,
+    pub received: u128,
+}
+
+/// The socket address a peer's heartbeat address currently resolves to.
+#[derive(Debug, Clone, Copy, PartialEq, Message)]
+#[rtype(result = "()")]
+pub struct Resolved {
+    pub id: Uuid,
+    pub address: SocketAddr,
 }
 
 #[derive(Clone, Message)]
 #[rtype(result = "()")]
 pub enum StatusEvent {
-    Subscribe(Addr<MonitorActor>),
-    UnSubscribe,
+    Subscribe(Uuid, Recipient<Status>),
+    UnSubscribe(Uuid),
+}
+
+/// Coarse health of a peer, ordered from best to worst: suspect means alive,
+/// but with phi past half the threshold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Level {
+    Alive,
+    Suspect,
+    Dead,
+}
+
+/// The peer entered `level` at `since` and stayed there until the next span.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Span {
+    pub level: Level,
+    pub since: u128,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Message)]
@@ -33,16 +72,42 @@ pub struct PeerStatus {
     pub id: Uuid,
     pub name: String,
     pub address: String,
+    /// What `address` last resolved to, `None` until it did.
+    pub resolved: Option<String>,
     pub history: std::vec::Vec<u128>,
     pub phi: f64,
     pub state: State,
     pub last: u128,
+    pub recorded: u64,
+    /// Milliseconds since the last heartbeat, 0 before the first one.
+    pub age: u128,
+    /// Smoothed round trip time of pings in milliseconds.
+    pub rtt: Option<f64>,
+    pub detector: Parameters,
+    /// Recent level changes, oldest first.
+    pub timeline: std::vec::Vec<Span>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Message)]
 #[rtype(result = "()")]
 pub struct Status(pub std::vec::Vec<PeerStatus>);
 
+/// Asks the inventory for the current status of every peer.
+#[derive(Debug, Clone, Message)]
+#[rtype(result = "Status")]
+pub struct GetStatus;
+
+/// Asks the inventory for the recorded phi history of a peer, `None` if the
+/// peer is unknown.
+#[derive(Debug, Clone, Message)]
+#[rtype(result = "Option<Query>")]
+pub struct GetTimeSeries {
+    pub id: Uuid,
+    pub from: u128,
+    pub to: u128,
+    pub step: Option<u128>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Message)]
 #[rtype(result = "()")]
 pub enum Monitor {
@@ -50,6 +115,15 @@ pub enum Monitor {
     UnRegister(Uuid),
 }
 
+/// Starts every heartbeat packet, followed by `HEARTBEAT_VERSION`.
+const HEARTBEAT_MAGIC: &[u8] = b"PHI";
+/// Version of the heartbeat packets. Version 1 was the unprefixed JSON of
+/// nodes without sequence numbers; nodes only talk to the same version.
+pub const HEARTBEAT_VERSION: u8 = 2;
+
+/// Heartbeat packets: the magic and version, then the message as JSON.
+/// Packets of another version or program fail to decode, while a valid
+/// prefix with an unreadable message decodes as `HeartBeat::Unknown`.
 pub struct HBCodec;
 
 impl Decoder for HBCodec {
@@ -57,7 +131,31 @@ impl Decoder for HBCodec {
     type Error = io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        Ok(json::from_slice::<HeartBeat>(&src)
+        if !src.starts_with(HEARTBEAT_MAGIC) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a heartbeat packet",
+            ));
+        }
+        match src.get(HEARTBEAT_MAGIC.len()) {
+            Some(&HEARTBEAT_VERSION) => (),
+            Some(version) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "heartbeat version {}, expected {}",
+                        version, HEARTBEAT_VERSION
+                    ),
+                ))
+            }
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "truncated heartbeat packet",
+                ))
+            }
+        }
+        Ok(json::from_slice::<HeartBeat>(&src[HEARTBEAT_MAGIC.len() + 1..])
             .ok()
             .or(Some(HeartBeat::Unknown)))
     }
@@ -70,9 +168,37 @@ impl Encoder<HeartBeat> for HBCodec {
         let msg = json::to_string(&msg).unwrap();
         let msg_ref: &[u8] = msg.as_ref();
 
-        dst.reserve(msg_ref.len());
+        dst.reserve(HEARTBEAT_MAGIC.len() + 1 + msg_ref.len());
+        dst.put(HEARTBEAT_MAGIC);
+        dst.put_u8(HEARTBEAT_VERSION);
         dst.put(msg_ref);
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{HBCodec, HeartBeat, HEARTBEAT_VERSION};
+    use bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+    use uuid::Uuid;
+
+    #[test]
+    fn heartbeats_carry_their_version() {
+        let ping = HeartBeat::Ping(Uuid::new_v4(), 7, 1_000);
+        let mut wire = BytesMut::new();
+        HBCodec.encode(ping.clone(), &mut wire).unwrap();
+        assert_eq!(&wire[..4], &[b'P', b'H', b'I', HEARTBEAT_VERSION][..]);
+        assert_eq!(HBCodec.decode(&mut wire.clone()).unwrap(), Some(ping));
+
+        let decode = |packet: &[u8]| HBCodec.decode(&mut BytesMut::from(packet));
+        let e = decode(b"PHI\x01{}").unwrap_err();
+        assert_eq!(e.to_string(), "heartbeat version 1, expected 2");
+        // nodes of version 1 sent bare JSON
+        let e = decode(br#"{"Ping":["b7822b9d-a32b-4525-9af6-45216cd96d40",1]}"#).unwrap_err();
+        assert_eq!(e.to_string(), "not a heartbeat packet");
+        assert!(decode(b"PHI").is_err());
+        assert_eq!(decode(b"PHI\x02garbage").unwrap(), Some(HeartBeat::Unknown));
+    }
+}
@@ -0,0 +1,319 @@
+//! Heartbeat addresses: `host:port` with bracketed IPv6 literals, resolution
+//! honoring an address family preference, dual-stack sockets and the
+//! multicast sockets of LAN discovery.
+
+use serde_derive::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, ToSocketAddrs, UdpSocket},
+    str::FromStr,
+};
+
+/// Which addresses to use when a host resolves to both families.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Family {
+    /// In the order the resolver returns them.
+    Any,
+    /// IPv4 when there is one, else IPv6.
+    Ipv4,
+    /// IPv6 when there is one, else IPv4.
+    Ipv6,
+}
+
+impl FromStr for Family {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "any" => Ok(Family::Any),
+            "ipv4" => Ok(Family::Ipv4),
+            "ipv6" => Ok(Family::Ipv6),
+            _ => Err(format!(
+                "unknown address family {:?}, expected any, ipv4 or ipv6",
+                s
+            )),
+        }
+    }
+}
+
+impl Family {
+    /// The preferred address among `candidates`.
+    pub fn pick(self, candidates: &[SocketAddr]) -> Option<SocketAddr> {
+        let preferred = candidates.iter().find(|a| match self {
+            Family::Any => true,
+            Family::Ipv4 => a.is_ipv4(),
+            Family::Ipv6 => a.is_ipv6(),
+        });
+        preferred.or_else(|| candidates.first()).copied()
+    }
+}
+
+/// Destinations a bound socket can send to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stack {
+    V4,
+    V6,
+    /// An IPv6 socket on the unspecified address that also carries IPv4.
+    Dual,
+}
+
+impl Stack {
+    pub fn reaches(self, addr: &SocketAddr) -> bool {
+        match self {
+            Stack::V4 => addr.is_ipv4(),
+            Stack::V6 => addr.is_ipv6(),
+            Stack::Dual => true,
+        }
+    }
+
+    /// `addr` as the socket must be given it: IPv4 addresses are mapped
+    /// into IPv6 on a dual-stack socket.
+    pub fn destination(self, addr: SocketAddr) -> SocketAddr {
+        match (self, addr) {
+            (Stack::Dual, SocketAddr::V4(v4)) => {
+                SocketAddr::V6(SocketAddrV6::new(v4.ip().to_ipv6_mapped(), v4.port(), 0, 0))
+            }
+            _ => addr,
+        }
+    }
+}
+
+/// Turns peer addresses into destinations of the heartbeat socket.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Resolver {
+    pub family: Family,
+    pub stack: Stack,
+}
+
+impl Resolver {
+    pub fn new(family: Family, stack: Stack) -> Self {
+        Resolver { family, stack }
+    }
+
+    pub fn resolve(&self, address: &str) -> io::Result<SocketAddr> {
+        let (host, port) =
+            split_host_port(address).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        // as a pair, as a zone given by interface name does not parse in a
+        // bracketed address
+        let candidates: std::vec::Vec<SocketAddr> = (host, port).to_socket_addrs()?.collect();
+        self.choose(&candidates).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} has no address the heartbeat socket can reach", address),
+            )
+        })
+    }
+
+    /// The preferred candidate the socket can reach, ready to send to.
+    pub fn choose(&self, candidates: &[SocketAddr]) -> Option<SocketAddr> {
+        let reachable: std::vec::Vec<SocketAddr> = candidates
+            .iter()
+            .filter(|a| self.stack.reaches(a))
+            .copied()
+            .collect();
+        self.family
+            .pick(&reachable)
+            .map(|a| self.stack.destination(a))
+    }
+}
+
+/// `addr` with an IPv4-mapped IPv6 address turned back into IPv4, for
+/// display.
+pub fn unmapped(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(v4) => SocketAddr::new(v4.into(), v6.port()),
+            None => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+/// Splits `host:port`, where an IPv6 host is written in brackets as in
+/// `[::1]:9000`, or with the zone of a link-local address as in
+/// `[fe80::1%eth0]:9000`; the zone is kept in the host.
+pub fn split_host_port(address: &str) -> Result<(&str, u16), String> {
+    let invalid = || format!("{:?} is not host:port", address);
+    let (host, port) = if let Some(rest) = address.strip_prefix('[') {
+        let end = rest.find("]:").ok_or_else(invalid)?;
+        let host = &rest[..end];
+        let (ip, zone) = match host.find('%') {
+            Some(at) => (&host[..at], Some(&host[at + 1..])),
+            None => (host, None),
+        };
+        if ip.parse::<Ipv6Addr>().is_err() || zone == Some("") {
+            return Err(format!("{:?} is not an IPv6 address", host));
+        }
+        (host, &rest[end + 2..])
+    } else {
+        let at = address.rfind(':').ok_or_else(invalid)?;
+        let host = &address[..at];
+        if host.contains(':') {
+            return Err(format!(
+                "{:?}: IPv6 addresses must be bracketed, as in [::1]:9000",
+                address
+            ));
+        }
+        (host, &address[at + 1..])
+    };
+    match port.parse::<u16>() {
+        Ok(port) if !host.is_empty() => Ok((host, port)),
+        _ => Err(invalid()),
+    }
+}
+
+/// Binds a UDP socket; on the IPv6 unspecified address it accepts IPv4 as
+/// well, whatever the system default.
+pub fn bind(addr: SocketAddr) -> io::Result<(UdpSocket, Stack)> {
+    let (domain, stack) = match addr {
+        SocketAddr::V4(_) => (Domain::ipv4(), Stack::V4),
+        SocketAddr::V6(v6) if v6.ip().is_unspecified() => (Domain::ipv6(), Stack::Dual),
+        SocketAddr::V6(_) => (Domain::ipv6(), Stack::V6),
+    };
+    let socket = Socket::new(domain, Type::dgram(), Some(Protocol::udp()))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(stack == Stack::V6)?;
+    }
+    socket.bind(&addr.into())?;
+    let socket = socket.into_udp_socket();
+    socket.set_nonblocking(true)?;
+    Ok((socket, stack))
+}
+
+/// Binds a UDP socket receiving what is sent to `group`, a multicast group or
+/// the IPv4 broadcast address, alongside other sockets of this host bound
+/// the same way. IPv4 groups are joined on `interface`, the default one when
+/// unspecified.
+pub fn multicast(group: SocketAddr, interface: Ipv4Addr) -> io::Result<UdpSocket> {
+    let (domain, any) = match group {
+        SocketAddr::V4(_) => (Domain::ipv4(), IpAddr::from(Ipv4Addr::UNSPECIFIED)),
+        SocketAddr::V6(_) => (Domain::ipv6(), IpAddr::from(Ipv6Addr::UNSPECIFIED)),
+    };
+    let socket = Socket::new(domain, Type::dgram(), Some(Protocol::udp()))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::new(any, group.port()).into())?;
+    match group.ip() {
+        IpAddr::V4(ip) if ip.is_broadcast() => socket.set_broadcast(true)?,
+        IpAddr::V4(ip) => {
+            socket.join_multicast_v4(&ip, &interface)?;
+            socket.set_multicast_if_v4(&interface)?;
+        }
+        IpAddr::V6(ip) => socket.join_multicast_v6(&ip, 0)?,
+    }
+    let socket = socket.into_udp_socket();
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bind, split_host_port, unmapped, Family, Resolver, Stack};
+    use std::net::{SocketAddr, UdpSocket};
+
+    #[test]
+    fn host_port() {
+        assert_eq!(split_host_port("peer1:9000"), Ok(("peer1", 9000)));
+        assert_eq!(split_host_port("[::1]:9000"), Ok(("::1", 9000)));
+        assert_eq!(
+            split_host_port("[fe80::1%eth0]:9000"),
+            Ok(("fe80::1%eth0", 9000))
+        );
+        assert_eq!(
+            split_host_port("[fe80::1%]:9000").unwrap_err(),
+            "\"fe80::1%\" is not an IPv6 address"
+        );
+        assert!(split_host_port("[peer1%eth0]:9000").is_err());
+        assert!(split_host_port("::1:9000").unwrap_err().contains("bracketed"));
+        assert!(split_host_port("[::1]9000").is_err());
+        assert!(split_host_port("[::1]:").is_err());
+        assert!(split_host_port(":9000").is_err());
+        assert!(split_host_port("peer1").is_err());
+    }
+
+    #[test]
+    fn family_preference_and_stack() {
+        let v4: SocketAddr = "192.0.2.1:9000".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:9000".parse().unwrap();
+        let both = [v4, v6];
+
+        assert_eq!(Family::Any.pick(&both), Some(v4));
+        assert_eq!(Family::Ipv6.pick(&both), Some(v6));
+        assert_eq!(Family::Ipv6.pick(&[v4]), Some(v4));
+        assert_eq!(Family::Ipv4.pick(&[]), None);
+
+        let v4_only = Resolver::new(Family::Ipv6, Stack::V4);
+        assert_eq!(v4_only.choose(&both), Some(v4));
+        assert_eq!(Resolver::new(Family::Ipv4, Stack::V6).choose(&[v4]), None);
+
+        let dual = Resolver::new(Family::Ipv4, Stack::Dual);
+        let mapped: SocketAddr = "[::ffff:192.0.2.1]:9000".parse().unwrap();
+        assert_eq!(dual.choose(&both), Some(mapped));
+        assert_eq!(unmapped(mapped), v4);
+        assert_eq!(unmapped(v6), v6);
+        assert_eq!(
+            Resolver::new(Family::Ipv6, Stack::Dual).choose(&both),
+            Some(v6)
+        );
+
+        assert_eq!(
+            Resolver::new(Family::Any, Stack::V6).resolve("[::1]:9000").unwrap(),
+            "[::1]:9000".parse::<SocketAddr>().unwrap()
+        );
+        assert!(Resolver::new(Family::Any, Stack::V4)
+            .resolve("[::1]:9000")
+            .is_err());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn resolve_zone_by_name() {
+        let resolver = Resolver::new(Family::Any, Stack::V6);
+        match resolver.resolve("[fe80::1%lo]:9000").unwrap() {
+            SocketAddr::V6(v6) => {
+                assert_eq!(*v6.ip(), "fe80::1".parse::<std::net::Ipv6Addr>().unwrap());
+                assert_ne!(v6.scope_id(), 0);
+            }
+            other => panic!("expected an IPv6 address, got {}", other),
+        }
+    }
+
+    #[test]
+    fn dual_stack_socket() {
+        // hosts and containers without IPv6 have nothing to test
+        if UdpSocket::bind("[::1]:0").is_err() {
+            println!("Skipping dual_stack_socket: IPv6 loopback is not available");
+            return;
+        }
+        let (socket, stack) = bind("[::]:0".parse().unwrap()).unwrap();
+        assert_eq!(stack, Stack::Dual);
+        socket.set_nonblocking(false).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let resolver = Resolver::new(Family::Any, stack);
+
+        // reachable from an IPv4 peer, and answering it on the mapped address
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        peer.send_to(b"ping", ("127.0.0.1", port)).unwrap();
+        let mut buf = [0; 4];
+        let (_, from) = socket.recv_from(&mut buf).unwrap();
+        let to = resolver
+            .resolve(&peer.local_addr().unwrap().to_string())
+            .unwrap();
+        assert_eq!(from, to);
+        socket.send_to(b"pong", to).unwrap();
+        peer.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf, b"pong");
+
+        // and from an IPv6 one
+        let peer = UdpSocket::bind("[::1]:0").unwrap();
+        let to = resolver
+            .resolve(&peer.local_addr().unwrap().to_string())
+            .unwrap();
+        socket.send_to(b"ping", to).unwrap();
+        peer.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+}
@@ -1,23 +1,43 @@
 use serde_derive::{Deserialize, Serialize};
+use std::str::FromStr;
 use uuid::Uuid;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+use crate::{net, phi::DetectorConfig};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Peer {
     pub id: Uuid,
     pub name: String,
     pub address: String,
+    /// Detector overrides for this peer, when registering it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detector: Option<DetectorConfig>,
 }
 
 impl Peer {
     pub fn new(id: Uuid, name: String, address: String) -> Peer {
-        Peer { id, name, address }
+        Peer {
+            id,
+            name,
+            address,
+            detector: None,
+        }
     }
 }
 
-impl From<String> for Peer {
-    fn from(str_peer: String) -> Self {
-        let prop: Vec<&str> = str_peer.splitn(3,"/").collect();
-        Peer::new(uuid::Uuid::parse_str(prop[0]).unwrap(), prop[1].to_owned(), prop[2].to_owned())
+/// Parses `uuid/name/address`, as in `PHI_ME` and `PHI_PEERS`; the address
+/// may be a bracketed IPv6 literal such as `[::1]:9000`.
+impl FromStr for Peer {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = value.splitn(3, '/').collect();
+        match (fields.as_slice(), Uuid::parse_str(fields[0])) {
+            ([_, name, address], Ok(id)) => {
+                net::split_host_port(address)?;
+                Ok(Peer::new(id, (*name).to_owned(), (*address).to_owned()))
+            }
+            _ => Err(format!("expected uuid/name/address, got {:?}", value)),
+        }
     }
-    
 }
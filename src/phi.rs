@@ -1,16 +1,32 @@
 use serde_derive::{Deserialize, Serialize};
 use std::{
-    collections::VecDeque,
+    collections::{BTreeMap, VecDeque},
+    convert::TryFrom,
+    error,
+    f64::consts::LN_10,
+    fmt,
     time::{SystemTime, UNIX_EPOCH},
 };
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+/// Version written into every `Snapshot`; bump it when the layout changes.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub enum State {
     Alive(f64),
     Dead(f64),
 }
 
-#[derive(Debug)]
+/// How the detector turns the observed intervals into a suspicion level.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum Mode {
+    /// Intervals are assumed to be normally distributed.
+    Normal,
+    /// Suspicion is the empirical tail probability of a histogram of intervals.
+    Histogram,
+}
+
+#[derive(Debug, Clone)]
 struct HeartbeatHistory {
     sample_size: usize,
     intervals: VecDeque<u128>,
@@ -28,16 +44,18 @@ impl HeartbeatHistory {
         }
     }
 
-    fn add(&mut self, interval: u128) {
+    fn add(&mut self, interval: u128) -> Option<u128> {
         self.intervals.push_back(interval);
         self.sum += interval;
         self.sum_squared += interval.pow(2);
         if self.intervals.len() > self.sample_size {
             if let Some(i) = self.intervals.pop_front() {
                 self.sum -= i;
-                self.sum_squared -= i.pow(2)
+                self.sum_squared -= i.pow(2);
+                return Some(i);
             }
         }
+        None
     }
 
     fn mean(&self) -> f64 {
@@ -57,17 +75,280 @@ impl HeartbeatHistory {
     }
 }
 
-#[derive(Debug)]
+/// Number of sub-buckets per power of two, as a power of two.
+const SUB_BUCKET_BITS: u32 = 3;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+
+/// Maps an interval to a log-linear bucket: exact below `2 * SUB_BUCKETS`,
+/// then `SUB_BUCKETS` equal-width buckets for every power of two above it.
+fn bucket_index(value: u128) -> usize {
+    let value = value.min(u64::MAX as u128) as u64;
+    if value < SUB_BUCKETS {
+        return value as usize;
+    }
+    let msb = 63 - value.leading_zeros();
+    let sub = (value >> (msb - SUB_BUCKET_BITS)) & (SUB_BUCKETS - 1);
+    ((msb - SUB_BUCKET_BITS + 1) as u64 * SUB_BUCKETS + sub) as usize
+}
+
+/// Lower bound and width of the values that fall into `index`.
+fn bucket_bounds(index: usize) -> (u64, u64) {
+    let index = index as u64;
+    if index < 2 * SUB_BUCKETS {
+        return (index, 1);
+    }
+    let shift = index / SUB_BUCKETS - 1;
+    let sub = index % SUB_BUCKETS;
+    ((SUB_BUCKETS + sub) << shift, 1 << shift)
+}
+
+#[derive(Debug, Clone)]
+struct HistogramHistory {
+    window: HeartbeatHistory,
+    buckets: BTreeMap<usize, u64>,
+}
+
+impl HistogramHistory {
+    fn new(sample_size: usize) -> HistogramHistory {
+        HistogramHistory {
+            window: HeartbeatHistory::new(sample_size),
+            buckets: BTreeMap::new(),
+        }
+    }
+
+    fn add(&mut self, interval: u128) {
+        *self.buckets.entry(bucket_index(interval)).or_insert(0) += 1;
+        if let Some(evicted) = self.window.add(interval) {
+            let index = bucket_index(evicted);
+            if let Some(count) = self.buckets.get_mut(&index) {
+                *count -= 1;
+                if *count == 0 {
+                    self.buckets.remove(&index);
+                }
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.window.intervals.len()
+    }
+
+    /// Number of samples above `value`, interpolating linearly inside the
+    /// bucket that contains it.
+    fn count_above(&self, value: f64) -> f64 {
+        self.buckets
+            .iter()
+            .map(|(&index, &count)| {
+                let (low, width) = bucket_bounds(index);
+                let (low, high) = (low as f64, (low + width) as f64);
+                if value < low {
+                    count as f64
+                } else if value < high {
+                    count as f64 * (high - value) / width as f64
+                } else {
+                    0.0
+                }
+            })
+            .sum()
+    }
+
+    /// Exclusive upper bound of the largest observed interval.
+    fn upper_bound(&self) -> f64 {
+        self.buckets
+            .keys()
+            .next_back()
+            .map(|&index| {
+                let (low, width) = bucket_bounds(index);
+                (low + width) as f64
+            })
+            .unwrap_or(0.0)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum History {
+    Normal(HeartbeatHistory),
+    Histogram(HistogramHistory),
+}
+
+impl History {
+    fn new(mode: Mode, sample_size: usize) -> History {
+        match mode {
+            Mode::Normal => History::Normal(HeartbeatHistory::new(sample_size)),
+            Mode::Histogram => History::Histogram(HistogramHistory::new(sample_size)),
+        }
+    }
+
+    fn add(&mut self, interval: u128) {
+        match self {
+            History::Normal(history) => {
+                history.add(interval);
+            }
+            History::Histogram(histogram) => histogram.add(interval),
+        }
+    }
+
+    fn window(&self) -> &HeartbeatHistory {
+        match self {
+            History::Normal(history) => history,
+            History::Histogram(histogram) => &histogram.window,
+        }
+    }
+}
+
+/// Serializable checkpoint of a detector: its parameters, the latest
+/// heartbeat and the intervals in its window, oldest first.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Snapshot {
+    pub version: u32,
+    pub threshold: f64,
+    pub sample_size: usize,
+    pub min_std_deviation: f64,
+    pub acceptable_heartbeat_pause: f64,
+    pub first_heartbeat_estimate: u128,
+    pub mode: Mode,
+    pub latest: u128,
+    pub intervals: std::vec::Vec<u128>,
+    #[serde(default)]
+    pub recorded: u64,
+}
+
+/// Configuration of a detector together with the statistics of its current
+/// window, as shown on the dashboard. The deviation is the one phi is
+/// computed with, never below `min_std_deviation`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Parameters {
+    pub threshold: f64,
+    pub sample_size: usize,
+    pub min_std_deviation: f64,
+    pub acceptable_heartbeat_pause: f64,
+    pub first_heartbeat_estimate: u128,
+    pub mode: Mode,
+    pub mean: f64,
+    pub std_deviation: f64,
+}
+
+/// Per-peer overrides of the detector parameters; unset fields keep the
+/// node-wide defaults.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct DetectorConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub threshold: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sample_size: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_std_deviation: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub acceptable_heartbeat_pause: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub first_heartbeat_estimate: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<Mode>,
+}
+
+impl DetectorConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        let positive = |name: &str, value: Option<f64>| match value {
+            Some(v) if v.is_nan() || v <= 0.0 => Err(format!("{} must be positive", name)),
+            _ => Ok(()),
+        };
+        positive("threshold", self.threshold)?;
+        positive("min_std_deviation", self.min_std_deviation)?;
+        // the mean and variance are taken over one less than the samples
+        if matches!(self.sample_size, Some(size) if size < 2) {
+            return Err("sample_size must be at least 2".to_owned());
+        }
+        if self.first_heartbeat_estimate == Some(0) {
+            return Err("first_heartbeat_estimate must be positive".to_owned());
+        }
+        match self.acceptable_heartbeat_pause {
+            Some(v) if v.is_nan() || v < 0.0 => {
+                Err("acceptable_heartbeat_pause must not be negative".to_owned())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// A builder starting from `defaults` with these overrides applied.
+    pub fn builder(
+        &self,
+        defaults: &PhiAccrualFailureDetectorBuilder,
+    ) -> PhiAccrualFailureDetectorBuilder {
+        let mut snapshot = defaults.0.snapshot();
+        self.apply(&mut snapshot);
+        let mut builder = PhiAccrualFailureDetectorBuilder::new();
+        builder
+            .with_threshold(snapshot.threshold)
+            .with_sample_size(snapshot.sample_size)
+            .with_min_std_deviation(snapshot.min_std_deviation)
+            .with_acceptable_heartbeat_pause(snapshot.acceptable_heartbeat_pause)
+            .with_first_heartbeat_estimate(snapshot.first_heartbeat_estimate)
+            .with_mode(snapshot.mode);
+        builder
+    }
+
+    fn apply(&self, snapshot: &mut Snapshot) {
+        if let Some(threshold) = self.threshold {
+            snapshot.threshold = threshold;
+        }
+        if let Some(sample_size) = self.sample_size {
+            snapshot.sample_size = sample_size;
+        }
+        if let Some(min_std_deviation) = self.min_std_deviation {
+            snapshot.min_std_deviation = min_std_deviation;
+        }
+        if let Some(pause) = self.acceptable_heartbeat_pause {
+            snapshot.acceptable_heartbeat_pause = pause;
+        }
+        if let Some(estimate) = self.first_heartbeat_estimate {
+            snapshot.first_heartbeat_estimate = u128::from(estimate);
+        }
+        if let Some(mode) = self.mode {
+            snapshot.mode = mode;
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SnapshotError {
+    UnsupportedVersion(u32),
+    InvalidSampleSize,
+    TooFewIntervals(usize),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::UnsupportedVersion(v) => write!(
+                f,
+                "unsupported snapshot version {} (expected at most {})",
+                v, SNAPSHOT_VERSION
+            ),
+            SnapshotError::InvalidSampleSize => write!(f, "snapshot sample size must be positive"),
+            SnapshotError::TooFewIntervals(n) => {
+                write!(f, "snapshot has {} intervals, at least 2 are needed", n)
+            }
+        }
+    }
+}
+
+impl error::Error for SnapshotError {}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(into = "Snapshot", try_from = "Snapshot")]
 pub struct PhiAccrualFailureDetector {
     threshold: f64,
     sample_size: usize,
     min_std_deviation: f64,
     acceptable_heartbeat_pause: f64,
     first_heartbeat_estimate: u128,
+    mode: Mode,
     latest: u128,
-    history: HeartbeatHistory,
+    recorded: u64,
+    history: History,
 }
 
+#[derive(Clone)]
 pub struct PhiAccrualFailureDetectorBuilder(PhiAccrualFailureDetector);
 
 impl PhiAccrualFailureDetectorBuilder {
@@ -113,9 +394,14 @@ impl PhiAccrualFailureDetectorBuilder {
         self
     }
 
+    pub fn with_mode(&mut self, mode: Mode) -> &mut PhiAccrualFailureDetectorBuilder {
+        self.0.mode = mode;
+        self
+    }
+
     pub fn build(&mut self) -> PhiAccrualFailureDetector {
         let mut detector = PhiAccrualFailureDetector {
-            history: HeartbeatHistory::new(self.0.sample_size),
+            history: History::new(self.0.mode, self.0.sample_size),
             ..self.0
         };
         let std_deviation = detector.first_heartbeat_estimate / 4;
@@ -144,13 +430,15 @@ impl PhiAccrualFailureDetector {
             min_std_deviation,
             acceptable_heartbeat_pause,
             first_heartbeat_estimate,
+            mode: Mode::Normal,
             latest: 0,
-            history: HeartbeatHistory::new(sample_size),
+            recorded: 0,
+            history: History::new(Mode::Normal, sample_size),
         }
     }
 
     fn is_alive(&self, phi: f64) -> bool {
-        return phi != 0.0 && phi < self.threshold;
+        self.latest != 0 && phi < self.threshold
     }
 
     pub fn state(&self, timestamp: u128) -> State {
@@ -175,7 +463,14 @@ impl PhiAccrualFailureDetector {
         }
 
         let diff = (timestamp - self.latest) as f64;
-        let mean = self.history.mean() + self.acceptable_heartbeat_pause as f64;
+        match &self.history {
+            History::Normal(history) => self.normal_phi(history, diff),
+            History::Histogram(histogram) => self.histogram_phi(histogram, diff),
+        }
+    }
+
+    fn normal_phi(&self, history: &HeartbeatHistory, diff: f64) -> f64 {
+        let mean = history.mean() + self.acceptable_heartbeat_pause;
         let std_dev = self.ensure_std_deviation();
 
         let y = (diff - mean) / std_dev;
@@ -189,26 +484,158 @@ impl PhiAccrualFailureDetector {
         -cdf.log10()
     }
 
+    /// The tail probability is smoothed so that it never reaches zero, and
+    /// decays exponentially past the largest observed interval so phi keeps
+    /// growing once the peer has been silent for longer than ever before.
+    fn histogram_phi(&self, histogram: &HistogramHistory, diff: f64) -> f64 {
+        let elapsed = diff - self.acceptable_heartbeat_pause;
+        let tail = (histogram.count_above(elapsed) + 1.0) / (histogram.len() as f64 + 1.0);
+        let beyond = (elapsed - histogram.upper_bound()).max(0.0);
+
+        -tail.log10() + beyond / (self.ensure_std_deviation() * LN_10)
+    }
+
     pub fn heartbeat(&mut self, timestamp: u128) {
         if self.latest > 0 {
             let interval = timestamp - self.latest;
             if self.is_available(timestamp) {
                 self.history.add(interval);
+                self.recorded += 1;
             }
         }
         self.latest = timestamp;
     }
 
     pub fn history(&self, num: usize) -> std::vec::Vec<u128> {
-        self.history.history(num)
+        self.history.window().history(num)
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn threshold(&self) -> f64 {
+        self.threshold
+    }
+
+    pub fn parameters(&self) -> Parameters {
+        let window = self.history.window();
+        Parameters {
+            threshold: self.threshold,
+            sample_size: self.sample_size,
+            min_std_deviation: self.min_std_deviation,
+            acceptable_heartbeat_pause: self.acceptable_heartbeat_pause,
+            first_heartbeat_estimate: self.first_heartbeat_estimate,
+            mode: self.mode,
+            mean: if window.intervals.len() > 1 {
+                window.mean()
+            } else {
+                0.0
+            },
+            std_deviation: self.ensure_std_deviation(),
+        }
     }
 
     pub fn last(&self) -> u128 {
         self.latest
     }
 
+    /// Number of intervals added to the history since the detector was built.
+    pub fn recorded(&self) -> u64 {
+        self.recorded
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            threshold: self.threshold,
+            sample_size: self.sample_size,
+            min_std_deviation: self.min_std_deviation,
+            acceptable_heartbeat_pause: self.acceptable_heartbeat_pause,
+            first_heartbeat_estimate: self.first_heartbeat_estimate,
+            mode: self.mode,
+            latest: self.latest,
+            intervals: self.history.window().intervals.iter().cloned().collect(),
+            recorded: self.recorded,
+        }
+    }
+
+    /// Rebuilds a detector from `snapshot`, keeping only the most recent
+    /// `sample_size` intervals, of which there must be at least two for the
+    /// mean and deviation.
+    pub fn restore(snapshot: Snapshot) -> Result<Self, SnapshotError> {
+        if snapshot.version > SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(snapshot.version));
+        }
+        if snapshot.sample_size == 0 {
+            return Err(SnapshotError::InvalidSampleSize);
+        }
+
+        let mut history = History::new(snapshot.mode, snapshot.sample_size);
+        let skip = snapshot
+            .intervals
+            .len()
+            .saturating_sub(snapshot.sample_size);
+        let kept = snapshot.intervals.len() - skip;
+        if kept < 2 {
+            return Err(SnapshotError::TooFewIntervals(kept));
+        }
+        for &interval in snapshot.intervals.iter().skip(skip) {
+            history.add(interval);
+        }
+
+        Ok(PhiAccrualFailureDetector {
+            threshold: snapshot.threshold,
+            sample_size: snapshot.sample_size,
+            min_std_deviation: snapshot.min_std_deviation,
+            acceptable_heartbeat_pause: snapshot.acceptable_heartbeat_pause,
+            first_heartbeat_estimate: snapshot.first_heartbeat_estimate,
+            mode: snapshot.mode,
+            latest: snapshot.latest,
+            recorded: snapshot.recorded,
+            history,
+        })
+    }
+
+    /// The same detector with `config` applied on top of `defaults`,
+    /// keeping the latest heartbeat and the intervals that still fit the
+    /// window.
+    pub fn reconfigure(
+        &self,
+        defaults: &PhiAccrualFailureDetectorBuilder,
+        config: &DetectorConfig,
+    ) -> Result<Self, SnapshotError> {
+        let mut snapshot = self.snapshot();
+        let base = defaults.0.snapshot();
+        snapshot.threshold = base.threshold;
+        snapshot.sample_size = base.sample_size;
+        snapshot.min_std_deviation = base.min_std_deviation;
+        snapshot.acceptable_heartbeat_pause = base.acceptable_heartbeat_pause;
+        snapshot.first_heartbeat_estimate = base.first_heartbeat_estimate;
+        snapshot.mode = base.mode;
+        config.apply(&mut snapshot);
+        PhiAccrualFailureDetector::restore(snapshot)
+    }
+
     fn ensure_std_deviation(&self) -> f64 {
-        self.history.std_deviation().max(self.min_std_deviation)
+        self.history
+            .window()
+            .std_deviation()
+            .max(self.min_std_deviation)
+    }
+}
+
+impl From<PhiAccrualFailureDetector> for Snapshot {
+    fn from(detector: PhiAccrualFailureDetector) -> Self {
+        detector.snapshot()
+    }
+}
+
+impl TryFrom<Snapshot> for PhiAccrualFailureDetector {
+    type Error = SnapshotError;
+
+    fn try_from(snapshot: Snapshot) -> Result<Self, Self::Error> {
+        PhiAccrualFailureDetector::restore(snapshot)
     }
 }
 
@@ -220,9 +647,35 @@ pub fn now() -> u128 {
     since_the_epoch.as_millis()
 }
 
+/// Where timestamps in milliseconds come from.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum Clock {
+    /// The system clock, as `now`.
+    #[default]
+    System,
+    /// `epoch` plus the time elapsed since `start` on the runtime's clock,
+    /// which simulations pause and advance.
+    Virtual {
+        start: tokio::time::Instant,
+        epoch: u128,
+    },
+}
+
+impl Clock {
+    pub fn now(&self) -> u128 {
+        match self {
+            Clock::System => now(),
+            Clock::Virtual { start, epoch } => epoch + start.elapsed().as_millis(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::PhiAccrualFailureDetectorBuilder;
+    use super::{
+        bucket_bounds, bucket_index, DetectorConfig, Mode, PhiAccrualFailureDetector,
+        PhiAccrualFailureDetectorBuilder, SnapshotError,
+    };
 
     #[test]
     fn should_fail_when_no_heartbeats() {
@@ -251,6 +704,26 @@ mod tests {
         }
     }
 
+    #[test]
+    fn alive_once_heartbeated() {
+        let mut detector = PhiAccrualFailureDetectorBuilder::new()
+            .with_min_std_deviation(1.0)
+            .build();
+        let now = super::now();
+
+        // a peer that never sent a heartbeat has no phi and is not alive
+        assert_eq!(detector.phi(now), 0.0);
+        assert!(!detector.is_available(now));
+
+        for t in 0..100 {
+            detector.heartbeat(now + t * 1000);
+        }
+        // one that just did is alive, even with a phi of zero
+        let tm = now + 99 * 1000;
+        assert_eq!(detector.phi(tm), 0.0);
+        assert!(detector.is_available(tm));
+    }
+
     #[test]
     fn should_recover() {
         let mut detector = PhiAccrualFailureDetectorBuilder::new().build();
@@ -308,13 +781,11 @@ mod tests {
                     assert!(!detector.is_available(timestamp));
                 }
                 continue;
-            } else if i > 200 {
-                if i % 5 == 0 {
-                    let phi = detector.phi(timestamp);
-                    assert!(0.1 < phi && phi < 0.5);
-                    assert!(detector.is_available(timestamp));
-                    continue;
-                }
+            } else if i > 200 && i % 5 == 0 {
+                let phi = detector.phi(timestamp);
+                assert!(0.1 < phi && phi < 0.5);
+                assert!(detector.is_available(timestamp));
+                continue;
             }
             detector.heartbeat(timestamp);
             assert!(detector.phi(timestamp) < 0.1);
@@ -336,4 +807,167 @@ mod tests {
         let hist = detector.history(5);
         assert_eq!(hist, actual);
     }
+
+    #[test]
+    fn histogram_buckets() {
+        for &value in &[0, 1, 7, 8, 15, 16, 17, 100, 999, 1000, 1024, 65_537] {
+            let (low, width) = bucket_bounds(bucket_index(value));
+            assert!(low as u128 <= value && value < (low + width) as u128);
+        }
+        assert!(bucket_index(1000) < bucket_index(1200));
+    }
+
+    #[test]
+    fn histogram_tolerates_skewed_intervals() {
+        let mut detector = PhiAccrualFailureDetectorBuilder::new()
+            .with_mode(Mode::Histogram)
+            .with_min_std_deviation(100.0)
+            .build();
+        let mut tm = super::now();
+
+        for i in 0..200 {
+            tm += if i % 10 == 0 { 1000 } else { 100 };
+            detector.heartbeat(tm);
+            assert!(detector.is_available(tm));
+        }
+
+        let mut last = 0.0;
+        for elapsed in (0..1000).step_by(50) {
+            let phi = detector.phi(tm + elapsed);
+            assert!(phi >= last);
+            last = phi;
+        }
+        assert!(detector.phi(tm + 900) < 2.0);
+        assert!(detector.is_available(tm + 900));
+        assert!(!detector.is_available(tm + 15_000));
+    }
+
+    #[test]
+    fn histogram_should_recover() {
+        let mut detector = PhiAccrualFailureDetectorBuilder::new()
+            .with_mode(Mode::Histogram)
+            .build();
+        let now = super::now();
+
+        for t in 0..100 {
+            detector.heartbeat(now + t * 1000);
+        }
+        assert!(!detector.is_available(now + 120 * 1000));
+
+        for t in 120..130 {
+            let tm = now + t * 1000;
+            detector.heartbeat(tm);
+            assert!(detector.is_available(tm));
+        }
+    }
+
+    #[test]
+    fn snapshot_roundtrip() {
+        for &mode in &[Mode::Normal, Mode::Histogram] {
+            let mut detector = PhiAccrualFailureDetectorBuilder::new()
+                .with_mode(mode)
+                .with_sample_size(50)
+                .build();
+            let now = super::now();
+            for t in 0..80 {
+                detector.heartbeat(now + t * 1000 + t % 7 * 10);
+            }
+
+            let json = serde_json::to_string(&detector).unwrap();
+            let restored: PhiAccrualFailureDetector = serde_json::from_str(&json).unwrap();
+
+            assert_eq!(restored.snapshot(), detector.snapshot());
+            assert_eq!(restored.mode(), mode);
+            for &elapsed in &[0, 500, 1500, 4000] {
+                let tm = detector.last() + elapsed;
+                assert_eq!(restored.phi(tm), detector.phi(tm));
+            }
+        }
+    }
+
+    #[test]
+    fn snapshot_restore_checks_version() {
+        let detector = PhiAccrualFailureDetectorBuilder::new().build();
+        let mut snapshot = detector.snapshot();
+        snapshot.version += 1;
+        let version = snapshot.version;
+
+        let err = PhiAccrualFailureDetector::restore(snapshot).unwrap_err();
+        assert_eq!(err, SnapshotError::UnsupportedVersion(version));
+    }
+
+    #[test]
+    fn snapshot_restore_trims_window() {
+        let mut snapshot = PhiAccrualFailureDetectorBuilder::new().build().snapshot();
+        snapshot.sample_size = 3;
+        snapshot.intervals = vec![10, 20, 30, 40, 50];
+
+        let detector = PhiAccrualFailureDetector::restore(snapshot).unwrap();
+        assert_eq!(detector.history(5), vec![50, 40, 30]);
+    }
+
+    #[test]
+    fn snapshot_restore_needs_two_intervals() {
+        let mut snapshot = PhiAccrualFailureDetectorBuilder::new().build().snapshot();
+        snapshot.intervals = vec![];
+        let err = PhiAccrualFailureDetector::restore(snapshot.clone()).unwrap_err();
+        assert_eq!(err, SnapshotError::TooFewIntervals(0));
+
+        snapshot.intervals = vec![1000];
+        let err = PhiAccrualFailureDetector::restore(snapshot.clone()).unwrap_err();
+        assert_eq!(err, SnapshotError::TooFewIntervals(1));
+
+        snapshot.intervals = vec![1000, 1000];
+        assert!(PhiAccrualFailureDetector::restore(snapshot).is_ok());
+    }
+
+    #[test]
+    fn reconfigure_keeps_history() {
+        let defaults = PhiAccrualFailureDetectorBuilder::new();
+        let mut detector = PhiAccrualFailureDetectorBuilder::new().build();
+        let mut now = 1_000_000;
+        for _ in 0..50 {
+            now += 1000;
+            detector.heartbeat(now);
+        }
+
+        let config = DetectorConfig {
+            threshold: Some(4.0),
+            sample_size: Some(10),
+            mode: Some(Mode::Histogram),
+            ..DetectorConfig::default()
+        };
+        let tuned = detector.reconfigure(&defaults, &config).unwrap();
+        let parameters = tuned.parameters();
+        assert_eq!(parameters.threshold, 4.0);
+        assert_eq!(parameters.mode, Mode::Histogram);
+        assert_eq!(parameters.min_std_deviation, 500.0);
+        assert_eq!(tuned.last(), now);
+        assert_eq!(tuned.history(100), vec![1000; 10]);
+
+        let reverted = tuned
+            .reconfigure(&defaults, &DetectorConfig::default())
+            .unwrap();
+        assert_eq!(reverted.parameters().threshold, 16.0);
+
+        let built = config.builder(&defaults).build();
+        assert_eq!(built.parameters().sample_size, 10);
+        assert!(DetectorConfig {
+            sample_size: Some(0),
+            ..DetectorConfig::default()
+        }
+        .validate()
+        .is_err());
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn validate_needs_two_samples() {
+        let sized = |sample_size| DetectorConfig {
+            sample_size: Some(sample_size),
+            ..DetectorConfig::default()
+        };
+        assert!(sized(1).validate().is_err());
+        assert!(sized(2).validate().is_ok());
+    }
 }
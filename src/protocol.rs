@@ -0,0 +1,645 @@
+//! JSON protocol spoken by dashboard clients over `/ws/`.
+//!
+//! Every frame, in both directions, is an envelope carrying the protocol
+//! version `v`, a `type` and the fields of that type. Replies to a command
+//! echo the `id` the client put in it.
+//!
+//! A session starts with a full `status` snapshot followed by `delta`s that
+//! only carry what changed. Both are numbered with `seq`, so a client that
+//! sees a gap sends `resync` to get a fresh snapshot.
+
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::{
+    messages::{PeerStatus, Span},
+    peer::Peer,
+    phi::{Parameters, State},
+};
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Smallest phi change worth sending in a delta.
+pub const DEFAULT_PHI_EPSILON: f64 = 0.05;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Request {
+    pub v: u32,
+    #[serde(default)]
+    pub id: Option<u64>,
+    #[serde(flatten)]
+    pub command: Command,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Command {
+    /// Only stream the given peers, or every peer when `peers` is missing.
+    Subscribe {
+        #[serde(default)]
+        peers: Option<std::vec::Vec<Uuid>>,
+    },
+    SetRate {
+        interval_ms: u64,
+    },
+    SetMode {
+        mode: StreamMode,
+        #[serde(default)]
+        epsilon: Option<f64>,
+    },
+    Snapshot,
+    Resync,
+    Register {
+        peer: Peer,
+    },
+    Unregister {
+        id: Uuid,
+    },
+}
+
+/// What a session pushes on every status tick. State transitions are always
+/// sent as they happen.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamMode {
+    Full,
+    Deltas,
+    Transitions,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Envelope {
+    pub v: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+impl Envelope {
+    pub fn new(id: Option<u64>, event: Event) -> Self {
+        Envelope {
+            v: PROTOCOL_VERSION,
+            id,
+            event,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// A full snapshot; `seq` is missing for one-off `snapshot` replies,
+    /// which are not part of the stream.
+    Status {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+        peers: std::vec::Vec<PeerStatus>,
+    },
+    Delta {
+        seq: u64,
+        peers: std::vec::Vec<PeerDelta>,
+        removed: std::vec::Vec<Uuid>,
+    },
+    Transition(Transition),
+    Ack,
+    Error {
+        message: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Health {
+    Alive,
+    Dead,
+}
+
+impl From<&State> for Health {
+    fn from(state: &State) -> Self {
+        match state {
+            State::Alive(_) => Health::Alive,
+            State::Dead(_) => Health::Dead,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Transition {
+    pub id: Uuid,
+    pub name: String,
+    pub from: Health,
+    pub to: Health,
+    pub phi: f64,
+    pub at: u128,
+}
+
+/// Round trip time changes smaller than this fraction are not sent.
+const RTT_TOLERANCE: f64 = 0.1;
+
+/// Changes of the mean interval or deviation of a detector's window smaller
+/// than this fraction are not sent.
+const STATISTICS_TOLERANCE: f64 = 0.01;
+
+/// Changes of a single peer since the last status sent to the client. Only
+/// the fields that changed are present; `intervals` are the intervals
+/// recorded since, newest first, to be prepended to the known history, and
+/// `timeline` the spans started since, to be appended. `age` is only sent
+/// for new peers, clients derive it from `last` afterwards. `detector` is
+/// only sent when its configuration changed, `mean` and `std_deviation`
+/// update the window statistics of the one known otherwise. `resolved` is
+/// sent as `null` when the address no longer resolves.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PeerDelta {
+    pub id: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resolved: Option<Option<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub phi: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<State>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last: Option<u128>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub intervals: std::vec::Vec<u128>,
+    pub recorded: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub age: Option<u128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rtt: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detector: Option<Parameters>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mean: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub std_deviation: Option<f64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub timeline: std::vec::Vec<Span>,
+}
+
+impl PeerDelta {
+    fn new(id: Uuid, recorded: u64) -> Self {
+        PeerDelta {
+            id,
+            name: None,
+            address: None,
+            resolved: None,
+            phi: None,
+            state: None,
+            last: None,
+            intervals: std::vec::Vec::new(),
+            recorded,
+            age: None,
+            rtt: None,
+            detector: None,
+            mean: None,
+            std_deviation: None,
+            timeline: std::vec::Vec::new(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.name.is_none()
+            && self.address.is_none()
+            && self.resolved.is_none()
+            && self.phi.is_none()
+            && self.state.is_none()
+            && self.last.is_none()
+            && self.intervals.is_empty()
+            && self.rtt.is_none()
+            && self.detector.is_none()
+            && self.mean.is_none()
+            && self.std_deviation.is_none()
+            && self.timeline.is_empty()
+    }
+}
+
+/// Turns successive status ticks into a numbered stream of one snapshot
+/// followed by deltas against what the client was last sent.
+#[derive(Debug)]
+pub struct DeltaEncoder {
+    epsilon: f64,
+    seq: u64,
+    synced: bool,
+    sent: HashMap<Uuid, PeerStatus>,
+}
+
+impl DeltaEncoder {
+    pub fn new(epsilon: f64) -> Self {
+        DeltaEncoder {
+            epsilon,
+            seq: 0,
+            synced: false,
+            sent: HashMap::new(),
+        }
+    }
+
+    pub fn set_epsilon(&mut self, epsilon: f64) {
+        self.epsilon = epsilon;
+    }
+
+    /// Makes the next `encode` send a full snapshot.
+    pub fn reset(&mut self) {
+        self.synced = false;
+        self.sent.clear();
+    }
+
+    pub fn snapshot(&mut self, peers: std::vec::Vec<PeerStatus>) -> Event {
+        self.seq += 1;
+        self.synced = true;
+        self.sent = peers.iter().map(|s| (s.id, s.clone())).collect();
+        Event::Status {
+            seq: Some(self.seq),
+            peers,
+        }
+    }
+
+    /// A snapshot if the client is not in sync, otherwise a delta, or
+    /// nothing when no peer changed.
+    pub fn encode(&mut self, peers: std::vec::Vec<PeerStatus>) -> Option<Event> {
+        if !self.synced {
+            return Some(self.snapshot(peers));
+        }
+
+        let mut changed = std::vec::Vec::new();
+        for status in &peers {
+            let delta = match self.sent.get_mut(&status.id) {
+                Some(sent) => delta(sent, status, self.epsilon),
+                None => {
+                    self.sent.insert(status.id, status.clone());
+                    PeerDelta {
+                        name: Some(status.name.clone()),
+                        address: Some(status.address.clone()),
+                        resolved: status.resolved.clone().map(Some),
+                        phi: Some(status.phi),
+                        state: Some(status.state.clone()),
+                        last: Some(status.last),
+                        intervals: status.history.clone(),
+                        age: Some(status.age),
+                        rtt: status.rtt,
+                        detector: Some(status.detector.clone()),
+                        timeline: status.timeline.clone(),
+                        ..PeerDelta::new(status.id, status.recorded)
+                    }
+                }
+            };
+            if !delta.is_empty() {
+                changed.push(delta);
+            }
+        }
+
+        let removed: std::vec::Vec<Uuid> = self
+            .sent
+            .keys()
+            .filter(|id| !peers.iter().any(|status| status.id == **id))
+            .cloned()
+            .collect();
+        for id in &removed {
+            self.sent.remove(id);
+        }
+
+        if changed.is_empty() && removed.is_empty() {
+            return None;
+        }
+        self.seq += 1;
+        Some(Event::Delta {
+            seq: self.seq,
+            peers: changed,
+            removed,
+        })
+    }
+}
+
+/// Compares `status` against what was last `sent` and records the fields
+/// included in the delta as sent.
+fn delta(sent: &mut PeerStatus, status: &PeerStatus, epsilon: f64) -> PeerDelta {
+    let mut delta = PeerDelta::new(status.id, status.recorded);
+
+    if sent.name != status.name {
+        sent.name = status.name.clone();
+        delta.name = Some(status.name.clone());
+    }
+    if sent.address != status.address {
+        sent.address = status.address.clone();
+        delta.address = Some(status.address.clone());
+    }
+    if sent.resolved != status.resolved {
+        sent.resolved = status.resolved.clone();
+        delta.resolved = Some(status.resolved.clone());
+    }
+    let moved = Health::from(&sent.state) != Health::from(&status.state);
+    if moved || (sent.phi - status.phi).abs() > epsilon {
+        sent.phi = status.phi;
+        delta.phi = Some(status.phi);
+    }
+    if moved {
+        sent.state = status.state.clone();
+        delta.state = Some(status.state.clone());
+    }
+    if sent.last != status.last {
+        sent.last = status.last;
+        delta.last = Some(status.last);
+    }
+    if sent.recorded != status.recorded {
+        let new = status.recorded.saturating_sub(sent.recorded) as usize;
+        delta.intervals = status.history.iter().take(new).cloned().collect();
+        sent.recorded = status.recorded;
+        sent.history = status.history.clone();
+    }
+    let rtt_moved = match (sent.rtt, status.rtt) {
+        (Some(before), Some(now)) => (now - before).abs() > before * RTT_TOLERANCE,
+        (before, now) => before.is_none() != now.is_none(),
+    };
+    if rtt_moved {
+        sent.rtt = status.rtt;
+        delta.rtt = status.rtt;
+    }
+    let (before, now) = (&mut sent.detector, &status.detector);
+    if reconfigured(before, now) {
+        *before = now.clone();
+        delta.detector = Some(now.clone());
+    } else {
+        if (now.mean - before.mean).abs() > before.mean * STATISTICS_TOLERANCE {
+            before.mean = now.mean;
+            delta.mean = Some(now.mean);
+        }
+        if (now.std_deviation - before.std_deviation).abs()
+            > before.std_deviation * STATISTICS_TOLERANCE
+        {
+            before.std_deviation = now.std_deviation;
+            delta.std_deviation = Some(now.std_deviation);
+        }
+    }
+    if sent.timeline != status.timeline {
+        let known = sent.timeline.last().map_or(0, |span| span.since);
+        delta.timeline = status
+            .timeline
+            .iter()
+            .filter(|span| span.since > known)
+            .cloned()
+            .collect();
+        sent.timeline = status.timeline.clone();
+    }
+    delta
+}
+
+/// Whether the configuration of a detector changed, leaving aside the
+/// statistics of its window that change with every heartbeat.
+fn reconfigured(before: &Parameters, now: &Parameters) -> bool {
+    before.threshold != now.threshold
+        || before.sample_size != now.sample_size
+        || before.min_std_deviation != now.min_std_deviation
+        || before.acceptable_heartbeat_pause != now.acceptable_heartbeat_pause
+        || before.first_heartbeat_estimate != now.first_heartbeat_estimate
+        || before.mode != now.mode
+}
+
+/// Alive/dead changes of peers already present in `previous`.
+pub fn transitions(
+    previous: &HashMap<Uuid, PeerStatus>,
+    current: &[PeerStatus],
+    at: u128,
+) -> std::vec::Vec<Transition> {
+    current
+        .iter()
+        .filter_map(|status| {
+            let from = Health::from(&previous.get(&status.id)?.state);
+            let to = Health::from(&status.state);
+            if from == to {
+                return None;
+            }
+            Some(Transition {
+                id: status.id,
+                name: status.name.clone(),
+                from,
+                to,
+                phi: status.phi,
+                at,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{transitions, Command, DeltaEncoder, Envelope, Event, Health, Request, StreamMode};
+    use crate::{
+        messages::{Level, PeerStatus, Span},
+        phi::{PhiAccrualFailureDetectorBuilder, State},
+    };
+    use std::collections::HashMap;
+    use uuid::Uuid;
+
+    fn status(id: Uuid, state: State, last: u128) -> PeerStatus {
+        status_with(id, state, last, vec![], 0)
+    }
+
+    fn status_with(
+        id: Uuid,
+        state: State,
+        last: u128,
+        history: Vec<u128>,
+        recorded: u64,
+    ) -> PeerStatus {
+        let phi = match state {
+            State::Alive(p) | State::Dead(p) => p,
+        };
+        PeerStatus {
+            id,
+            name: "peer".to_owned(),
+            address: "peer:9000".to_owned(),
+            resolved: Some("10.0.0.7:9000".to_owned()),
+            history,
+            phi,
+            state,
+            last,
+            recorded,
+            age: 0,
+            rtt: None,
+            detector: PhiAccrualFailureDetectorBuilder::new().build().parameters(),
+            timeline: vec![],
+        }
+    }
+
+    #[test]
+    fn parse_commands() {
+        let req: Request =
+            serde_json::from_str(r#"{"v":1,"id":7,"type":"set_mode","mode":"deltas"}"#).unwrap();
+        assert_eq!(req.id, Some(7));
+        assert_eq!(
+            req.command,
+            Command::SetMode {
+                mode: StreamMode::Deltas,
+                epsilon: None,
+            }
+        );
+
+        let req: Request = serde_json::from_str(r#"{"v":1,"type":"subscribe"}"#).unwrap();
+        assert_eq!(req.command, Command::Subscribe { peers: None });
+        assert!(serde_json::from_str::<Request>(r#"{"v":1,"type":"reboot"}"#).is_err());
+    }
+
+    #[test]
+    fn envelope_is_flat() {
+        let json = serde_json::to_value(Envelope::new(Some(3), Event::Ack)).unwrap();
+        assert_eq!(json, serde_json::json!({"v": 1, "id": 3, "type": "ack"}));
+    }
+
+    #[test]
+    fn transitions_between_ticks() {
+        let (a, b, c) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let previous: HashMap<_, _> = vec![
+            status(a, State::Alive(0.1), 100),
+            status(b, State::Alive(0.2), 100),
+        ]
+        .into_iter()
+        .map(|s| (s.id, s))
+        .collect();
+        let current = vec![
+            status(a, State::Alive(0.1), 100),
+            status(b, State::Dead(20.0), 100),
+            status(c, State::Dead(0.0), 0),
+        ];
+
+        let moved = transitions(&previous, &current, 500);
+        assert_eq!(moved.len(), 1);
+        assert_eq!(
+            (moved[0].id, moved[0].from, moved[0].to),
+            (b, Health::Alive, Health::Dead)
+        );
+    }
+
+    #[test]
+    fn snapshot_then_deltas() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut encoder = DeltaEncoder::new(0.05);
+
+        let first = vec![
+            status_with(a, State::Alive(0.10), 100, vec![150, 150], 2),
+            status_with(b, State::Alive(0.20), 100, vec![150], 1),
+        ];
+        match encoder.encode(first.clone()) {
+            Some(Event::Status { seq, peers }) => {
+                assert_eq!(seq, Some(1));
+                assert_eq!(peers.len(), 2);
+            }
+            other => panic!("expected a snapshot, got {:?}", other),
+        }
+        assert!(encoder.encode(first).is_none());
+
+        let second = vec![
+            status_with(a, State::Alive(0.12), 100, vec![150, 150], 2),
+            status_with(b, State::Dead(18.0), 400, vec![160, 155, 150], 3),
+        ];
+        match encoder.encode(second) {
+            Some(Event::Delta {
+                seq,
+                peers,
+                removed,
+            }) => {
+                assert_eq!(seq, 2);
+                assert!(removed.is_empty());
+                assert_eq!(peers.len(), 1);
+                assert_eq!(peers[0].id, b);
+                assert_eq!(peers[0].phi, Some(18.0));
+                assert_eq!(peers[0].last, Some(400));
+                assert_eq!(peers[0].intervals, vec![160, 155]);
+                assert!(peers[0].name.is_none());
+                assert!(peers[0].resolved.is_none());
+            }
+            other => panic!("expected a delta, got {:?}", other),
+        }
+
+        let mut third = vec![status_with(a, State::Alive(0.16), 100, vec![150, 150], 2)];
+        third[0].rtt = Some(2.0);
+        third[0].resolved = Some("10.0.0.9:9000".to_owned());
+        third[0].timeline = vec![Span {
+            level: Level::Suspect,
+            since: 90,
+        }];
+        match encoder.encode(third) {
+            Some(Event::Delta {
+                seq,
+                peers,
+                removed,
+            }) => {
+                assert_eq!(seq, 3);
+                assert_eq!(peers[0].phi, Some(0.16));
+                assert_eq!(peers[0].rtt, Some(2.0));
+                assert_eq!(
+                    peers[0].resolved,
+                    Some(Some("10.0.0.9:9000".to_owned()))
+                );
+                assert_eq!(peers[0].timeline.len(), 1);
+                assert_eq!(removed, vec![b]);
+            }
+            other => panic!("expected a delta, got {:?}", other),
+        }
+
+        encoder.reset();
+        assert!(matches!(
+            encoder.encode(vec![]),
+            Some(Event::Status { seq: Some(4), .. })
+        ));
+    }
+
+    #[test]
+    fn detector_statistics_within_tolerance() {
+        let a = Uuid::new_v4();
+        let mut encoder = DeltaEncoder::new(0.05);
+        let first = status(a, State::Alive(0.1), 100);
+        encoder.encode(vec![first.clone()]);
+
+        // a window mean that barely moved is not sent at all
+        let mut second = first.clone();
+        second.detector.mean += first.detector.mean * 0.001;
+        assert!(encoder.encode(vec![second.clone()]).is_none());
+
+        // one that moved is sent on its own, the configuration is not
+        let mut third = second.clone();
+        third.detector.mean *= 2.0;
+        match encoder.encode(vec![third.clone()]) {
+            Some(Event::Delta { peers, .. }) => {
+                assert_eq!(peers[0].mean, Some(third.detector.mean));
+                assert!(peers[0].std_deviation.is_none());
+                assert!(peers[0].detector.is_none());
+            }
+            other => panic!("expected a delta, got {:?}", other),
+        }
+
+        let mut fourth = third;
+        fourth.detector.threshold = 8.0;
+        match encoder.encode(vec![fourth.clone()]) {
+            Some(Event::Delta { peers, .. }) => {
+                assert_eq!(peers[0].detector, Some(fourth.detector));
+                assert!(peers[0].mean.is_none());
+            }
+            other => panic!("expected a delta, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn cleared_address_is_sent() {
+        let a = Uuid::new_v4();
+        let mut encoder = DeltaEncoder::new(0.05);
+        let first = status(a, State::Alive(0.1), 100);
+        encoder.encode(vec![first.clone()]);
+
+        let mut second = first;
+        second.resolved = None;
+        match encoder.encode(vec![second]) {
+            Some(Event::Delta { peers, .. }) => {
+                assert_eq!(peers[0].resolved, Some(None));
+                let json = serde_json::to_value(&peers[0]).unwrap();
+                assert_eq!(json["resolved"], serde_json::Value::Null);
+                assert!(json.as_object().unwrap().contains_key("resolved"));
+            }
+            other => panic!("expected a delta, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//! Reloading the configuration while running, on SIGHUP or through the
+//! admin API. Detector defaults, heartbeat intervals, the log filter and the
+//! configured peers are applied to the running actors; peers keep their
+//! heartbeat histories. Other sections are only read at startup.
+
+use crate::{
+    actor::{
+        heartbeat::{Intervals, StaticPeers, UdpActor},
+        inventory::{DetectorDefaults, InventoryActor},
+    },
+    config::{Config, Flags},
+    logging::Logger,
+};
+use actix::prelude::*;
+use log::{info, warn};
+use serde_derive::Serialize;
+use std::env;
+
+/// What a reload changed, and what it could not.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Outcome {
+    /// Settings applied to the running node.
+    pub applied: std::vec::Vec<&'static str>,
+    /// Changed sections that take effect on the next start.
+    pub restart_required: std::vec::Vec<&'static str>,
+}
+
+/// Reads the configuration again; an invalid one is reported and ignored.
+#[derive(Message)]
+#[rtype(result = "Result<Outcome, std::vec::Vec<String>>")]
+pub struct Reload;
+
+pub struct Reloader {
+    flags: Flags,
+    /// The configuration the node is running with.
+    config: Config,
+    heartbeat: Addr<UdpActor>,
+    inventory: Addr<InventoryActor>,
+    logger: &'static Logger,
+}
+
+impl Reloader {
+    pub fn new(
+        flags: Flags,
+        config: Config,
+        heartbeat: Addr<UdpActor>,
+        inventory: Addr<InventoryActor>,
+        logger: &'static Logger,
+    ) -> Self {
+        Reloader {
+            flags,
+            config,
+            heartbeat,
+            inventory,
+            logger,
+        }
+    }
+
+    fn apply(&mut self, new: Config) -> Outcome {
+        let mut outcome = Outcome {
+            restart_required: new.startup_only_changes(&self.config),
+            ..Outcome::default()
+        };
+        if new.detector != self.config.detector {
+            self.inventory.do_send(DetectorDefaults(new.detector()));
+            outcome.applied.push("detector");
+        }
+        if new.heartbeat != self.config.heartbeat {
+            // validated by `Config::resolve`
+            if let (Ok(ping), Ok(resolve)) = (new.ping_interval(), new.resolve_interval()) {
+                self.heartbeat.do_send(Intervals { ping, resolve });
+                outcome.applied.push("heartbeat");
+            }
+        }
+        if new.log != self.config.log {
+            self.logger.set_filter(&new.log.level);
+            outcome.applied.push("log.level");
+        }
+        // after the detector defaults, so re-registered peers get the new ones
+        if new.peers != self.config.peers {
+            self.heartbeat.do_send(StaticPeers(new.peers.clone()));
+            outcome.applied.push("peers");
+        }
+        self.config.detector = new.detector;
+        self.config.heartbeat = new.heartbeat;
+        self.config.log = new.log;
+        self.config.peers = new.peers;
+        outcome
+    }
+}
+
+impl Actor for Reloader {
+    type Context = Context<Self>;
+}
+
+impl Handler<Reload> for Reloader {
+    type Result = Result<Outcome, std::vec::Vec<String>>;
+
+    fn handle(&mut self, _msg: Reload, _ctx: &mut Context<Self>) -> Self::Result {
+        let resolved = Config::resolve(&self.flags, |name| env::var(name).ok())
+            .and_then(|mut config| config.identify().map(|_| config).map_err(|e| vec![e]));
+        let new = match resolved {
+            Ok(config) => config,
+            Err(errors) => {
+                warn!(
+                    "Not reloading invalid configuration:\n  {}",
+                    errors.join("\n  ")
+                );
+                return Err(errors);
+            }
+        };
+        let outcome = self.apply(new);
+        info!(
+            "Reloaded configuration, applied {:?}, restart required for {:?}",
+            outcome.applied, outcome.restart_required
+        );
+        Ok(outcome)
+    }
+}
+
+/// Reloads the configuration whenever the process receives SIGHUP.
+#[cfg(unix)]
+pub fn on_hangup(reloader: Addr<Reloader>) -> std::io::Result<()> {
+    use actix_rt::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup())?;
+    actix_rt::spawn(async move {
+        while hangups.recv().await.is_some() {
+            info!("Received SIGHUP, reloading configuration");
+            // failures are logged by the reloader
+            let _ = reloader.send(Reload).await;
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn on_hangup(_reloader: Addr<Reloader>) -> std::io::Result<()> {
+    Ok(())
+}
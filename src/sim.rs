@@ -0,0 +1,483 @@
+//! Deterministic, in-process network for end-to-end failure detection tests.
+//!
+//! Every node runs the real `UdpActor`, `HeartBeatActor`s and
+//! `InventoryActor`, sending heartbeats through a simulated `Transport`
+//! instead of a UDP socket. The actix system runs with tokio's clock paused:
+//! time only moves while the simulation runs, jumping straight to the next
+//! timer when every actor is idle, so a whole cluster can be exercised for
+//! minutes of virtual time in well under a second.
+
+use actix::prelude::*;
+use actix_rt::SystemRunner;
+use bytes::BytesMut;
+use futures::channel::mpsc;
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    f64::consts::PI,
+    net::{Ipv4Addr, SocketAddr},
+    future::Future,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::time::{self, Instant};
+use tokio_util::codec::{Decoder, Encoder};
+use uuid::Uuid;
+
+use crate::{
+    actor::{
+        heartbeat::{Transport, UdpActor, UdpPacket, PING_INTERVAL},
+        inventory::InventoryActor,
+    },
+    messages::{GetStatus, HBCodec, HeartBeat, Monitor},
+    net::{Family, Resolver, Stack},
+    peer::Peer,
+    phi::{Clock, PhiAccrualFailureDetectorBuilder, State},
+    timeseries::{self, TimeSeries},
+};
+
+/// Virtual time at which every simulation starts, in milliseconds.
+const EPOCH: u128 = 1_000_000;
+/// Phi history kept by the nodes, short as nothing reads it.
+const TIERS: &str = "1s:1m";
+/// Port every node listens on, each having an address of its own.
+const PORT: u16 = 9000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Latency {
+    Fixed(u128),
+    Uniform(u128, u128),
+    Normal { mean: f64, std_dev: f64 },
+}
+
+/// Behaviour of packets sent from one node to another.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Link {
+    pub latency: Latency,
+    pub loss: f64,
+}
+
+impl Default for Link {
+    fn default() -> Self {
+        Link {
+            latency: Latency::Fixed(1),
+            loss: 0.0,
+        }
+    }
+}
+
+/// xorshift64*, so runs only depend on the seed.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        Rng(seed.max(1))
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Uniform in `[0, 1)`.
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn latency(&mut self, latency: Latency) -> u128 {
+        match latency {
+            Latency::Fixed(ms) => ms,
+            Latency::Uniform(low, high) => {
+                low + (self.next_f64() * (high.saturating_sub(low) + 1) as f64) as u128
+            }
+            Latency::Normal { mean, std_dev } => {
+                let (u1, u2) = (1.0 - self.next_f64(), self.next_f64());
+                let z = (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos();
+                (mean + z * std_dev).max(0.0).round() as u128
+            }
+        }
+    }
+}
+
+
+/// The wire between the nodes, shared by their transports.
+struct Network {
+    rng: Rng,
+    link: Link,
+    links: BTreeMap<(Uuid, Uuid), Link>,
+    partitions: BTreeSet<(Uuid, Uuid)>,
+    nodes: BTreeMap<SocketAddr, Uuid>,
+    /// Where each node receives its packets.
+    inboxes: BTreeMap<Uuid, mpsc::UnboundedSender<UdpPacket>>,
+    paused_until: BTreeMap<Uuid, Instant>,
+}
+
+impl Network {
+    /// Until when `id` is paused, if it is.
+    fn paused(&self, id: Uuid) -> Option<Instant> {
+        self.paused_until
+            .get(&id)
+            .cloned()
+            .filter(|&until| until > Instant::now())
+    }
+
+    /// How long a packet from `from` to `to` travels, `None` when it is lost.
+    fn route(&mut self, from: Uuid, to: Uuid) -> Option<Duration> {
+        if self.partitions.contains(&(from, to)) {
+            return None;
+        }
+        let link = *self.links.get(&(from, to)).unwrap_or(&self.link);
+        if self.rng.next_f64() < link.loss {
+            return None;
+        }
+        Some(Duration::from_millis(self.rng.latency(link.latency) as u64))
+    }
+}
+
+/// A node's end of the network.
+struct SimTransport {
+    me: Uuid,
+    address: SocketAddr,
+    network: Rc<RefCell<Network>>,
+}
+
+impl Transport for SimTransport {
+    /// Packets are encoded like on the wire so the simulation also covers
+    /// the codec. A paused node sends nothing, and packets sent to it are
+    /// handled once it resumes.
+    fn send(&mut self, packet: HeartBeat, to: SocketAddr) {
+        let mut network = self.network.borrow_mut();
+        if network.paused(self.me).is_some() {
+            return;
+        }
+        let to = match network.nodes.get(&to) {
+            Some(&id) => id,
+            None => return,
+        };
+        let delay = match network.route(self.me, to) {
+            Some(delay) => delay,
+            None => return,
+        };
+        let mut wire = BytesMut::new();
+        HBCodec.encode(packet, &mut wire).unwrap();
+        let (network, from) = (self.network.clone(), self.address);
+        actix_rt::spawn(async move {
+            time::delay_for(delay).await;
+            loop {
+                let paused = network.borrow().paused(to);
+                match paused {
+                    Some(until) => time::delay_until(until).await,
+                    None => break,
+                }
+            }
+            let packet = HBCodec.decode(&mut wire).unwrap().unwrap();
+            if let Some(inbox) = network.borrow().inboxes.get(&to) {
+                let _ = inbox.unbounded_send(UdpPacket(packet, from));
+            }
+        });
+    }
+}
+
+struct Node {
+    address: SocketAddr,
+    inventory: Addr<InventoryActor>,
+    heartbeat: Addr<UdpActor>,
+}
+
+pub struct Simulation {
+    system: SystemRunner,
+    /// When the simulation started on the paused runtime clock.
+    start: Instant,
+    elapsed: Duration,
+    detector: PhiAccrualFailureDetectorBuilder,
+    network: Rc<RefCell<Network>>,
+    nodes: BTreeMap<Uuid, Node>,
+}
+
+impl Simulation {
+    pub fn new(seed: u64) -> Simulation {
+        Simulation::with_detector(seed, PhiAccrualFailureDetectorBuilder::new())
+    }
+
+    pub fn with_detector(seed: u64, detector: PhiAccrualFailureDetectorBuilder) -> Simulation {
+        let mut system = System::new("sim");
+        let start = system.block_on(async {
+            time::pause();
+            Instant::now()
+        });
+        let network = Network {
+            rng: Rng::new(seed),
+            link: Link::default(),
+            links: BTreeMap::new(),
+            partitions: BTreeSet::new(),
+            nodes: BTreeMap::new(),
+            inboxes: BTreeMap::new(),
+            paused_until: BTreeMap::new(),
+        };
+        Simulation {
+            system,
+            start,
+            elapsed: Duration::default(),
+            detector,
+            network: Rc::new(RefCell::new(network)),
+            nodes: BTreeMap::new(),
+        }
+    }
+
+    /// Virtual time elapsed since the simulation started.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Adds a node that monitors, and is monitored by, every existing node.
+    pub fn add_node(&mut self) -> Uuid {
+        let n = self.nodes.len();
+        let address = SocketAddr::from((Ipv4Addr::from(0x0a00_0001 + n as u32), PORT));
+        let (inbox, packets) = mpsc::unbounded();
+        let id = {
+            let mut network = self.network.borrow_mut();
+            let id = Uuid::from_u128(network.rng.next_u64() as u128 | (n as u128) << 64);
+            network.nodes.insert(address, id);
+            network.inboxes.insert(id, inbox);
+            id
+        };
+
+        let transport = SimTransport {
+            me: id,
+            address,
+            network: self.network.clone(),
+        };
+        let clock = Clock::Virtual {
+            start: self.start,
+            epoch: EPOCH,
+        };
+        let detector = self.detector.clone();
+        let tiers = timeseries::parse_tiers(TIERS).unwrap();
+        let (inventory, heartbeat) = self.system.block_on(async move {
+            let inventory = InventoryActor::new(id, detector, TimeSeries::new(tiers))
+                .with_clock(clock)
+                .start();
+            let heartbeat = UdpActor::create(|ctx| {
+                ctx.add_stream(packets);
+                UdpActor {
+                    me: id,
+                    transport: Box::new(transport),
+                    inventory: inventory.clone(),
+                    monitored: HashMap::new(),
+                    trace: None,
+                    ping_interval: PING_INTERVAL,
+                    resolve_interval: Duration::from_secs(30),
+                    resolver: Resolver::new(Family::Any, Stack::V4),
+                    static_peers: HashMap::new(),
+                    discovered: HashMap::new(),
+                    added: HashSet::new(),
+                    clock,
+                }
+            });
+            (inventory, heartbeat)
+        });
+
+        let peer = |id: Uuid, address: SocketAddr| {
+            Monitor::Register(Peer::new(id, id.to_string(), address.to_string()))
+        };
+        for (&other, node) in &self.nodes {
+            heartbeat.do_send(peer(other, node.address));
+            node.heartbeat.do_send(peer(id, address));
+        }
+        self.nodes.insert(
+            id,
+            Node {
+                address,
+                inventory,
+                heartbeat,
+            },
+        );
+        id
+    }
+
+    /// Sets the link used between nodes without a specific one.
+    pub fn set_default_link(&mut self, link: Link) {
+        self.network.borrow_mut().link = link;
+    }
+
+    /// Sets the link for packets travelling from `from` to `to`.
+    pub fn set_link(&mut self, from: Uuid, to: Uuid, link: Link) {
+        self.network.borrow_mut().links.insert((from, to), link);
+    }
+
+    /// Drops every packet between the two groups, in both directions.
+    pub fn partition(&mut self, left: &[Uuid], right: &[Uuid]) {
+        let mut network = self.network.borrow_mut();
+        for &a in left {
+            for &b in right {
+                network.partitions.insert((a, b));
+                network.partitions.insert((b, a));
+            }
+        }
+    }
+
+    pub fn heal(&mut self) {
+        self.network.borrow_mut().partitions.clear();
+    }
+
+    /// Freezes `id` for `duration`: it neither pings nor answers, and
+    /// packets sent to it are handled once it resumes.
+    pub fn pause(&mut self, id: Uuid, duration: Duration) {
+        let until = self.start + self.elapsed + duration;
+        self.network.borrow_mut().paused_until.insert(id, until);
+    }
+
+    /// How `observer` currently sees `peer`, as its inventory reports it.
+    pub fn state(&mut self, observer: Uuid, peer: Uuid) -> Option<State> {
+        let inventory = self.nodes.get(&observer)?.inventory.clone();
+        let status = self.settle(inventory.send(GetStatus)).ok()?;
+        status
+            .0
+            .into_iter()
+            .find(|status| status.id == peer)
+            .map(|status| status.state)
+    }
+
+    pub fn is_alive(&mut self, observer: Uuid, peer: Uuid) -> bool {
+        matches!(self.state(observer, peer), Some(State::Alive(_)))
+    }
+
+    /// Runs `future` to completion without moving the clock. Tokio 0.2
+    /// advances a paused clock to the next timer whenever the scheduler
+    /// parks, as it would while an actor answers, so a task keeps it busy
+    /// meanwhile.
+    fn settle<F: Future + 'static>(&mut self, future: F) -> F::Output {
+        let done = Arc::new(AtomicBool::new(false));
+        let spinning = done.clone();
+        self.system.block_on(async move {
+            tokio::spawn(async move {
+                while !spinning.load(Ordering::Relaxed) {
+                    let () = tokio::task::yield_now().await;
+                }
+            });
+            let output = future.await;
+            done.store(true, Ordering::Relaxed);
+            output
+        })
+    }
+
+    /// Runs every node for `duration` of virtual time.
+    pub fn run_for(&mut self, duration: Duration) {
+        // the timer must be created on the runtime
+        self.system
+            .block_on(async move { time::delay_for(duration).await });
+        self.elapsed += duration;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Latency, Link, Simulation};
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    fn cluster(seed: u64, size: usize) -> (Simulation, std::vec::Vec<Uuid>) {
+        let mut sim = Simulation::new(seed);
+        sim.set_default_link(Link {
+            latency: Latency::Normal {
+                mean: 5.0,
+                std_dev: 2.0,
+            },
+            loss: 0.01,
+        });
+        let nodes = (0..size).map(|_| sim.add_node()).collect();
+        sim.run_for(Duration::from_secs(30));
+        (sim, nodes)
+    }
+
+    fn all_alive(sim: &mut Simulation, observers: &[Uuid], peers: &[Uuid]) -> bool {
+        observers.iter().all(|&a| {
+            peers
+                .iter()
+                .filter(|&&b| b != a)
+                .all(|&b| sim.is_alive(a, b))
+        })
+    }
+
+    #[test]
+    fn healthy_cluster_stays_alive() {
+        let (mut sim, nodes) = cluster(7, 5);
+        for _ in 0..60 {
+            sim.run_for(Duration::from_secs(1));
+            assert!(all_alive(&mut sim, &nodes, &nodes));
+        }
+        assert_eq!(sim.elapsed(), Duration::from_secs(90));
+    }
+
+    #[test]
+    fn partition_is_detected_and_heals() {
+        let (mut sim, nodes) = cluster(11, 4);
+        let (left, right) = nodes.split_at(2);
+
+        sim.partition(left, right);
+        sim.run_for(Duration::from_secs(5));
+        assert!(all_alive(&mut sim, left, left));
+        assert!(all_alive(&mut sim, right, right));
+        for &a in left {
+            for &b in right {
+                assert!(!sim.is_alive(a, b));
+                assert!(!sim.is_alive(b, a));
+            }
+        }
+
+        sim.heal();
+        sim.run_for(Duration::from_secs(1));
+        assert!(all_alive(&mut sim, &nodes, &nodes));
+    }
+
+    #[test]
+    fn paused_node_is_suspected_then_recovers() {
+        let (mut sim, nodes) = cluster(3, 3);
+        let paused = nodes[0];
+
+        sim.pause(paused, Duration::from_secs(10));
+        sim.run_for(Duration::from_secs(5));
+        assert!(!sim.is_alive(nodes[1], paused));
+        assert!(!sim.is_alive(nodes[2], paused));
+        assert!(sim.is_alive(nodes[1], nodes[2]));
+
+        sim.run_for(Duration::from_secs(6));
+        assert!(all_alive(&mut sim, &nodes, &nodes));
+    }
+
+    #[test]
+    fn one_way_loss_breaks_the_link_both_ways() {
+        let (mut sim, nodes) = cluster(5, 3);
+        sim.set_link(
+            nodes[1],
+            nodes[0],
+            Link {
+                latency: Latency::Uniform(1, 10),
+                loss: 1.0,
+            },
+        );
+        sim.run_for(Duration::from_secs(5));
+
+        assert!(!sim.is_alive(nodes[0], nodes[1]));
+        assert!(!sim.is_alive(nodes[1], nodes[0]));
+        assert!(sim.is_alive(nodes[0], nodes[2]));
+        assert!(sim.is_alive(nodes[2], nodes[1]));
+    }
+
+    #[test]
+    fn same_seed_same_run() {
+        let phis = |seed| {
+            let (mut sim, nodes) = cluster(seed, 3);
+            sim.pause(nodes[2], Duration::from_secs(2));
+            sim.run_for(Duration::from_millis(1500));
+            format!("{:?}", sim.state(nodes[0], nodes[2]))
+        };
+        assert_eq!(phis(42), phis(42));
+    }
+}
@@ -0,0 +1,97 @@
+//! Server-Sent Events framing and the replay buffer behind `Last-Event-ID`.
+
+use bytes::Bytes;
+use std::collections::VecDeque;
+
+/// Formats one event; `data` must not contain newlines, which holds for
+/// compact JSON.
+pub fn format(id: u64, event: &str, data: &str) -> Bytes {
+    Bytes::from(format!("id: {}\nevent: {}\ndata: {}\n\n", id, event, data))
+}
+
+/// The most recent events, numbered from 1, so reconnecting clients can
+/// resume where they left off.
+#[derive(Debug)]
+pub struct EventLog {
+    capacity: usize,
+    next_id: u64,
+    events: VecDeque<(u64, Bytes)>,
+}
+
+impl EventLog {
+    pub fn new(capacity: usize) -> Self {
+        EventLog {
+            capacity,
+            next_id: 1,
+            events: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn push(&mut self, event: &str, data: &str) -> Bytes {
+        let frame = format(self.next_id, event, data);
+        self.events.push_back((self.next_id, frame.clone()));
+        if self.events.len() > self.capacity {
+            self.events.pop_front();
+        }
+        self.next_id += 1;
+        frame
+    }
+
+    /// Drops the buffered events and skips an id, so no client can resume
+    /// across the gap.
+    pub fn clear(&mut self) {
+        self.events.clear();
+        self.next_id += 1;
+    }
+
+    /// Events after `last_id`, or `None` when some of them were already
+    /// evicted, or the id was never issued, and the client has to start over.
+    pub fn since(&self, last_id: u64) -> Option<std::vec::Vec<Bytes>> {
+        let oldest = self.events.front().map_or(self.next_id, |(id, _)| *id);
+        let next = last_id.checked_add(1)?;
+        if next < oldest || last_id >= self.next_id {
+            return None;
+        }
+        Some(
+            self.events
+                .iter()
+                .filter(|(id, _)| *id > last_id)
+                .map(|(_, frame)| frame.clone())
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EventLog;
+
+    #[test]
+    fn frames_are_numbered() {
+        let mut log = EventLog::new(4);
+        let frame = log.push("status", "[]");
+        assert_eq!(&frame[..], &b"id: 1\nevent: status\ndata: []\n\n"[..]);
+    }
+
+    #[test]
+    fn resume_from_last_event_id() {
+        let mut log = EventLog::new(3);
+        for i in 0..5 {
+            log.push("status", &i.to_string());
+        }
+
+        let replay = log.since(3).unwrap();
+        assert_eq!(replay.len(), 2);
+        assert!(replay[0].starts_with(b"id: 4\n"));
+        assert_eq!(log.since(5).unwrap().len(), 0);
+        assert!(log.since(1).is_none());
+        assert!(log.since(9).is_none());
+        assert!(log.since(u64::MAX).is_none());
+
+        log.clear();
+        assert!(log.since(5).is_none());
+        log.push("status", "[]");
+        assert!(log.since(5).is_none());
+        assert_eq!(log.since(7).unwrap().len(), 0);
+    }
+}
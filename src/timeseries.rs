@@ -0,0 +1,349 @@
+//! In-memory history of phi and health level per peer, kept in tiers of
+//! decreasing resolution so recent history is detailed and older history
+//! cheap.
+
+use serde_derive::Serialize;
+use std::collections::{HashMap, VecDeque};
+use uuid::Uuid;
+
+use crate::messages::Level;
+
+/// Samples every second for an hour, then 10s for a day and a minute for a
+/// week.
+pub const DEFAULT_TIERS: &str = "1s:1h,10s:1d,1m:7d";
+/// Finest step, as every peer is sampled at the first tier's step.
+pub const MIN_STEP: u128 = 100;
+/// Most points a tier keeps per peer, at 48 bytes each.
+pub const MAX_POINTS: u128 = 100_000;
+
+/// Resolution and retention of one tier, in milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tier {
+    pub step: u128,
+    pub retention: u128,
+}
+
+impl Tier {
+    fn capacity(&self) -> usize {
+        (self.retention / self.step) as usize
+    }
+}
+
+/// Parses tiers written as `step:retention` pairs separated by commas, such
+/// as `1s:1h,1m:7d`. The first tier's step is the sampling resolution, at
+/// least `MIN_STEP`; each following tier must be coarser, a multiple of the
+/// previous step, and retain more. No tier keeps more than `MAX_POINTS`.
+pub fn parse_tiers(spec: &str) -> Result<std::vec::Vec<Tier>, String> {
+    let mut tiers: std::vec::Vec<Tier> = std::vec::Vec::new();
+    for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let mut fields = part.splitn(2, ':');
+        let step = duration(fields.next().unwrap_or(""))?;
+        let retention = duration(fields.next().unwrap_or(""))?;
+        if step == 0 || retention < step {
+            return Err(format!("tier {:?} must retain at least one step", part));
+        }
+        if step < MIN_STEP {
+            return Err(format!(
+                "tier {:?} samples more often than every {}ms",
+                part, MIN_STEP
+            ));
+        }
+        if retention / step > MAX_POINTS {
+            return Err(format!(
+                "tier {:?} keeps more than {} points per peer",
+                part, MAX_POINTS
+            ));
+        }
+        if let Some(previous) = tiers.last() {
+            if step <= previous.step || step % previous.step != 0 || retention <= previous.retention
+            {
+                return Err(format!(
+                    "tier {:?} must be coarser than, a multiple of and retain more than the previous one",
+                    part
+                ));
+            }
+        }
+        tiers.push(Tier { step, retention });
+    }
+    if tiers.is_empty() {
+        return Err("no tiers given".to_owned());
+    }
+    Ok(tiers)
+}
+
+/// Parses `500ms`, `10s`, `5m`, `2h` or `7d` into milliseconds.
+pub fn duration(value: &str) -> Result<u128, String> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u128 = number
+        .parse()
+        .map_err(|_| format!("invalid duration {:?}", value))?;
+    let scale = match unit {
+        "ms" => 1,
+        "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        "d" => 24 * 60 * 60 * 1000,
+        _ => return Err(format!("invalid duration {:?}", value)),
+    };
+    Ok(number * scale)
+}
+
+/// Phi and level over `[at, at + step)`: the mean and largest phi, and the
+/// worst level seen.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Point {
+    pub at: u128,
+    pub phi: f64,
+    pub max: f64,
+    pub level: Level,
+}
+
+#[derive(Debug, Clone)]
+struct Bucket {
+    at: u128,
+    sum: f64,
+    count: u32,
+    max: f64,
+    level: Level,
+}
+
+impl Bucket {
+    fn new(at: u128, phi: f64, level: Level) -> Self {
+        Bucket {
+            at,
+            sum: phi,
+            count: 1,
+            max: phi,
+            level,
+        }
+    }
+
+    fn add(&mut self, phi: f64, level: Level) {
+        self.sum += phi;
+        self.count += 1;
+        self.max = self.max.max(phi);
+        self.level = self.level.max(level);
+    }
+
+    fn merge(&mut self, point: &Point) {
+        self.add(point.phi, point.level);
+        self.max = self.max.max(point.max);
+    }
+
+    fn point(&self) -> Point {
+        Point {
+            at: self.at,
+            phi: self.sum / self.count as f64,
+            max: self.max,
+            level: self.level,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Ring {
+    tier: Tier,
+    points: VecDeque<Point>,
+    current: Option<Bucket>,
+}
+
+impl Ring {
+    fn new(tier: Tier) -> Self {
+        Ring {
+            tier,
+            points: VecDeque::new(),
+            current: None,
+        }
+    }
+
+    fn add(&mut self, at: u128, phi: f64, level: Level) {
+        let start = at - at % self.tier.step;
+        match &mut self.current {
+            Some(bucket) if bucket.at == start => bucket.add(phi, level),
+            Some(bucket) if bucket.at > start => (),
+            current => {
+                if let Some(done) = current.replace(Bucket::new(start, phi, level)) {
+                    self.points.push_back(done.point());
+                    if self.points.len() > self.tier.capacity() {
+                        self.points.pop_front();
+                    }
+                }
+            }
+        }
+    }
+
+    fn oldest(&self) -> Option<u128> {
+        self.points
+            .front()
+            .map(|p| p.at)
+            .or_else(|| self.current.as_ref().map(|b| b.at))
+    }
+
+    fn points(&self) -> impl Iterator<Item = Point> + '_ {
+        self.points
+            .iter()
+            .cloned()
+            .chain(self.current.iter().map(Bucket::point))
+    }
+}
+
+/// Points of one peer between `from` and `to`, `step` apart.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Query {
+    pub id: Uuid,
+    pub step: u128,
+    pub points: std::vec::Vec<Point>,
+}
+
+#[derive(Debug)]
+pub struct TimeSeries {
+    tiers: std::vec::Vec<Tier>,
+    series: HashMap<Uuid, std::vec::Vec<Ring>>,
+}
+
+impl TimeSeries {
+    pub fn new(tiers: std::vec::Vec<Tier>) -> Self {
+        TimeSeries {
+            tiers,
+            series: HashMap::new(),
+        }
+    }
+
+    /// How often peers should be sampled.
+    pub fn resolution(&self) -> u128 {
+        self.tiers[0].step
+    }
+
+    pub fn record(&mut self, id: Uuid, at: u128, phi: f64, level: Level) {
+        let tiers = &self.tiers;
+        let rings = self
+            .series
+            .entry(id)
+            .or_insert_with(|| tiers.iter().cloned().map(Ring::new).collect());
+        for ring in rings {
+            ring.add(at, phi, level);
+        }
+    }
+
+    pub fn remove(&mut self, id: &Uuid) {
+        self.series.remove(id);
+    }
+
+    /// Reads from the finest tier that still reaches back to `from` and is
+    /// no coarser than `step`, or else the one reaching back furthest, and
+    /// merges its points into `step` wide buckets. `step` defaults to the
+    /// resolution of that tier. `None` for unknown peers.
+    pub fn query(&self, id: Uuid, from: u128, to: u128, step: Option<u128>) -> Option<Query> {
+        let rings = self.series.get(&id)?;
+        let candidates: std::vec::Vec<&Ring> = rings
+            .iter()
+            .filter(|ring| match step {
+                Some(step) => ring.tier.step <= step || ring.tier == self.tiers[0],
+                None => true,
+            })
+            .collect();
+        let ring = candidates
+            .iter()
+            .find(|ring| match ring.oldest() {
+                Some(oldest) => oldest <= from,
+                None => true,
+            })
+            .or_else(|| candidates.last())
+            .copied()
+            .unwrap_or(&rings[0]);
+        let step = step.unwrap_or(ring.tier.step).max(ring.tier.step).max(1);
+
+        let mut points = std::vec::Vec::new();
+        let mut bucket: Option<Bucket> = None;
+        for point in ring.points().filter(|p| p.at >= from && p.at <= to) {
+            let start = point.at - point.at % step;
+            match &mut bucket {
+                Some(b) if b.at == start => b.merge(&point),
+                current => {
+                    let mut fresh = Bucket::new(start, point.phi, point.level);
+                    fresh.max = point.max;
+                    if let Some(done) = current.replace(fresh) {
+                        points.push(done.point());
+                    }
+                }
+            }
+        }
+        points.extend(bucket.map(|b| b.point()));
+        Some(Query { id, step, points })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_tiers, Tier, TimeSeries};
+    use crate::messages::Level;
+    use uuid::Uuid;
+
+    #[test]
+    fn parse_tier_spec() {
+        assert_eq!(
+            parse_tiers("1s:1h, 1m:1d").unwrap(),
+            vec![
+                Tier {
+                    step: 1000,
+                    retention: 3_600_000
+                },
+                Tier {
+                    step: 60_000,
+                    retention: 86_400_000
+                },
+            ]
+        );
+        assert!(parse_tiers("").is_err());
+        assert!(parse_tiers("1s").is_err());
+        assert!(parse_tiers("1m:1h,1s:1d").is_err());
+        assert!(parse_tiers("2s:1h,3s:1d").is_err());
+        assert!(parse_tiers("1x:1h").is_err());
+        assert!(parse_tiers("1ms:1s").is_err());
+        assert!(parse_tiers("100ms:1m").is_ok());
+        assert!(parse_tiers("1s:2d").is_err());
+        assert!(parse_tiers("1s:1h,1m:100d").is_err());
+    }
+
+    #[test]
+    fn tiers_downsample_and_expire() {
+        let id = Uuid::new_v4();
+        let mut series = TimeSeries::new(parse_tiers("1s:10s,5s:1m").unwrap());
+        let start = 1_000_000;
+        for i in 0..30u128 {
+            let level = if i == 22 { Level::Dead } else { Level::Alive };
+            series.record(id, start + i * 1000, i as f64, level);
+        }
+
+        // the fine tier only reaches back 10 points, plus the open bucket
+        let recent = series
+            .query(id, start + 19_000, start + 40_000, None)
+            .unwrap();
+        assert_eq!(recent.step, 1000);
+        assert_eq!(recent.points.len(), 11);
+        assert_eq!(recent.points.last().unwrap().phi, 29.0);
+
+        // older history comes from the coarse tier
+        let old = series.query(id, start, start + 40_000, None).unwrap();
+        assert_eq!(old.step, 5000);
+        assert_eq!(old.points.len(), 6);
+        assert_eq!(old.points[0].phi, 2.0);
+        assert_eq!(old.points[0].max, 4.0);
+        assert_eq!(old.points[4].level, Level::Dead);
+
+        // a wider step merges the points of the chosen tier
+        let merged = series
+            .query(id, start, start + 40_000, Some(10_000))
+            .unwrap();
+        assert_eq!(merged.step, 10_000);
+        assert_eq!(merged.points.len(), 3);
+        assert_eq!(merged.points[2].max, 29.0);
+
+        assert!(series.query(Uuid::new_v4(), 0, 1, None).is_none());
+        series.remove(&id);
+        assert!(series.query(id, 0, 1, None).is_none());
+    }
+}
@@ -0,0 +1,390 @@
+//! HTTPS and WSS for the API and dashboard: the certificate is reloaded when
+//! its files change, and client certificates can be verified against a CA,
+//! their common names mapping to API roles.
+
+use crate::auth::ClientIdentity;
+use actix_http::{
+    http::{header, Uri},
+    RequestHead,
+};
+use actix_rt::net::TcpStream;
+use log::{info, warn};
+use std::{
+    fs,
+    io::{self, BufReader},
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio_rustls::{
+    rustls::{
+        internal::pemfile,
+        sign::{self, CertifiedKey},
+        AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientHello,
+        NoClientAuth, ResolvesServerCert, RootCertStore, ServerConfig, Session, SignatureScheme,
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+
+/// How often the certificate and key files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Whether clients must present a certificate signed by the client CA, or
+/// may also connect without one and authenticate with a token.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClientAuth {
+    Required,
+    Optional,
+}
+
+impl FromStr for ClientAuth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "required" => Ok(ClientAuth::Required),
+            "optional" => Ok(ClientAuth::Optional),
+            _ => Err(format!(
+                "unknown client auth {:?}, expected required or optional",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// CA bundle client certificates are verified against, if any.
+    pub client_ca: Option<PathBuf>,
+    pub client_auth: ClientAuth,
+}
+
+/// Certificate chain and key served to clients, swapped in place when the
+/// files on disk change.
+pub struct Certificates {
+    cert: PathBuf,
+    key: PathBuf,
+    /// File contents last seen, valid or not, so a bad file is reported once.
+    seen: RwLock<(std::vec::Vec<u8>, std::vec::Vec<u8>)>,
+    current: RwLock<CertifiedKey>,
+}
+
+impl Certificates {
+    pub fn load(cert: PathBuf, key: PathBuf) -> io::Result<Self> {
+        let (cert_pem, key_pem) = (fs::read(&cert)?, fs::read(&key)?);
+        let current = certified_key(&cert_pem, &key_pem)?;
+        Ok(Certificates {
+            cert,
+            key,
+            seen: RwLock::new((cert_pem, key_pem)),
+            current: RwLock::new(current),
+        })
+    }
+
+    /// Reloads the certificate and key if either file changed, returning
+    /// whether it did. Invalid files, such as a certificate renewed without
+    /// its key yet, leave the current certificate in place.
+    pub fn reload(&self) -> io::Result<bool> {
+        let (cert_pem, key_pem) = (fs::read(&self.cert)?, fs::read(&self.key)?);
+        {
+            let mut seen = self.seen.write().unwrap();
+            if seen.0 == cert_pem && seen.1 == key_pem {
+                return Ok(false);
+            }
+            *seen = (cert_pem.clone(), key_pem.clone());
+        }
+        let key = certified_key(&cert_pem, &key_pem)?;
+        *self.current.write().unwrap() = key;
+        Ok(true)
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, _: ClientHello) -> Option<CertifiedKey> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn certified_key(cert_pem: &[u8], key_pem: &[u8]) -> io::Result<CertifiedKey> {
+    let certs = pemfile::certs(&mut BufReader::new(cert_pem))
+        .map_err(|_| invalid("invalid certificate file"))?;
+    if certs.is_empty() {
+        return Err(invalid("no certificate in certificate file"));
+    }
+    let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(key_pem))
+        .map_err(|_| invalid("invalid key file"))?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut BufReader::new(key_pem))
+            .map_err(|_| invalid("invalid key file"))?;
+    }
+    let key = keys
+        .first()
+        .ok_or_else(|| invalid("no private key in key file"))?;
+    let key = sign::any_supported_type(key).map_err(|_| invalid("unsupported private key"))?;
+    check_key(&certs[0].0, key.as_ref())?;
+    Ok(CertifiedKey::new(certs, Arc::new(key)))
+}
+
+/// Signs a probe with the key and verifies it against the certificate, so
+/// a certificate renewed before its key is not served with the old key.
+fn check_key(cert: &[u8], key: &dyn sign::SigningKey) -> io::Result<()> {
+    let schemes = [
+        (SignatureScheme::ECDSA_NISTP256_SHA256, &webpki::ECDSA_P256_SHA256),
+        (SignatureScheme::ECDSA_NISTP384_SHA384, &webpki::ECDSA_P384_SHA384),
+        (SignatureScheme::ED25519, &webpki::ED25519),
+        (SignatureScheme::RSA_PKCS1_SHA256, &webpki::RSA_PKCS1_2048_8192_SHA256),
+    ];
+    let offered: std::vec::Vec<SignatureScheme> = schemes.iter().map(|(s, _)| *s).collect();
+    let signer = key
+        .choose_scheme(&offered)
+        .ok_or_else(|| invalid("unsupported private key"))?;
+    let probe = b"phifd certificate check";
+    let signature = signer
+        .sign(probe)
+        .map_err(|e| invalid(&format!("could not sign with key: {}", e)))?;
+    let algorithm = schemes
+        .iter()
+        .find(|(s, _)| *s == signer.get_scheme())
+        .map(|(_, a)| *a)
+        .ok_or_else(|| invalid("unsupported private key"))?;
+    webpki::EndEntityCert::from(cert)
+        .and_then(|cert| cert.verify_signature(algorithm, probe, &signature))
+        .map_err(|_| invalid("key does not match certificate"))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())
+}
+
+pub fn acceptor(settings: &Settings, certs: Arc<Certificates>) -> io::Result<TlsAcceptor> {
+    let verifier = match &settings.client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            let (valid, _) = roots
+                .add_pem_file(&mut BufReader::new(fs::File::open(path)?))
+                .map_err(|_| invalid("invalid client CA file"))?;
+            if valid == 0 {
+                return Err(invalid("no certificate in client CA file"));
+            }
+            match settings.client_auth {
+                ClientAuth::Required => AllowAnyAuthenticatedClient::new(roots),
+                ClientAuth::Optional => AllowAnyAnonymousOrAuthenticatedClient::new(roots),
+            }
+        }
+        None => NoClientAuth::new(),
+    };
+    let mut config = ServerConfig::new(verifier);
+    config.cert_resolver = certs;
+    config.set_protocols(&[b"http/1.1".to_vec()]);
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Polls the certificate files and swaps in renewed certificates.
+pub fn watch(certs: Arc<Certificates>) {
+    actix_rt::spawn(async move {
+        let mut interval = actix_rt::time::interval(RELOAD_INTERVAL);
+        loop {
+            interval.tick().await;
+            match certs.reload() {
+                Ok(true) => info!("Reloaded TLS certificate"),
+                Ok(false) => (),
+                Err(e) => warn!("Could not reload TLS certificate: {}", e),
+            }
+        }
+    });
+}
+
+/// Identity of the verified client certificate of a connection, stored in
+/// the extensions of its requests.
+pub fn client_identity(io: &TlsStream<TcpStream>) -> Option<ClientIdentity> {
+    let (_, session) = io.get_ref();
+    let certs = session.get_peer_certificates()?;
+    common_name(&certs.first()?.0).map(ClientIdentity)
+}
+
+/// Makes the URI of a request received over TLS absolute, with the https
+/// scheme and its Host header, or else `bind`, for authority, so its
+/// connection info reports what a secure `AppConfig` would: actix-web only
+/// builds one for its own listeners.
+pub fn secure_uri(head: &mut RequestHead, bind: &str) {
+    if head.uri.scheme().is_some() {
+        return;
+    }
+    let authority = head
+        .headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or(bind);
+    let path = head.uri.path_and_query().map_or("/", |path| path.as_str());
+    if let Ok(uri) = Uri::builder()
+        .scheme("https")
+        .authority(authority)
+        .path_and_query(path)
+        .build()
+    {
+        head.uri = uri;
+    }
+}
+
+/// Subject common name of a DER encoded X.509 certificate.
+pub fn common_name(der: &[u8]) -> Option<String> {
+    const COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+
+    let (_, cert, _) = element(der)?;
+    let (_, tbs, _) = element(cert)?;
+    let fields = elements(tbs)?;
+    // the version is an optional explicitly tagged first field
+    let skip = if fields.first()?.0 == 0xa0 { 1 } else { 0 };
+    // serial number, signature algorithm, issuer, validity, subject
+    let (_, subject) = fields.get(skip + 4)?;
+    for (_, set) in elements(subject)? {
+        for (_, attribute) in elements(set)? {
+            match elements(attribute)?.as_slice() {
+                [(0x06, oid), (_, value)] if *oid == COMMON_NAME => {
+                    return String::from_utf8(value.to_vec()).ok()
+                }
+                _ => (),
+            }
+        }
+    }
+    None
+}
+
+/// Splits a DER element into its tag, contents and the remaining input;
+/// indefinite and non-minimal lengths are not DER and rejected.
+fn element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let n = (first & 0x7f) as usize;
+        if n == 0 || n > 4 || rest.len() < n {
+            return None;
+        }
+        let len = rest[..n].iter().fold(0, |len, &b| len << 8 | b as usize);
+        if rest[0] == 0 || len < 0x80 {
+            return None;
+        }
+        (len, &rest[n..])
+    };
+    if rest.len() < len {
+        return None;
+    }
+    Some((tag, &rest[..len], &rest[len..]))
+}
+
+fn elements(mut input: &[u8]) -> Option<std::vec::Vec<(u8, &[u8])>> {
+    let mut all = std::vec::Vec::new();
+    while !input.is_empty() {
+        let (tag, contents, rest) = element(input)?;
+        all.push((tag, contents));
+        input = rest;
+    }
+    Some(all)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{common_name, element, secure_uri, Certificates};
+    use actix_http::{http::header, RequestHead};
+    use rcgen::{Certificate, CertificateParams, DistinguishedName, DnType};
+    use std::{env, fs};
+
+    fn certificate(name: &str) -> Certificate {
+        let mut params = CertificateParams::new(vec!["localhost".to_owned()]);
+        params.distinguished_name = DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(DnType::OrganizationName, "phifd");
+        params.distinguished_name.push(DnType::CommonName, name);
+        Certificate::from_params(params).unwrap()
+    }
+
+    #[test]
+    fn subject_common_name() {
+        let cert = certificate("ops");
+        assert_eq!(
+            common_name(&cert.serialize_der().unwrap()),
+            Some("ops".to_owned())
+        );
+        assert_eq!(common_name(&[0x30, 0x05, 0x01]), None);
+    }
+
+    #[test]
+    fn truncated_certificate() {
+        // long enough for the lengths of the name and its parents to take
+        // more than one byte
+        let name = "n".repeat(200);
+        let der = certificate(&name).serialize_der().unwrap();
+        assert_eq!(common_name(&der), Some(name));
+        for cut in 0..der.len() {
+            assert_eq!(common_name(&der[..cut]), None, "cut at {}", cut);
+        }
+    }
+
+    #[test]
+    fn der_lengths() {
+        let long = [&[0x04, 0x81, 0x80][..], &[0x2a; 0x80], &[0x05]].concat();
+        assert_eq!(element(&long), Some((0x04, &long[3..131], &[0x05][..])));
+        assert_eq!(
+            element(&[0x04, 0x82, 0x01, 0x00]),
+            None,
+            "contents shorter than the length"
+        );
+        assert_eq!(element(&[0x04, 0x82, 0x01]), None, "truncated length");
+        assert_eq!(element(&[0x04, 0x80, 0x00, 0x00]), None, "indefinite");
+        assert_eq!(element(&[0x04, 0x81, 0x01, 0x2a]), None, "not minimal");
+        assert_eq!(element(&[0x04, 0x82, 0x00, 0x81]), None, "leading zero");
+        assert_eq!(element(&[0x04, 0x85, 1, 0, 0, 0, 0]), None, "too long");
+    }
+
+    #[test]
+    fn requests_are_secure() {
+        let mut head = RequestHead::default();
+        head.uri = "/api/info?x=1".parse().unwrap();
+        secure_uri(&mut head, "0.0.0.0:8443");
+        assert_eq!(head.uri, "https://0.0.0.0:8443/api/info?x=1");
+
+        head.uri = "/".parse().unwrap();
+        head.headers
+            .insert(header::HOST, "phifd.test:8443".parse().unwrap());
+        secure_uri(&mut head, "0.0.0.0:8443");
+        assert_eq!(head.uri, "https://phifd.test:8443/");
+    }
+
+    #[test]
+    fn reload_on_change() {
+        let dir = env::temp_dir().join(format!("phifd-tls-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+        let write = |cert: &Certificate| {
+            fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+            fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+        };
+
+        let first = certificate("a");
+        write(&first);
+        let certs = Certificates::load(cert_path.clone(), key_path.clone()).unwrap();
+        assert!(!certs.reload().unwrap());
+
+        // a certificate without its matching key is rejected, once
+        let second = certificate("b");
+        fs::write(&cert_path, second.serialize_pem().unwrap()).unwrap();
+        assert!(certs.reload().is_err());
+        assert!(!certs.reload().unwrap());
+        let served = certs.current.read().unwrap().cert[0].0.clone();
+        assert_eq!(common_name(&served), Some("a".to_owned()));
+
+        write(&second);
+        assert!(certs.reload().unwrap());
+        let served = certs.current.read().unwrap().cert[0].0.clone();
+        assert_eq!(common_name(&served), Some("b".to_owned()));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,350 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+use uuid::Uuid;
+
+use crate::messages::Arrival;
+
+/// Leading bytes of a binary trace file, the last one being the format version.
+const MAGIC: &[u8; 5] = b"PHIT\x01";
+
+/// A single heartbeat arrival: which peer it came from and when it was
+/// received, in milliseconds since the epoch.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TraceRecord {
+    pub id: Uuid,
+    #[serde(alias = "received")]
+    pub timestamp: u128,
+}
+
+impl From<&Arrival> for TraceRecord {
+    fn from(arrival: &Arrival) -> Self {
+        TraceRecord {
+            id: arrival.id,
+            timestamp: arrival.received,
+        }
+    }
+}
+
+/// Reads a trace of heartbeat arrivals, either as written by `TraceWriter` or
+/// as `id,timestamp` CSV. Blank lines, `#` comments and a CSV header are
+/// skipped.
+pub fn read<P: AsRef<Path>>(path: P) -> io::Result<std::vec::Vec<TraceRecord>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    if reader.fill_buf()?.starts_with(MAGIC) {
+        return Ok(read_binary(reader)?.iter().map(TraceRecord::from).collect());
+    }
+    parse(reader)
+}
+
+pub fn parse<R: BufRead>(reader: R) -> io::Result<std::vec::Vec<TraceRecord>> {
+    let mut records = std::vec::Vec::new();
+    for (n, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match parse_line(line) {
+            Ok(record) => records.push(record),
+            Err(_) if n == 0 && !line.starts_with('{') => continue,
+            Err(e) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: {}", n + 1, e),
+                ))
+            }
+        }
+    }
+    Ok(records)
+}
+
+fn parse_line(line: &str) -> Result<TraceRecord, String> {
+    if line.starts_with('{') {
+        return serde_json::from_str(line).map_err(|e| e.to_string());
+    }
+
+    let fields: std::vec::Vec<&str> = line.split(',').map(str::trim).collect();
+    if fields.len() < 2 {
+        return Err(format!("expected `id,timestamp`, got {:?}", line));
+    }
+    Ok(TraceRecord {
+        id: Uuid::parse_str(fields[0]).map_err(|e| e.to_string())?,
+        timestamp: fields[1].parse().map_err(|e| format!("{}", e))?,
+    })
+}
+
+/// Size of an arrival in a binary trace: the peer id, sequence number and
+/// the sent and received timestamps.
+const RECORD: usize = 16 + 3 * 8;
+
+/// Reads arrivals from a binary trace file written by `TraceWriter`. A
+/// record cut short at the end, as left by a node that stopped mid-write,
+/// is skipped with a warning.
+pub fn read_binary<R: Read>(mut reader: R) -> io::Result<std::vec::Vec<Arrival>> {
+    let mut magic = [0; 5];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a binary heartbeat trace",
+        ));
+    }
+
+    let mut arrivals = std::vec::Vec::new();
+    let mut record = [0; RECORD];
+    loop {
+        let mut filled = 0;
+        while filled < RECORD {
+            match reader.read(&mut record[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if filled < RECORD {
+            if filled > 0 {
+                println!(
+                    "Ignoring truncated trace record after {} arrivals ({} of {} bytes)",
+                    arrivals.len(),
+                    filled,
+                    RECORD
+                );
+            }
+            break;
+        }
+
+        let mut id = [0; 16];
+        id.copy_from_slice(&record[..16]);
+        let mut fields = &record[16..];
+        arrivals.push(Arrival {
+            id: Uuid::from_bytes(id),
+            seq: fields.read_u64::<BigEndian>()?,
+            sent: fields.read_u64::<BigEndian>()? as u128,
+            received: fields.read_u64::<BigEndian>()? as u128,
+        });
+    }
+    Ok(arrivals)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Binary,
+}
+
+impl Format {
+    /// Files ending in `.bin` are binary, anything else is JSON lines.
+    pub fn from_path(path: &Path) -> Format {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("bin") => Format::Binary,
+            _ => Format::Json,
+        }
+    }
+}
+
+/// Appends every received heartbeat to a local file, rotating it once it
+/// grows past `max_bytes` and keeping at most `max_files` rotated files
+/// (`trace.jsonl.1` being the most recent).
+pub struct TraceWriter {
+    path: PathBuf,
+    format: Format,
+    max_bytes: u64,
+    max_files: usize,
+    written: u64,
+    out: BufWriter<File>,
+}
+
+impl TraceWriter {
+    pub fn open<P: Into<PathBuf>>(path: P, max_bytes: u64, max_files: usize) -> io::Result<Self> {
+        let path = path.into();
+        let format = Format::from_path(&path);
+        let (out, written) = TraceWriter::create(&path, format)?;
+        Ok(TraceWriter {
+            path,
+            format,
+            max_bytes,
+            max_files,
+            written,
+            out,
+        })
+    }
+
+    fn create(path: &Path, format: Format) -> io::Result<(BufWriter<File>, u64)> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut written = file.metadata()?.len();
+        let mut out = BufWriter::new(file);
+        if format == Format::Binary && written == 0 {
+            out.write_all(MAGIC)?;
+            written += MAGIC.len() as u64;
+        }
+        Ok((out, written))
+    }
+
+    pub fn write(&mut self, arrival: &Arrival) -> io::Result<()> {
+        if self.written >= self.max_bytes {
+            self.rotate()?;
+        }
+
+        match self.format {
+            Format::Json => {
+                let line = serde_json::to_string(arrival)?;
+                self.out.write_all(line.as_bytes())?;
+                self.out.write_all(b"\n")?;
+                self.written += line.len() as u64 + 1;
+            }
+            Format::Binary => {
+                self.out.write_all(arrival.id.as_bytes())?;
+                self.out.write_u64::<BigEndian>(arrival.seq)?;
+                self.out.write_u64::<BigEndian>(arrival.sent as u64)?;
+                self.out.write_u64::<BigEndian>(arrival.received as u64)?;
+                self.written += RECORD as u64;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.out.flush()?;
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(self.rotated(self.max_files));
+            for n in (1..self.max_files).rev() {
+                let _ = fs::rename(self.rotated(n), self.rotated(n + 1));
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+
+        let (out, written) = TraceWriter::create(&self.path, self.format)?;
+        self.out = out;
+        self.written = written;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, read, read_binary, TraceWriter};
+    use crate::messages::Arrival;
+    use std::{env, fs};
+
+    const PEER: &str = "78441273-5550-4733-9d37-1c0319a21053";
+
+    #[test]
+    fn parse_csv_with_header() {
+        let input = format!("id,timestamp\n{0},1000\n\n# gap\n{0}, 2000\n", PEER);
+        let records = parse(input.as_bytes()).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].timestamp, 2000);
+        assert_eq!(records[1].id.to_string(), PEER);
+    }
+
+    #[test]
+    fn parse_jsonl() {
+        let input = format!(
+            "{{\"id\":\"{0}\",\"timestamp\":1000}}\n{{\"id\":\"{0}\",\"received\":1100}}\n",
+            PEER
+        );
+        let records = parse(input.as_bytes()).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].timestamp, 1100);
+    }
+
+    #[test]
+    fn parse_reports_bad_line() {
+        let input = format!("{0},1000\n{0},soon\n", PEER);
+        let err = parse(input.as_bytes()).unwrap_err();
+        assert!(err.to_string().starts_with("line 2"));
+    }
+
+    fn arrival(seq: u64) -> Arrival {
+        Arrival {
+            id: PEER.parse().unwrap(),
+            seq,
+            sent: 1000 + seq as u128 * 150,
+            received: 1002 + seq as u128 * 150,
+        }
+    }
+
+    #[test]
+    fn write_and_read_back() {
+        let dir = env::temp_dir().join(format!("phifd-trace-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+
+        for name in &["trace.jsonl", "trace.bin"] {
+            let path = dir.join(name);
+            let mut writer = TraceWriter::open(&path, 1 << 20, 2).unwrap();
+            for seq in 0..10 {
+                writer.write(&arrival(seq)).unwrap();
+            }
+            writer.flush().unwrap();
+
+            let records = read(&path).unwrap();
+            assert_eq!(records.len(), 10);
+            assert_eq!(records[3].timestamp, arrival(3).received);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotate_keeps_max_files() {
+        let dir = env::temp_dir().join(format!("phifd-trace-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("trace.bin");
+
+        let mut writer = TraceWriter::open(&path, 100, 2).unwrap();
+        for seq in 0..20 {
+            writer.write(&arrival(seq)).unwrap();
+        }
+        writer.flush().unwrap();
+
+        let mut names: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["trace.bin", "trace.bin.1", "trace.bin.2"]);
+        assert_eq!(read(dir.join("trace.bin.1")).unwrap().len(), 3);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncated_binary_keeps_complete_records() {
+        let dir = env::temp_dir().join(format!("phifd-trace-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("trace.bin");
+
+        let mut writer = TraceWriter::open(&path, 1 << 20, 2).unwrap();
+        for seq in 0..3 {
+            writer.write(&arrival(seq)).unwrap();
+        }
+        writer.flush().unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        // cut into the last record, both in its id and in its timestamps
+        for &cut in &[30, 5] {
+            let arrivals = read_binary(&bytes[..bytes.len() - cut]).unwrap();
+            assert_eq!(arrivals, vec![arrival(0), arrival(1)]);
+        }
+        assert_eq!(read_binary(&bytes[..]).unwrap().len(), 3);
+    }
+}
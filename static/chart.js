@@ -0,0 +1,177 @@
+// Minimal bar, line, time series and strip charts drawn on a canvas, enough for the dashboard
+// without pulling a charting library from a CDN.
+var Charts = (function () {
+    var FONT = '12px sans-serif';
+    var PADDING = { top: 28, right: 12, bottom: 36, left: 44 };
+
+    function setup(canvas) {
+        var ratio = window.devicePixelRatio || 1;
+        var width = canvas.clientWidth;
+        var height = canvas.clientHeight;
+        if (canvas.width !== width * ratio || canvas.height !== height * ratio) {
+            canvas.width = width * ratio;
+            canvas.height = height * ratio;
+        }
+        var ctx = canvas.getContext('2d');
+        ctx.setTransform(ratio, 0, 0, ratio, 0, 0);
+        ctx.clearRect(0, 0, width, height);
+        ctx.font = FONT;
+        return { ctx: ctx, width: width, height: height };
+    }
+
+    function niceMax(value) {
+        if (value <= 0) {
+            return 1;
+        }
+        var step = Math.pow(10, Math.floor(Math.log10(value)));
+        var scaled = value / step;
+        var nice = scaled <= 1 ? 1 : scaled <= 2 ? 2 : scaled <= 5 ? 5 : 10;
+        return nice * step;
+    }
+
+    function axes(c, title, max) {
+        var ctx = c.ctx;
+        var plotHeight = c.height - PADDING.top - PADDING.bottom;
+
+        ctx.fillStyle = '#666';
+        ctx.textAlign = 'center';
+        ctx.fillText(title, c.width / 2, 16);
+
+        ctx.strokeStyle = '#e5e5e5';
+        ctx.textAlign = 'right';
+        ctx.textBaseline = 'middle';
+        for (var i = 0; i <= 4; i++) {
+            var y = PADDING.top + plotHeight - plotHeight * i / 4;
+            ctx.beginPath();
+            ctx.moveTo(PADDING.left, y);
+            ctx.lineTo(c.width - PADDING.right, y);
+            ctx.stroke();
+            ctx.fillText(+(max * i / 4).toFixed(2), PADDING.left - 6, y);
+        }
+        ctx.textBaseline = 'alphabetic';
+        return plotHeight;
+    }
+
+    function scaleY(plotHeight, max, value) {
+        return PADDING.top + plotHeight - plotHeight * Math.min(value, max) / max;
+    }
+
+    // bars: [{ label, value, color }]
+    function bar(canvas, title, bars, suggestedMax) {
+        var c = setup(canvas);
+        var max = niceMax(Math.max.apply(null, bars.map(b => b.value).concat([suggestedMax || 0])));
+        var plotHeight = axes(c, title, max);
+        var plotWidth = c.width - PADDING.left - PADDING.right;
+        var slot = plotWidth / Math.max(bars.length, 1);
+
+        bars.forEach(function (b, i) {
+            var x = PADDING.left + slot * i;
+            var y = scaleY(plotHeight, max, b.value);
+            c.ctx.fillStyle = b.color;
+            c.ctx.fillRect(x + slot * 0.1, y, slot * 0.8, PADDING.top + plotHeight - y);
+            c.ctx.fillStyle = '#666';
+            c.ctx.textAlign = 'center';
+            c.ctx.fillText(b.label, x + slot / 2, c.height - PADDING.bottom + 16);
+        });
+    }
+
+    // series: [{ label, color, data: [number] }], labels: x axis labels
+    function line(canvas, title, labels, series) {
+        var c = setup(canvas);
+        var values = [].concat.apply([], series.map(s => s.data));
+        var max = niceMax(Math.max.apply(null, values.concat([0])));
+        var plotHeight = axes(c, title, max);
+        var plotWidth = c.width - PADDING.left - PADDING.right;
+        var step = plotWidth / Math.max(labels.length - 1, 1);
+
+        c.ctx.fillStyle = '#666';
+        c.ctx.textAlign = 'center';
+        labels.forEach(function (label, i) {
+            if (i % Math.ceil(labels.length / 8) === 0) {
+                c.ctx.fillText(label, PADDING.left + step * i, c.height - PADDING.bottom + 16);
+            }
+        });
+
+        series.forEach(function (s, n) {
+            var offset = labels.length - s.data.length;
+            c.ctx.strokeStyle = s.color;
+            c.ctx.lineWidth = 2;
+            c.ctx.beginPath();
+            s.data.forEach(function (value, i) {
+                var x = PADDING.left + step * (offset + i);
+                var y = scaleY(plotHeight, max, value);
+                if (i === 0) {
+                    c.ctx.moveTo(x, y);
+                } else {
+                    c.ctx.lineTo(x, y);
+                }
+            });
+            c.ctx.stroke();
+            c.ctx.lineWidth = 1;
+
+            var legendX = PADDING.left + n * 90;
+            c.ctx.fillStyle = s.color;
+            c.ctx.fillRect(legendX, c.height - 14, 10, 10);
+            c.ctx.fillStyle = '#666';
+            c.ctx.textAlign = 'left';
+            c.ctx.fillText(s.label, legendX + 14, c.height - 5);
+        });
+    }
+
+    // series: [{ label, color, points: [{ at, value }] }] over the time range
+    // [from, to] in milliseconds
+    function time(canvas, title, from, to, series, suggestedMax) {
+        var c = setup(canvas);
+        var values = [].concat.apply([], series.map(s => s.points.map(p => p.value)));
+        var max = niceMax(Math.max.apply(null, values.concat([suggestedMax || 0])));
+        var plotHeight = axes(c, title, max);
+        var plotWidth = c.width - PADDING.left - PADDING.right;
+        var scaleX = at => PADDING.left + plotWidth * (at - from) / Math.max(to - from, 1);
+
+        c.ctx.fillStyle = '#666';
+        c.ctx.textAlign = 'center';
+        for (var i = 0; i <= 4; i++) {
+            var at = from + (to - from) * i / 4;
+            var date = new Date(at);
+            var label = date.getHours() + ':' + String(date.getMinutes()).padStart(2, '0');
+            c.ctx.fillText(label, scaleX(at), c.height - PADDING.bottom + 16);
+        }
+
+        series.forEach(function (s, n) {
+            c.ctx.strokeStyle = s.color;
+            c.ctx.lineWidth = 2;
+            c.ctx.beginPath();
+            s.points.filter(p => p.at >= from).forEach(function (p, i) {
+                var x = scaleX(p.at);
+                var y = scaleY(plotHeight, max, p.value);
+                if (i === 0) {
+                    c.ctx.moveTo(x, y);
+                } else {
+                    c.ctx.lineTo(x, y);
+                }
+            });
+            c.ctx.stroke();
+            c.ctx.lineWidth = 1;
+
+            var legendX = PADDING.left + n * 90;
+            c.ctx.fillStyle = s.color;
+            c.ctx.fillRect(legendX, c.height - 14, 10, 10);
+            c.ctx.fillStyle = '#666';
+            c.ctx.textAlign = 'left';
+            c.ctx.fillText(s.label, legendX + 14, c.height - 5);
+        });
+    }
+
+    // segments: [{ from, to, color }], positions as fractions of the width
+    function strip(canvas, segments) {
+        var c = setup(canvas);
+        c.ctx.fillStyle = '#e9ecef';
+        c.ctx.fillRect(0, 0, c.width, c.height);
+        segments.forEach(function (s) {
+            c.ctx.fillStyle = s.color;
+            c.ctx.fillRect(s.from * c.width, 0, (s.to - s.from) * c.width, c.height);
+        });
+    }
+
+    return { bar: bar, line: line, time: time, strip: strip };
+})();
@@ -0,0 +1,431 @@
+var webSocket;
+var peers = {};
+var lastSeq = 0;
+var chartColors = ["#3e95cd", "#8e5ea2", "#3cba9f", "#e8c3b9", "#c45850"];
+var levelColors = { alive: '#3cba9f', suspect: '#e8a33e', dead: '#c45850' };
+var colors = {};
+var intervals = { labels: [], series: {} };
+var maxElem = 15;
+var timelineWindow = 10 * 60 * 1000;
+var historyWindow = 15 * 60 * 1000;
+// Phi of every peer over the history window, loaded from the server and
+// extended with every status tick.
+var phiHistory = {};
+// Server clock minus browser clock, so ages are computed in server time.
+var clockOffset = 0;
+var selected = null;
+// API token, kept across visits; empty when the server needs none.
+var token = window.localStorage.getItem('phiToken') || '';
+
+function init() {
+    var scheme = window.location.protocol === 'https:' ? 'wss://' : 'ws://';
+    var query = token ? '?token=' + encodeURIComponent(token) : '';
+    webSocket = new WebSocket(scheme + window.location.host + '/ws/' + query);
+    var opened = false;
+
+    webSocket.onopen = function () {
+        opened = true;
+    };
+
+    // the handshake fails with 401 when the server wants a token
+    webSocket.onclose = function () {
+        if (!opened) {
+            showAccess(token ? 'The access token was rejected' : 'This server requires an access token');
+        }
+    };
+
+    webSocket.onmessage = function (event) {
+        var msg = JSON.parse(event.data);
+        if (msg.type === 'status' && msg.seq !== undefined) {
+            peers = {};
+            msg.peers.forEach(function (p) {
+                p.samples = p.history.slice();
+                peers[p.id] = p;
+                syncClock(p);
+            });
+            lastSeq = msg.seq;
+            msg.peers.forEach(p => loadHistory(p.id));
+        } else if (msg.type === 'delta') {
+            if (msg.seq !== lastSeq + 1) {
+                webSocket.send(JSON.stringify({ v: 1, type: 'resync' }));
+                return;
+            }
+            lastSeq = msg.seq;
+            msg.removed.forEach(function (id) {
+                delete peers[id];
+                delete phiHistory[id];
+            });
+            msg.peers.filter(p => !peers[p.id]).forEach(p => loadHistory(p.id));
+            msg.peers.forEach(applyDelta);
+        } else {
+            return;
+        }
+
+        var data = Object.values(peers).sort(compare);
+        addDataIntervals(data);
+        addDataHistory(data);
+        draw(data);
+    };
+
+    document.querySelector('#peers tbody').addEventListener('click', function (event) {
+        var row = event.target.closest('tr');
+        if (row) {
+            selected = selected === row.dataset.id ? null : row.dataset.id;
+            fillDetector(peers[selected]);
+            draw(Object.values(peers).sort(compare));
+        }
+    });
+
+    document.getElementById('access').addEventListener('submit', function (event) {
+        event.preventDefault();
+        window.localStorage.setItem('phiToken', event.target.elements.token.value.trim());
+        window.location.reload();
+    });
+
+    document.getElementById('addPeer').addEventListener('submit', function (event) {
+        event.preventDefault();
+        var form = event.target;
+        var fields = form.elements;
+        register({ id: fields.id.value.trim(), name: fields.name.value.trim(), address: fields.address.value.trim() })
+            .then(() => form.reset());
+    });
+
+    document.getElementById('addRemote').addEventListener('submit', function (event) {
+        event.preventDefault();
+        var form = event.target;
+        fetch(form.elements.url.value.replace(/\/+$/, '') + '/api/info')
+            .then(response => response.ok ? response.json() : Promise.reject(response.statusText))
+            .then(register)
+            .then(() => form.reset())
+            .catch(e => showMessage('Could not fetch node info: ' + e, true));
+    });
+
+    document.getElementById('detector').addEventListener('submit', function (event) {
+        event.preventDefault();
+        var peer = peers[selected];
+        if (!peer) {
+            return;
+        }
+        var form = event.target;
+        var detector = { mode: form.elements.mode.value };
+        ['threshold', 'sample_size', 'min_std_deviation', 'acceptable_heartbeat_pause', 'first_heartbeat_estimate']
+            .forEach(function (key) {
+                if (form.elements[key].value !== '') {
+                    detector[key] = Number(form.elements[key].value);
+                }
+            });
+        register({ id: peer.id, name: peer.name, address: peer.address, detector: detector });
+    });
+
+    document.getElementById('removePeer').addEventListener('click', function () {
+        var peer = peers[selected];
+        if (!peer || !window.confirm('Stop monitoring ' + peer.name + '?')) {
+            return;
+        }
+        request('DELETE', '/api/unregister/' + peer.id)
+            .then(() => showMessage('Removed ' + peer.name))
+            .catch(e => showMessage('Could not remove ' + peer.name + ': ' + e, true));
+        selected = null;
+    });
+
+    window.addEventListener('resize', function () {
+        draw(Object.values(peers).sort(compare));
+    });
+}
+
+// fetch from this node's API, with the access token if any
+function api(url, init) {
+    init = init || {};
+    init.headers = init.headers || {};
+    if (token) {
+        init.headers.Authorization = 'Bearer ' + token;
+    }
+    return fetch(url, init);
+}
+
+function request(method, url, body) {
+    var init = { method: method, headers: {} };
+    if (body !== undefined) {
+        init.headers['Content-Type'] = 'application/json';
+        init.body = JSON.stringify(body);
+    }
+    return api(url, init).then(function (response) {
+        if (response.status === 401 || response.status === 403) {
+            showAccess(null);
+        }
+        if (!response.ok) {
+            return response.text().then(text => Promise.reject(text || response.statusText));
+        }
+    });
+}
+
+function register(peer) {
+    return request('POST', '/api/register', peer)
+        .then(() => showMessage('Registered ' + peer.name))
+        .catch(function (e) {
+            showMessage('Could not register ' + peer.name + ': ' + e, true);
+            return Promise.reject(e);
+        });
+}
+
+function showAccess(text) {
+    document.getElementById('access').classList.remove('hidden');
+    if (text) {
+        showMessage(text, true);
+    }
+}
+
+function showMessage(text, error) {
+    var message = document.getElementById('message');
+    message.textContent = text;
+    message.className = error ? 'message error' : 'message';
+}
+
+function fillDetector(peer) {
+    var form = document.getElementById('detector');
+    var detector = (peer && peer.detector) || {};
+    ['threshold', 'sample_size', 'min_std_deviation', 'acceptable_heartbeat_pause', 'first_heartbeat_estimate', 'mode']
+        .forEach(function (key) {
+            form.elements[key].value = detector[key] !== undefined ? detector[key] : '';
+        });
+}
+
+function syncClock(peer) {
+    if (peer.age !== undefined && peer.last > 0) {
+        clockOffset = peer.last + peer.age - Date.now();
+    }
+}
+
+function applyDelta(delta) {
+    var peer = peers[delta.id] || { id: delta.id, history: [], samples: [], timeline: [] };
+    ['name', 'address', 'resolved', 'phi', 'state', 'last', 'recorded', 'age', 'rtt', 'detector'].forEach(function (key) {
+        if (delta[key] !== undefined) {
+            peer[key] = delta[key];
+        }
+    });
+    ['mean', 'std_deviation'].forEach(function (key) {
+        if (delta[key] !== undefined && peer.detector) {
+            peer.detector[key] = delta[key];
+        }
+    });
+    if (delta.intervals) {
+        peer.history = delta.intervals.concat(peer.history).slice(0, 20);
+        peer.samples = delta.intervals.concat(peer.samples).slice(0, sampleSize(peer));
+    }
+    if (delta.timeline) {
+        peer.timeline = peer.timeline.concat(delta.timeline);
+    }
+    syncClock(delta);
+    peers[delta.id] = peer;
+}
+
+function sampleSize(peer) {
+    return peer.detector ? peer.detector.sample_size : 200;
+}
+
+function compare(a, b) {
+    if (a.name < b.name) {
+        return -1;
+    }
+    if (a.name > b.name) {
+        return 1;
+    }
+    return 0;
+}
+
+function serverNow() {
+    return Date.now() + clockOffset;
+}
+
+function level(peer) {
+    if (peer.timeline && peer.timeline.length) {
+        return peer.timeline[peer.timeline.length - 1].level;
+    }
+    return peer.state && peer.state.Dead !== undefined ? 'dead' : 'alive';
+}
+
+function color(name) {
+    if (!colors[name]) {
+        var used = Object.keys(colors).length;
+        colors[name] = used < chartColors.length ? chartColors[used] : getRandomColor();
+    }
+    return colors[name];
+}
+
+function loadHistory(id) {
+    var from = Math.floor(serverNow() - historyWindow);
+    api('/api/peers/' + id + '/timeseries?from=' + from)
+        .then(response => response.ok ? response.json() : Promise.reject(response.statusText))
+        .then(function (series) {
+            var loaded = series.points.map(p => ({ at: p.at, value: p.phi }));
+            var live = (phiHistory[id] || []).filter(function (p) {
+                return !loaded.length || p.at > loaded[loaded.length - 1].at;
+            });
+            phiHistory[id] = loaded.concat(live);
+        })
+        .catch(() => undefined);
+}
+
+function addDataHistory(state) {
+    var now = serverNow();
+    state.forEach(function (s) {
+        var points = (phiHistory[s.id] || []).filter(p => p.at >= now - historyWindow);
+        points.push({ at: now, value: s.phi });
+        phiHistory[s.id] = points;
+    });
+}
+
+function addDataIntervals(state) {
+    var today = new Date();
+    var label = today.getHours() + ":" + today.getMinutes() + ":" + today.getSeconds();
+
+    intervals.labels.push(label);
+    if (intervals.labels.length > maxElem) {
+        intervals.labels.shift();
+    }
+    state.forEach(function (s) {
+        var data = intervals.series[s.name] || [];
+        data.push(s.history[0]);
+        if (data.length > maxElem) {
+            data.shift();
+        }
+        intervals.series[s.name] = data;
+    });
+}
+
+function draw(state) {
+    drawTable(state);
+    drawDetails(peers[selected]);
+    var now = serverNow();
+    Charts.time(document.getElementById('historyChart'), 'Phi (15 min)', now - historyWindow, now,
+        state.map(s => ({ label: s.name, color: color(s.name), points: phiHistory[s.id] || [] })), 16);
+    Charts.bar(document.getElementById('phiChart'), 'Phi',
+        state.map(s => ({ label: s.name, value: s.phi, color: color(s.name) })), 16);
+    Charts.line(document.getElementById('intervalsChart'), 'Intervals', intervals.labels,
+        Object.keys(intervals.series).map(name => ({
+            label: name,
+            color: color(name),
+            data: intervals.series[name].filter(v => v !== undefined)
+        })));
+}
+
+function formatAge(peer) {
+    if (!peer.last) {
+        return 'never';
+    }
+    var age = Math.max(serverNow() - peer.last, 0);
+    return age < 10000 ? age + ' ms' : (age / 1000).toFixed(1) + ' s';
+}
+
+function formatRtt(peer) {
+    return peer.rtt === undefined || peer.rtt === null ? '-' : peer.rtt.toFixed(1) + ' ms';
+}
+
+function cell(row, text, className) {
+    var td = document.createElement('td');
+    td.textContent = text;
+    if (className) {
+        td.className = className;
+    }
+    row.appendChild(td);
+    return td;
+}
+
+function drawTable(state) {
+    var body = document.querySelector('#peers tbody');
+    body.textContent = '';
+    state.forEach(function (peer) {
+        var row = document.createElement('tr');
+        row.dataset.id = peer.id;
+        if (peer.id === selected) {
+            row.className = 'selected';
+        }
+        cell(row, peer.name);
+        cell(row, peer.id.slice(0, 8)).title = peer.id;
+        cell(row, peer.address).title = peer.resolved ? 'resolves to ' + peer.resolved : 'not resolved';
+        var badge = document.createElement('span');
+        badge.className = 'badge ' + level(peer);
+        badge.textContent = level(peer);
+        cell(row, '').appendChild(badge);
+        cell(row, peer.phi.toFixed(2), 'num');
+        cell(row, formatAge(peer), 'num');
+        cell(row, formatRtt(peer), 'num');
+        var canvas = document.createElement('canvas');
+        canvas.className = 'timeline';
+        cell(row, '').appendChild(canvas);
+        body.appendChild(row);
+        drawTimeline(canvas, peer.timeline || []);
+    });
+}
+
+function drawTimeline(canvas, timeline) {
+    var now = serverNow();
+    var start = now - timelineWindow;
+    Charts.strip(canvas, timeline.map(function (span, i) {
+        var end = i + 1 < timeline.length ? timeline[i + 1].since : now;
+        return {
+            from: (Math.max(span.since, start) - start) / timelineWindow,
+            to: (end - start) / timelineWindow,
+            color: levelColors[span.level]
+        };
+    }).filter(s => s.to > 0));
+}
+
+function drawDetails(peer) {
+    var details = document.getElementById('details');
+    if (!peer) {
+        details.classList.add('hidden');
+        return;
+    }
+    details.classList.remove('hidden');
+    document.getElementById('detailsTitle').textContent = peer.name + ' (' + peer.id + ')';
+
+    Charts.bar(document.getElementById('distributionChart'), 'Interval distribution (ms)',
+        histogram(peer.samples || []));
+
+    var table = document.getElementById('parameters');
+    table.textContent = '';
+    var detector = peer.detector || {};
+    [
+        ['Mode', detector.mode],
+        ['Threshold', detector.threshold],
+        ['Sample size', detector.sample_size],
+        ['Min std deviation', detector.min_std_deviation + ' ms'],
+        ['Acceptable pause', detector.acceptable_heartbeat_pause + ' ms'],
+        ['First heartbeat estimate', detector.first_heartbeat_estimate + ' ms'],
+        ['Mean interval', (detector.mean || 0).toFixed(1) + ' ms'],
+        ['Std deviation', (detector.std_deviation || 0).toFixed(1) + ' ms'],
+        ['Intervals recorded', peer.recorded]
+    ].forEach(function (entry) {
+        var row = document.createElement('tr');
+        cell(row, entry[0]);
+        cell(row, entry[1], 'num');
+        table.appendChild(row);
+    });
+}
+
+function histogram(samples) {
+    if (!samples.length) {
+        return [];
+    }
+    var bins = 12;
+    var min = Math.min.apply(null, samples);
+    var max = Math.max.apply(null, samples);
+    var width = Math.max(Math.ceil((max - min + 1) / bins), 1);
+    var counts = new Array(bins).fill(0);
+    samples.forEach(function (s) {
+        counts[Math.min(Math.floor((s - min) / width), bins - 1)] += 1;
+    });
+    return counts.map((count, i) => ({ label: String(min + i * width), value: count, color: '#3e95cd' }));
+}
+
+function getRandomColor() {
+    var letters = '0123456789ABCDEF';
+    var color = '#';
+    for (var i = 0; i < 6; i++) {
+        color += letters[Math.floor(Math.random() * 16)];
+    }
+    return color;
+}
+
+window.addEventListener('load', init);